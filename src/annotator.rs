@@ -12,21 +12,67 @@ use std::{
 
 use annotate::add_scaffolding;
 use data::DebugDataOwned;
+use filters::{FunctionFilters, PathGlob};
+use profiles::ExclusionProfile;
 
 use crate::noise::NoiseLevel;
 
 /// A module for annotating Wat files with the runner harness
 pub mod annotate;
+/// A module for splitting function bodies into basic blocks
+pub mod cfg;
 /// A module containing the representation of debug data structs
 pub mod data;
 /// A module for extracting debug information from Wat files
 pub mod debug;
+/// A module for choosing which functions get probes, by the file they're declared in or their name
+pub mod filters;
+/// A module for modelling component index spaces, and shifting indices when new items are inserted
+pub mod index_space;
+/// A module for covering functions by name, for binaries without any line info
+pub mod names;
 /// A module for mapping the offsets in an original file to their positiions in a modified one
 pub mod offset_tracker;
+/// A module for recognizing toolchain and generated source files, whose code isn't instrumented by default
+pub mod profiles;
+/// A module for mapping the code of an instrumented binary back to the original, to move its DWARF and to resolve traps
+pub mod relocate;
+/// A module for reading source maps, for toolchains that don't emit DWARF
+pub mod source_map;
 /// A module for commonly used utility functions
 pub mod utils;
+/// A module for validating annotated output and explaining validation failures
+pub mod validate;
+/// A module for covering hand-written WAT against its own lines
+pub mod wat_source;
+
+/// Options that control how a Wasm component is annotated
+#[derive(Default)]
+pub struct AnnotateOptions {
+    /// A source map to read line info from, for a core module without DWARF
+    /// It's used instead of the map that module's `sourceMappingURL` section points to
+    pub source_map: Option<PathBuf>,
+    /// The index of the core module `source_map` is for
+    /// Without it, the module is the one whose `sourceMappingURL` names the map's file, or the only one without line info
+    pub source_map_module: Option<usize>,
+    /// The directory relative source map URLs are resolved against, which is usually the one holding the Wasm file
+    pub base_dir: Option<PathBuf>,
+    /// Where to list the functions that are covered by name when no core module has line info
+    /// Without it, binaries without line info aren't covered at all
+    pub function_listing: Option<PathBuf>,
+    /// The hand-written WAT file the component was encoded from, to report coverage against the lines of instead of DWARF
+    pub wat_source: Option<PathBuf>,
+    /// The exclusion profiles whose code is instrumented anyway
+    /// Code from the files of every other profile, like the Rust standard library, isn't instrumented at all
+    pub included_profiles: Vec<ExclusionProfile>,
+    /// Globs of source paths whose code isn't instrumented, on top of the exclusion profiles
+    pub excluded_paths: Vec<PathGlob>,
+    /// The filters that pick which functions are instrumented, by their DWARF
+    pub function_filters: FunctionFilters,
+}
 
 /// Types of counters corresponding to different control flow blocks that we place counters at
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(i32)]
 pub enum CounterType {
     /// A Block Wasm instruction
@@ -65,13 +111,14 @@ impl CounterType {
     }
 }
 
-/// Takes the same input arguments as the CLI, and outputs either the modified WAT and data, or an error
+/// Takes the same input arguments as the CLI, and outputs either the modified WAT, its encoded binary, and data, or an error
 pub fn modify_wasm(
     path: Option<PathBuf>,
     mut text: Option<String>,
     binary_path: Option<PathBuf>,
+    mut options: AnnotateOptions,
     noise_level: NoiseLevel,
-) -> Result<(String, DebugDataOwned), Box<dyn Error>> {
+) -> Result<(String, Vec<u8>, DebugDataOwned), Box<dyn Error>> {
     if path.is_none() && text.is_none() {
         // try read text from stdin
         let mut buffer = String::new();
@@ -80,9 +127,17 @@ pub fn modify_wasm(
         text = Some(buffer.to_string());
     }
 
+    if options.base_dir.is_none() {
+        options.base_dir = binary_path
+            .as_ref()
+            .and_then(|p| p.parent())
+            .map(|p| p.to_path_buf());
+    }
+
     Ok(add_scaffolding(
         text.unwrap(),
         binary_path.map(|p| Cow::Owned(fs::read(p).unwrap())),
+        &options,
         noise_level,
     )?)
 }
@@ -1,46 +1,117 @@
 use core::str;
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
+use itertools::Itertools;
 use regex::Regex;
-use wast::core::{ExportKind, Func, Instruction, ModuleField};
+use wast::core::{
+    ExportKind, Func, FuncKind, GlobalKind, Instruction, ItemKind, MemoryKind, ModuleField,
+    TableKind, TagKind,
+};
 use wast::parser::{parse, ParseBuffer};
-use wast::token::Index;
-use wast::{component::*, Wat};
+use wast::token::{Id, Index, Span};
+use wast::{component::*, kw, Wat};
 use wast::{parser, Error};
 
+use crate::annotator::cfg::basic_blocks;
 use crate::annotator::data::DebugDataOwned;
-use crate::annotator::debug::{find_code_offsets, read_dbg_info, SourceDebugInfo, WatLineMapper};
+use crate::annotator::debug::{
+    find_code_modules, find_code_offsets, find_module_code_sections, read_dbg_info, WatLineMapper,
+};
+use crate::annotator::index_space::{
+    find_module_func_references, IndexMap, IndexShifts, IndexSpace,
+};
+use crate::annotator::names::add_name_section_functions;
 use crate::annotator::offset_tracker::OffsetTracker;
+use crate::annotator::source_map::read_source_maps;
 use crate::annotator::utils::*;
+use crate::annotator::validate::validate_output;
+use crate::annotator::wat_source::read_wat_source;
+use crate::annotator::AnnotateOptions;
 use crate::noise::NoiseLevel;
 use crate::printer::{println_annotate_dbg, println_annotate_error};
 
-const INSTANTIATION_REGEX_STR: &str = r"core instance \(;[0-9]+;\) \(instantiate [0-9]+";
-const BINARY_OFFSET_REGEX_STR: &str = r"(?P<whole>\(;@(?P<hex>[0-9a-f]+)\s*;\))";
-const INC_FUNC_NAME: &str = "inc-counter";
-const INC_MODULE_NAME: &str = "inc-counter-module";
+const INSTANTIATION_REGEX_STR: &str =
+    r"core instance (\$[^\s()]+ )?\(;[0-9]+;\) \(instantiate ([0-9]+|\$[^\s()]+)";
+const COMPONENT_INSTANTIATION_REGEX_STR: &str =
+    r"instance (\$[^\s()]+ )?\(;[0-9]+;\) \(instantiate ([0-9]+|\$[^\s()]+)";
+/// Matches the binary offsets wasmprinter prints in front of items
+// Offsets are printed at the start of a line, which tells them apart from branch label comments
+pub const BINARY_OFFSET_REGEX_STR: &str = r"(?m)^(?P<whole>\(;@(?P<hex>[0-9a-f]+)\s*;\))";
+/// The name of the counter function that probes call
+pub const INC_FUNC_NAME: &str = "inc-counter";
+const LOCAL_COUNTER_NAME: &str = "wcov-counter";
+const FLUSH_FUNC_NAME: &str = "wcov-flush";
+const FLUSH_INSTANCE_NAME: &str = "wcov-flushed-instance";
+/// The prefix of the component exports that flush the counters of `realloc` and post-return functions
+pub const FLUSH_EXPORT_PREFIX: &str = "wcov-flush-instance";
+/// The module that core modules import the counter function from
+pub const INC_MODULE_NAME: &str = "inc-counter-module";
 // Is there a good way to ensure that these are always compatible? maybe a macro
 const INC_FUNC_DESC_COMP: &str =
-    "(param \"idx\" s32) (param \"type\" s32) (param \"file-idx\" s32) (param \"line-num\" s32) (param \"column\" s32)";
+    "(param \"idx\" s32) (param \"count\" s32) (param \"file-idx\" s32) (param \"line-num\" s32) (param \"column\" s32)";
 const INC_FUNC_DESC_CORE: &str = "(param i32) (param i32) (param i32) (param i32) (param i32)";
 
-/// Accepts the text of a Wat file (and optionally the bytes of a binary Wasm file), and outputs a modified Wat file, as well as some debugging information
+/// The names of the items that keep and flush the counters of blacklisted functions
+/// Each one starts with its usual name, and is changed if that name is already used somewhere in the text
+pub struct FlushNames {
+    /// The prefix of the ids of the globals that count how often each block ran
+    counter: String,
+    /// The id and export name of the flush function in each module
+    func: String,
+    /// The prefix of the ids given to unnamed instances whose flush functions are exported
+    instance: String,
+    /// The prefix of the component exports of flush functions, which always starts with [`FLUSH_EXPORT_PREFIX`]
+    export: String,
+}
+
+impl FlushNames {
+    /// Pick the names, making sure none of them clash with an id or string in `text`
+    pub fn new(text: &str) -> FlushNames {
+        FlushNames {
+            counter: unique_name(text, LOCAL_COUNTER_NAME),
+            func: unique_name(text, FLUSH_FUNC_NAME),
+            instance: unique_name(text, FLUSH_INSTANCE_NAME),
+            export: unique_name(text, FLUSH_EXPORT_PREFIX),
+        }
+    }
+}
+
+/// Find a name starting with `base` that no id or string in `text` starts with, by adding a number to the end if needed
+/// Since nothing in `text` starts with the name, it can be used as a prefix too
+fn unique_name(text: &str, base: &str) -> String {
+    (0..)
+        .map(|n| match n {
+            0 => base.to_string(),
+            n => format!("{}{}", base, n),
+        })
+        .find(|name| {
+            !text.contains(&format!("${}", name)) && !text.contains(&format!("\"{}", name))
+        })
+        .unwrap()
+}
+
+/// Accepts the text of a Wat file (and optionally the bytes of a binary Wasm file), and outputs a modified Wat file, its encoded binary, and some debugging information
 /// If the binary file is not provided, this function will compile it from the Wat tezt (this adds extra time)
 pub fn add_scaffolding(
     wat_text: String,
     binary: Option<Cow<[u8]>>,
+    options: &AnnotateOptions,
     noise_level: NoiseLevel,
-) -> parser::Result<(String, DebugDataOwned)> {
+) -> parser::Result<(String, Vec<u8>, DebugDataOwned)> {
     // Things to do: (in order)
-    // Add import statement for the inc counter function (in type and import section)
     // Add import statements within each module
     // Add function calls wherever we want
-    // Bump the index of any instance reference
-    // Bump the index of any component function
-    // Bump the index of any core function
-    // Add canon lower of inc counter func (right after module sections)
+    // Then, in every component that instantiates those modules:
+    // Add import statement for the inc counter function (in type and import section)
+    // Add canon lower of inc counter func (right before the first core instance)
     // Add instance exporting that core function
-    let mut output = wat_text.clone();
+    // Pass the inc counter func to nested components that need it
+    // Export the flush functions of modules with `realloc` or post-return functions
+    // Shift every index that points past one of the new items
+    // Make all of those edits to the text at once
+    // Then make sure the result is valid, and explain what went wrong if it isn't
     let mut buf = ParseBuffer::new(&wat_text)?;
     let buf = buf.track_instr_spans(true);
     let wat = parse::<Wat>(buf)?;
@@ -55,457 +126,594 @@ pub fn add_scaffolding(
     let mut wat_mapper = WatLineMapper::new(
         find_code_offsets(&binary)
             .map_err(|_| Error::new(wat.span(), "Error reading binary file".to_string()))?,
+        find_module_code_sections(&binary)
+            .map_err(|_| Error::new(wat.span(), "Error reading binary file".to_string()))?,
+    );
+    // Functions are filtered before their modules' functions are added to the SDIs, so the ones left out aren't reported
+    wat_mapper.set_function_filters(options.function_filters.clone());
+    if let Some(source_path) = &options.wat_source {
+        read_wat_source(&wat, &wat_text, source_path, &mut wat_mapper, noise_level)?;
+    } else {
+        read_dbg_info(&wat, &wat_text, &mut wat_mapper, noise_level)?;
+    }
+    let code_modules = find_code_modules(&binary)
+        .map_err(|_| Error::new(wat.span(), "Error reading binary file".to_string()))?;
+    read_source_maps(
+        &wat,
+        &code_modules,
+        &mut wat_mapper,
+        options.source_map.as_deref(),
+        options.source_map_module,
+        options.base_dir.as_deref().unwrap_or(Path::new("")),
+        noise_level,
+    )?;
+    if let Some(listing_path) = &options.function_listing {
+        add_name_section_functions(&code_modules, &mut wat_mapper, listing_path, noise_level)
+            .map_err(|e| {
+                Error::new(
+                    wat.span(),
+                    format!(
+                        "Couldn't write function listing {}: {}",
+                        listing_path.display(),
+                        e
+                    ),
+                )
+            })?;
+    }
+
+    wat_mapper.exclude_files(
+        &options.included_profiles,
+        &options.excluded_paths,
+        noise_level,
     );
-    read_dbg_info(&wat, &wat_text, &mut wat_mapper, noise_level)?;
 
-    let type_idx_bound = add_inc_import_section(&wat, &mut output, &mut total_increment)?;
-    add_imports_in_module(&wat, &mut output, &mut total_increment)?;
+    total_increment.set_pass("module imports");
+    add_imports_in_module(&wat, &mut total_increment)?;
+    let flush_names = FlushNames::new(&wat_text);
+    let flushed_modules;
     {
-        let bl = bump_core_func_idxs(&wat, &mut output, &mut total_increment)?;
         // process blacklisted functions
-        let bl = process_blacklist(&wat, bl, noise_level)?;
-        add_func_calls(
+        let bl = process_blacklist(&wat, noise_level)?;
+        total_increment.set_pass("probes");
+        flushed_modules = add_func_calls(
             &wat,
-            &mut output,
             &mut total_increment,
             bl,
-            &wat_mapper,
+            &flush_names,
+            &mut wat_mapper,
             &wat_text,
             noise_level,
         )?;
     }
 
-    bump_instance_idxs(&wat, &mut output, &mut total_increment)?;
-    bump_comp_func_idxs(&wat, &mut output, &mut total_increment, noise_level)?;
-    bump_type_idxs(&wat, &mut output, &mut total_increment, type_idx_bound)?;
-    add_instantiaion_arg(&wat, &mut output, &mut total_increment, noise_level)?;
-    //panic!("erm.. what the bug");
-    add_canon_lower_and_instance(&wat, &mut output, &mut total_increment)?;
-    Ok((output, wat_mapper.into_debug_data()))
+    total_increment.set_pass("counter plumbing");
+    add_counter_plumbing(&wat, &wat_text, &mut total_increment, noise_level)?;
+    total_increment.set_pass("flush exports");
+    add_flush_exports(
+        &wat,
+        &flushed_modules,
+        &flush_names,
+        &mut total_increment,
+        &wat_text,
+        noise_level,
+    )?;
+    let output = total_increment.apply(&wat_text);
+    let output_binary = validate_output(&output, &wat_text, &total_increment, noise_level)?;
+    Ok((output, output_binary, wat_mapper.into_debug_data()))
 }
 
-/// Adds the instructions that import the host functions
-pub fn add_inc_import_section(
-    wat: &Wat,
-    output: &mut String,
-    total_increment: &mut OffsetTracker,
-) -> parser::Result<u32> {
+/// Find where the import of the counter function should go in a component
+/// The import goes after the leading run of type, import, and alias fields, or at the very start of the component if there isn't one
+fn inc_import_position(fields: &[ComponentField]) -> Option<usize> {
     let mut was_last_ty_import_alias = false;
-    let mut offset = 0;
-    let mut type_idx = 0;
-    'field: for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
+    for (idx, field) in fields.iter().enumerate() {
         match field {
             ComponentField::Type(_) | ComponentField::Import(_) | ComponentField::Alias(_) => {
                 was_last_ty_import_alias = true;
-                if let ComponentField::Type(_) = field {
-                    type_idx += 1;
-                } else if let ComponentField::Alias(alias) = field {
-                    if let AliasTarget::Export { kind, .. } = alias.target {
-                        if matches!(kind, ComponentExportAliasKind::Type) {
-                            type_idx += 1;
-                        }
-                    }
-                }
             }
             _ => {
                 if was_last_ty_import_alias && get_span(field).is_some() {
-                    offset = get_span(field).unwrap().offset() - 1;
-                    break 'field;
+                    return Some(idx);
                 }
             }
         }
     }
+    fields.iter().position(|f| get_span(f).is_some())
+}
 
+/// Adds the instruction that imports the counter function in front of `fields[position]`
+pub fn add_inc_import_section<'a>(
+    fields: &'a [ComponentField<'a>],
+    position: usize,
+    total_increment: &mut OffsetTracker,
+    shifts: &mut IndexShifts,
+) {
+    let offset = get_span(&fields[position]).unwrap().offset() - 1;
     let msg = format!(
         "(import \"{0}\" (func ${0} {1}))",
         INC_FUNC_NAME, INC_FUNC_DESC_COMP
     );
-    total_increment.add_to_string(output, offset, &msg);
-
-    Ok(type_idx)
+    total_increment.insert(offset, &msg);
+    // The inline function type is expanded into a type definition right before the import
+    shifts.insert(fields, position, IndexSpace::Type);
+    shifts.insert(fields, position, IndexSpace::Func);
 }
 
-/// Adds function imports to each inline module
-pub fn add_imports_in_module(
-    wat: &Wat,
-    output: &mut String,
-    total_increment: &mut OffsetTracker,
-) -> parser::Result<()> {
-    'comp: for field in get_fields(wat).ok_or(Error::new(
+/// Adds function imports to each inline module, including modules defined in nested components
+/// The import goes right before the module's first definition, since imports have to come before any functions, tables, memories, globals, or tags are defined
+/// Defining a new function import shifts the index of every function defined in the module, so numeric references to those functions are increased to match
+pub fn add_imports_in_module(wat: &Wat, total_increment: &mut OffsetTracker) -> parser::Result<()> {
+    for (_, fields) in get_inline_modules(wat).ok_or(Error::new(
         wat.span(),
         "Input WAT file could not be parsed (may be binary or module)".to_string(),
     ))? {
-        if let ComponentField::CoreModule(m) = field {
-            let mut offset = 0;
-            // parse module fields
-            if let CoreModuleKind::Inline { fields } = &m.kind {
-                let mut was_last_import = false;
-                'module: for field in fields {
-                    if let ModuleField::Import(_) = field {
-                        was_last_import = true
-                    } else if was_last_import {
-                        was_last_import = false;
-                        if let Some(span) = get_module_span(field) {
-                            offset = span.offset() - 1;
-                            break 'module;
-                        }
-                    }
-                }
-            }
-            if offset == 0 {
-                continue 'comp;
-            }
-            let msg = format!(
-                "(import \"{0}\" \"{1}\" (func ${1} {2}))\n",
-                INC_MODULE_NAME, INC_FUNC_NAME, INC_FUNC_DESC_CORE
-            );
+        // A module without definitions has no functions to add calls to
+        // This has to match `imports_inc_func`, which decides which instantiations are passed the counter function
+        let Some(position) = fields.iter().position(is_module_definition) else {
+            continue;
+        };
+        let offset = get_module_span(&fields[position]).unwrap().offset() - 1;
+        let msg = format!(
+            "(import \"{0}\" \"{1}\" (func ${1} {2}))\n",
+            INC_MODULE_NAME, INC_FUNC_NAME, INC_FUNC_DESC_CORE
+        );
+        total_increment.insert(offset, &msg);
 
-            total_increment.add_to_string(output, offset, &msg);
+        let bound = fields[..position]
+            .iter()
+            .filter(|field| match field {
+                ModuleField::Import(i) => matches!(i.item.kind, ItemKind::Func(_)),
+                ModuleField::Func(f) => matches!(f.kind, FuncKind::Import(_)),
+                _ => false,
+            })
+            .count() as u32;
+        for idx in find_module_func_references(fields) {
+            total_increment.increment_idx(idx, Some(bound));
         }
     }
 
     Ok(())
 }
 
-/// Adds function calls to control flow instructions
-/// The blacklist argument specifies functions that should not have calls inserted
-/// This is mostly used to ensure that `realloc` functions don't call other functions outside their instances, which is an error in Webassembly
+/// Check whether `add_imports_in_module` gave a module the import of the counter function
+fn imports_inc_func(fields: &Vec<ModuleField>) -> bool {
+    fields.iter().any(is_module_definition)
+}
+
+/// Check whether a module field defines a function, table, memory, global, or tag, rather than importing it
+fn is_module_definition(field: &ModuleField) -> bool {
+    match field {
+        ModuleField::Func(f) => !matches!(f.kind, FuncKind::Import(_)),
+        ModuleField::Table(t) => !matches!(t.kind, TableKind::Import { .. }),
+        ModuleField::Memory(m) => !matches!(m.kind, MemoryKind::Import { .. }),
+        ModuleField::Global(g) => !matches!(g.kind, GlobalKind::Import(_)),
+        ModuleField::Tag(t) => !matches!(t.kind, TagKind::Import(_)),
+        _ => false,
+    }
+}
+
+/// Adds a call to the counter function at the start of every basic block in every function
+/// Each counter covers the DWARF statement rows inside its block, apart from those in a prologue or epilogue, and a block without any covers the statement its first instruction is part of
+/// The blacklist argument specifies functions that can't call other functions outside their instances, which is an error in `realloc` and post-return functions
+/// Blacklisted functions increment a global in their module instead, and the module gets a flush function that passes those counts on to the counter function
+/// Functions that the mapper's function filters leave out, found by the DWARF function their first instruction is in, don't get any probes
+/// Returns the span and fields of every module that was given a flush function
 pub fn add_func_calls<'a>(
     wat: &'a Wat,
-    output: &mut String,
     total_increment: &mut OffsetTracker,
-    blacklist: Vec<(Index<'a>, &'a Func<'a>)>,
-    map: &WatLineMapper,
+    blacklist: Vec<&'a Func<'a>>,
+    flush_names: &FlushNames,
+    map: &mut WatLineMapper,
     text: &str,
     noise_level: NoiseLevel,
-) -> parser::Result<()> {
-    let mut counter_idx = 0;
-    let mut inline_mod_idx = 0;
-    let mut sdi_iter = None;
-    let mut line_addrs_inserted = Vec::new();
+) -> parser::Result<Vec<(Span, &'a Vec<ModuleField<'a>>)>> {
+    let mut flushed_modules = Vec::new();
     let binary_offset_re = Regex::new(BINARY_OFFSET_REGEX_STR).unwrap();
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        if let ComponentField::CoreModule(m) = field {
-            // parse module fields
-            if let CoreModuleKind::Inline { fields } = &m.kind {
-                'fields: for field in fields {
-                    if let ModuleField::Func(func) = field {
-                        if blacklist.iter().any(|(_, f)| f.span == func.span) {
-                            continue;
-                        }
-                        if noise_level.debug() {
-                            println_annotate_dbg(format!("Func defined @{}", func.span.offset()));
-                        }
+    for (inline_mod_idx, (module_span, fields)) in get_inline_modules(wat)
+        .ok_or(Error::new(
+            wat.span(),
+            "Input WAT file could not be parsed (may be binary or module)".to_string(),
+        ))?
+        .into_iter()
+        .enumerate()
+    {
+        let Some(code_module_idx) = map.code_module_idx(inline_mod_idx) else {
+            continue;
+        };
+        let Some(mod_offset) = map.get_code_addr(code_module_idx) else {
+            continue;
+        };
+        // Modules without line rows have nothing to cover
+        if map.module(code_module_idx).is_none() {
+            continue;
+        }
 
-                        if let wast::core::FuncKind::Inline {
-                            locals: _,
-                            expression,
-                        } = &func.kind
-                        {
-                            let _instrs = &expression.instrs;
-                            let spans = expression.instr_spans.as_ref().unwrap();
-                            if spans.len() == 0 {
-                                continue 'fields;
-                            }
-
-                            let lines = map
-                                .lines()
-                                .iter()
-                                .filter(|dli| dli.code_module_idx == inline_mod_idx);
-                            if let Some(mod_offset) = map.get_code_addr(inline_mod_idx) {
-                                for line in lines {
-                                    if !sdi_iter.as_ref().is_some_and(|sdi_iter: &Vec<_>| {
-                                        sdi_iter.iter().next().is_some_and(
-                                            |n: &&SourceDebugInfo| n.path_idx == line.path_idx,
-                                        )
-                                    }) {
-                                        sdi_iter = Some(
-                                            map.sdi_vec
-                                                .iter()
-                                                .filter(|sdi| sdi.path_idx == line.path_idx)
-                                                .collect(),
-                                        );
-                                    }
-
-                                    let func_at = sdi_iter
-                                        .as_ref()
-                                        .unwrap()
-                                        .iter()
-                                        .filter_map(|sdi| {
-                                            sdi.functions
-                                                .iter()
-                                                .find(|sdi_func| line.address == sdi_func.3)
-                                        })
-                                        .next();
-
-                                    if line_addrs_inserted.contains(&(line.address)) {
-                                        continue;
-                                    }
-
-                                    let true_bin_addr = mod_offset as u64 + line.address;
-                                    let txt_line = str::from_utf8(
-                                        if let Some(end) = spans.last().map(|s| s.offset()) {
-                                            &text.as_bytes()[func.span.offset()..end + 1]
-                                        } else {
-                                            &text.as_bytes()[func.span.offset()..]
-                                        },
-                                    )
-                                    .unwrap();
-
-                                    let hex_iter =
-                                        binary_offset_re.captures_iter(txt_line).map(|c| {
-                                            let m = c.name("hex").unwrap();
-                                            let bin_offset =
-                                                u64::from_str_radix(m.as_str(), 16).unwrap();
-                                            let m_whole = c.name("whole").unwrap();
-                                            let txt_offset = m_whole.end();
-                                            (bin_offset, txt_offset)
-                                        });
-
-                                    let text_offset = if func_at.is_some() {
-                                        let hexes = hex_iter.collect::<Vec<_>>();
-
-                                        // If the byte ranges "bound" or "surround" the function address, we know this is the function
-                                        if hexes
-                                            .iter()
-                                            .filter(|(off, _)| {
-                                                *off >= func_at.unwrap().3 + mod_offset as u64
-                                            })
-                                            .count()
-                                            > 0
-                                        {
-                                            if noise_level.debug() {
-                                                println_annotate_dbg(format!("USING FUNC START, spans: {}, func: {}, name: {}, dli: {:?}", 
-                                                spans.first().unwrap().offset() , func.span.offset(), func_at.unwrap().2, line));
-                                            }
-
-                                            spans.first().unwrap().offset()
-                                        } else {
-                                            let Some(text_offset) = hexes
-                                                .iter()
-                                                .filter(|(x, _)| *x == true_bin_addr)
-                                                .min_by(|(b1, _), (b2, _)| b1.cmp(b2))
-                                            else {
-                                                continue;
-                                            };
-                                            text_offset.1 + func.span.offset()
-                                        }
-                                    } else {
-                                        let Some(text_offset) = hex_iter
-                                            .filter(|(x, _)| *x == true_bin_addr)
-                                            .min_by(|(b1, _), (b2, _)| b1.cmp(b2))
-                                        else {
-                                            continue;
-                                        };
-                                        text_offset.1 + func.span.offset()
-                                    };
-
-                                    let msg = format!(
-                                        "i32.const {} i32.const {} i32.const {} i32.const {} i32.const {} call ${}\n",
-                                        counter_idx, 0, line.path_idx, line.line, line.column, INC_FUNC_NAME
-                                    );
-
-                                    counter_idx += 1;
-
-                                    total_increment.add_to_string(output, text_offset, &msg);
-
-                                    line_addrs_inserted.push(line.address);
-                                }
-                            }
-                        }
-                    }
+        // Each function's code ends where the next one starts
+        let funcs = fields
+            .iter()
+            .filter_map(|field| match field {
+                ModuleField::Func(func) if matches!(func.kind, FuncKind::Inline { .. }) => {
+                    Some(func)
                 }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let func_addrs = funcs
+            .iter()
+            .map(|func| {
+                printed_offset(text, func.span.offset(), &binary_offset_re)
+                    .map(|offset| offset - mod_offset as u64)
+            })
+            .collect::<Vec<_>>();
 
-                inline_mod_idx += 1;
+        let mut local_counters = Vec::new();
+        for (func_idx, func) in funcs.iter().enumerate() {
+            let FuncKind::Inline { expression, .. } = &func.kind else {
+                continue;
+            };
+            if noise_level.debug() {
+                println_annotate_dbg(format!("Func defined @{}", func.span.offset()));
             }
-        }
-    }
-
-    Ok(())
-}
-
-/// Increase all instance indices to ensure they point to the correct instances
-pub fn bump_instance_idxs(
-    wat: &Wat,
-    output: &mut String,
-    total_increment: &mut OffsetTracker,
-) -> parser::Result<()> {
-    // instance idxs are used in:
-    // alias export statements
-    // instantiation args
+            let spans = expression.instr_spans.as_ref().unwrap();
+            let (Some(last_span), Some(func_addr)) = (spans.last(), func_addrs[func_idx]) else {
+                continue;
+            };
+            let func_end = func_addrs[func_idx + 1..]
+                .iter()
+                .find_map(|addr| *addr)
+                .unwrap_or(u64::MAX);
+            let blacklisted = blacklist.iter().any(|f| f.span == func.span);
 
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        match field {
-            ComponentField::Alias(a) => match a.target {
-                AliasTarget::CoreExport { instance: idx, .. } => {
-                    // TODO Figure out lower bound flexibly
+            // The offset comments in front of each instruction give its address
+            let func_text = &text[func.span.offset()..=last_span.offset()];
+            let instr_offsets = binary_offset_re
+                .captures_iter(func_text)
+                .map(|c| {
+                    let hex = u64::from_str_radix(c.name("hex").unwrap().as_str(), 16).unwrap();
+                    let txt_offset = c.name("whole").unwrap().end() + func.span.offset();
+                    (hex - mod_offset as u64, txt_offset)
+                })
+                .collect::<Vec<_>>();
+            let instr_addr = |instr_idx: usize| {
+                let pos =
+                    instr_offsets.partition_point(|(_, txt)| *txt <= spans[instr_idx].offset());
+                pos.checked_sub(1).map(|pos| instr_offsets[pos].0)
+            };
 
-                    total_increment.increment_idx(output, idx, None);
-                }
-                AliasTarget::Export { instance: _idx, .. } => {}
-                _ => {}
-            },
-            ComponentField::Instance(i) => {
-                if let InstanceKind::Instantiate { component: _, args } = &i.kind {
-                    for arg in args {
-                        if let InstantiationArgKind::Item(ComponentExportKind::Instance(i)) =
-                            &arg.kind
-                        {
-                            // Other things here need to be bumped, but that happens in other functions
-
-                            // TODO Figure out lower bound flexibly
-                            total_increment.increment_idx(output, i.idx, None);
-                        }
-                    }
+            let dwarf_func = instr_addr(0)
+                .and_then(|addr| map.module(code_module_idx).unwrap().function_at(addr));
+            if !map.instruments(dwarf_func) {
+                if noise_level.debug() {
+                    println_annotate_dbg(format!(
+                        "Skipping {}, which the function filters leave out",
+                        dwarf_func.map_or("a function without DWARF", |func| func.name.as_str())
+                    ));
                 }
+                continue;
             }
-            ComponentField::CoreInstance(i) => {
-                if let CoreInstanceKind::Instantiate { module: _, args } = &i.kind {
-                    for arg in args {
-                        // I think this is the right match for an instance arg
-                        if let CoreInstantiationArgKind::Instance(iref) = &arg.kind {
-                            total_increment.increment_idx(output, iref.idx, None);
+
+            let blocks = basic_blocks(&expression.instrs);
+            // The first block also covers the rows for the function's locals
+            let starts = blocks
+                .iter()
+                .enumerate()
+                .map(|(idx, block)| {
+                    if idx == 0 {
+                        Some(func_addr)
+                    } else {
+                        instr_addr(block.start)
+                    }
+                })
+                .collect::<Vec<_>>();
+            for (block_idx, block) in blocks.iter().enumerate() {
+                let Some(start) = starts[block_idx] else {
+                    continue;
+                };
+                let end = starts[block_idx + 1..]
+                    .iter()
+                    .find_map(|addr| *addr)
+                    .unwrap_or(func_end);
+                let module = map.module(code_module_idx).unwrap();
+                // A location is only counted once per run of the block, however many rows it has there
+                let mut locations = module
+                    .block_rows(start..end, block_idx == 0)
+                    .into_iter()
+                    .map(|dli| (dli.path_idx, dli.line, dli.column, dli.discriminator))
+                    .unique()
+                    .collect::<Vec<_>>();
+                if locations.is_empty() {
+                    if module.entries_only() {
+                        continue;
+                    }
+                    match module.statement_row_at(start) {
+                        Some(dli) if dli.address >= func_addr => {
+                            locations.push((dli.path_idx, dli.line, dli.column, dli.discriminator))
                         }
+                        _ => continue,
                     }
                 }
+                // Code from excluded files isn't instrumented at all
+                locations.retain(|(path_idx, _, _, _)| !map.excludes_file(*path_idx));
+                if locations.is_empty() {
+                    continue;
+                }
+                let (path_idx, line, column, _) = locations[0];
+                if noise_level.debug() {
+                    println_annotate_dbg(format!(
+                        "{} block at {:x}..{:x} covers {} locations, starting at {}:{}:{}",
+                        block.kind,
+                        start,
+                        end,
+                        locations.len(),
+                        path_idx,
+                        line,
+                        column
+                    ));
+                }
+                let counter_idx = map.add_counter(locations);
+
+                let msg = if blacklisted {
+                    local_counters.push((counter_idx, path_idx, line, column));
+                    format!(
+                        "global.get ${0}{1} i32.const 1 i32.add global.set ${0}{1}\n",
+                        flush_names.counter, counter_idx
+                    )
+                } else {
+                    format!(
+                        "i32.const {} i32.const 1 i32.const {} i32.const {} i32.const {} call ${}\n",
+                        counter_idx, path_idx, line, column, INC_FUNC_NAME
+                    )
+                };
+                total_increment.insert(spans[block.start].offset(), &msg);
             }
+        }
 
-            _ => {}
+        if !local_counters.is_empty() {
+            add_flush_func(
+                module_span,
+                &local_counters,
+                flush_names,
+                total_increment,
+                text,
+            )?;
+            flushed_modules.push((module_span, fields));
         }
     }
 
-    Ok(())
+    Ok(flushed_modules)
+}
+
+/// Find the binary offset wasmprinter printed at the start of the line with the item at `offset`
+pub fn printed_offset(text: &str, offset: usize, binary_offset_re: &Regex) -> Option<u64> {
+    let line_start = text[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+    let captures = binary_offset_re.captures(&text[line_start..offset])?;
+    u64::from_str_radix(captures.name("hex")?.as_str(), 16).ok()
 }
 
-/// Increase all component function indices to ensure they point to the correct functions
-pub fn bump_comp_func_idxs(
-    wat: &Wat,
-    output: &mut String,
+/// Adds a global for each local counter to the end of a module, along with an exported function that passes their counts on to the counter function
+/// Each count is passed on in a single call, and then reset so flushing twice doesn't count anything twice
+/// Everything is added after the module's existing fields, so no indices have to be shifted
+fn add_flush_func(
+    module_span: Span,
+    local_counters: &[(usize, usize, u64, u64)],
+    names: &FlushNames,
     total_increment: &mut OffsetTracker,
-    noise_level: NoiseLevel,
+    text: &str,
 ) -> parser::Result<()> {
-    // What to bump
-    // canon lower <idx>
-    // (instantiate $instance (with "func" (func <idx>)))
-    // (instantiate $instance (export "func" (func <idx>)))
+    let end = find_closing_paren(text, module_span.offset() - 1).ok_or(Error::new(
+        module_span,
+        "Couldn't find the end of core module".to_string(),
+    ))?;
+    let mut msg = String::new();
+    for (idx, ..) in local_counters {
+        msg += &format!(
+            "(global ${}{} (mut i32) (i32.const 0))\n",
+            names.counter, idx
+        );
+    }
+    msg += &format!("(func ${0} (export \"{0}\")\n", names.func);
+    for (idx, path_idx, line, column) in local_counters {
+        // Blocks that never ran are skipped, so they aren't marked as run
+        msg += &format!(
+            "(if (global.get ${0}{1}) (then (call ${2} (i32.const {1}) (global.get ${0}{1}) (i32.const {3}) (i32.const {4}) (i32.const {5})) (global.set ${0}{1} (i32.const 0))))\n",
+            names.counter, idx, INC_FUNC_NAME, path_idx, line, column
+        );
+    }
+    msg += ")\n";
+    total_increment.insert(end, &msg);
+    Ok(())
+}
 
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        match field {
-            ComponentField::CoreFunc(cf) => {
-                if let CoreFuncKind::Lower(cl) = &cf.kind {
-                    total_increment.increment_idx(output, cl.func.idx, None)
-                }
-            }
-            ComponentField::CoreInstance(i) => match &i.kind {
-                CoreInstanceKind::Instantiate { module: _, args } => {
-                    for arg in args {
-                        match &arg.kind {
-                            // I think this is the right match for an instance arg
-                            CoreInstantiationArgKind::Instance(_) => {
-                                // no-op
-                            }
-                            CoreInstantiationArgKind::BundleOfExports(_, exports) => {
-                                for export in exports {
-                                    if export.item.kind == ExportKind::Func {
-                                        total_increment.increment_idx(
-                                            output,
-                                            export.item.idx,
-                                            None,
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                CoreInstanceKind::BundleOfExports(exps) => {
-                    for export in exps {
-                        if export.item.kind == ExportKind::Func {
-                            total_increment.increment_idx(output, export.item.idx, None);
-                        }
-                    }
-                }
-            },
-            ComponentField::Instance(i) => {
-                if let InstanceKind::Instantiate { component, args } = &i.kind {
-                    if noise_level.debug() {
-                        println_annotate_dbg(format!("comp: {:?}, args: {:?}", component, args));
-                    }
-                    for arg in args {
-                        match &arg.kind {
-                            // I think this is the right match for an instance arg
-                            InstantiationArgKind::Item(item) => {
-                                if let ComponentExportKind::Func(cf) = item {
-                                    total_increment.increment_idx(output, cf.idx, None);
-                                }
-                            }
-                            InstantiationArgKind::BundleOfExports(_, exports) => {
-                                for export in exports {
-                                    if let ComponentExportKind::Func(cf) = &export.kind {
-                                        total_increment.increment_idx(output, cf.idx, None);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {}
+/// A component whose fields are being annotated, along with a map for resolving references to its items
+struct Scope<'a> {
+    id: Option<Id<'a>>,
+    fields: &'a [ComponentField<'a>],
+    index_map: IndexMap<'a>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(id: Option<Id<'a>>, fields: &'a [ComponentField<'a>]) -> Scope<'a> {
+        Scope {
+            id,
+            fields,
+            index_map: IndexMap::new(fields),
         }
     }
 
-    Ok(())
+    fn root(wat: &'a Wat<'a>) -> parser::Result<Scope<'a>> {
+        let fields = get_fields(wat).ok_or(Error::new(
+            wat.span(),
+            "Input WAT file could not be parsed (may be binary or module)".to_string(),
+        ))?;
+        let id = match wat {
+            Wat::Component(c) => c.id,
+            Wat::Module(_) => None,
+        };
+        Ok(Scope::new(id, fields))
+    }
+
+    /// Create the scope of a nested component, if it's defined inline
+    fn nested(component: &'a NestedComponent<'a>) -> Option<Scope<'a>> {
+        match &component.kind {
+            NestedComponentKind::Inline(fields) => Some(Scope::new(component.id, fields)),
+            NestedComponentKind::Import { .. } => None,
+        }
+    }
 }
 
-/// Increase all core function indices to ensure they point to the correct functions
-pub fn bump_core_func_idxs<'a, 'b: 'a, 'c>(
-    wat: &'b Wat,
-    output: &'c mut String,
-    total_increment: &'c mut OffsetTracker,
-) -> parser::Result<Vec<Index<'a>>> {
-    // What to bump
-    // (realloc <funcidx>)
-    // ((canon lift (core func <funcidx>)))
+/// Describe an item by its id if it has one, or by where it's defined otherwise
+fn describe(id: Option<Id>, span: Span) -> String {
+    match id {
+        Some(id) => format!("${}", id.name()),
+        None => format!("at offset {}", span.offset()),
+    }
+}
 
-    let mut bl = Vec::new();
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        match field {
-            ComponentField::CoreFunc(cf) => {
-                if let CoreFuncKind::Lower(cl) = &cf.kind {
-                    // The func contained is a comp func, we want to find the realloc optioon
-                    for opt in &cl.opts {
-                        if let CanonOpt::Realloc(re) = opt {
-                            total_increment.increment_idx(output, re.idx, None);
-                            bl.push(re.idx);
-                        }
-                    }
+/// Find the scope an outer alias refers to, as an index into the stack of scopes
+fn outer_scope(scopes: &[&Scope], outer: Index) -> Option<usize> {
+    match outer {
+        Index::Num(count, _) => scopes.len().checked_sub(count as usize + 1),
+        Index::Id(id) => scopes.iter().rposition(|s| s.id == Some(id)),
+    }
+}
+
+/// Find the nested component an index in the innermost scope refers to, following aliases and re-exports
+/// Returns the component along with the index of the scope that defines it
+fn resolve_component<'a>(
+    scopes: &[&Scope<'a>],
+    idx: Index<'a>,
+) -> Option<(usize, &'a NestedComponent<'a>)> {
+    let scope = scopes.last()?;
+    match scope.index_map.field(IndexSpace::Component, idx)? {
+        ComponentField::Component(c) => Some((scopes.len() - 1, c)),
+        ComponentField::Alias(Alias {
+            target:
+                AliasTarget::Outer {
+                    outer,
+                    index,
+                    kind: ComponentOuterAliasKind::Component,
+                },
+            ..
+        }) => resolve_component(&scopes[..=outer_scope(scopes, *outer)?], *index),
+        ComponentField::Export(ComponentExport {
+            kind: ComponentExportKind::Component(r),
+            ..
+        }) if r.export_names.is_empty() => resolve_component(scopes, r.idx),
+        _ => None,
+    }
+}
+
+/// Find the fields of the inline core module an index in the innermost scope refers to, following aliases and re-exports
+fn resolve_module<'a>(scopes: &[&Scope<'a>], idx: Index<'a>) -> Option<&'a Vec<ModuleField<'a>>> {
+    let scope = scopes.last()?;
+    match scope.index_map.field(IndexSpace::CoreModule, idx)? {
+        ComponentField::CoreModule(CoreModule {
+            kind: CoreModuleKind::Inline { fields },
+            ..
+        }) => Some(fields),
+        ComponentField::Alias(Alias {
+            target:
+                AliasTarget::Outer {
+                    outer,
+                    index,
+                    kind: ComponentOuterAliasKind::CoreModule,
+                },
+            ..
+        }) => resolve_module(&scopes[..=outer_scope(scopes, *outer)?], *index),
+        ComponentField::Alias(Alias {
+            target:
+                AliasTarget::Export {
+                    instance,
+                    name,
+                    kind: ComponentExportAliasKind::CoreModule,
+                },
+            ..
+        }) => resolve_module_export(scopes, *instance, name),
+        ComponentField::Export(ComponentExport {
+            kind: ComponentExportKind::CoreModule(r),
+            ..
+        }) if r.export_names.is_empty() => resolve_module(scopes, r.idx),
+        _ => None,
+    }
+}
+
+/// Find the fields of the inline core module that a component instance exports as `name`
+/// This only works for instances of components defined inline, since wcov can't see into anything else
+fn resolve_module_export<'a>(
+    scopes: &[&Scope<'a>],
+    instance: Index<'a>,
+    name: &str,
+) -> Option<&'a Vec<ModuleField<'a>>> {
+    let scope = scopes.last()?;
+    let ComponentField::Instance(Instance {
+        kind: InstanceKind::Instantiate { component, .. },
+        ..
+    }) = scope.index_map.field(IndexSpace::Instance, instance)?
+    else {
+        return None;
+    };
+    if !component.export_names.is_empty() {
+        return None;
+    }
+    let (depth, component) = resolve_component(scopes, component.idx)?;
+    let nested = Scope::nested(component)?;
+    let export = nested.fields.iter().find_map(|field| match field {
+        ComponentField::Export(ComponentExport {
+            name: export_name,
+            kind: ComponentExportKind::CoreModule(r),
+            ..
+        }) if export_name.0 == name && r.export_names.is_empty() => Some(r.idx),
+        _ => None,
+    })?;
+    let mut stack = scopes[..=depth].to_vec();
+    stack.push(&nested);
+    resolve_module(&stack, export)
+}
+
+/// Find the functions used as `realloc` or `post-return` options by canonical functions, in the innermost scope and every component nested in it
+/// These are the starting points of the blacklist, since they are called in the middle of lifting or lowering and can't call back out of their instances
+/// Each function is paired with the fields of the module that defines it
+fn find_realloc_funcs<'a>(
+    scopes: &[&Scope<'a>],
+    out: &mut Vec<(&'a Vec<ModuleField<'a>>, &'a Func<'a>)>,
+    noise_level: NoiseLevel,
+) -> parser::Result<()> {
+    let scope = scopes.last().unwrap();
+    for field in scope.fields {
+        let opts = match field {
+            ComponentField::CoreFunc(CoreFunc {
+                kind: CoreFuncKind::Lower(cl),
+                ..
+            })
+            | ComponentField::CanonicalFunc(CanonicalFunc {
+                kind: CanonicalFuncKind::Lower(cl),
+                ..
+            }) => &cl.opts,
+            ComponentField::CanonicalFunc(CanonicalFunc {
+                kind: CanonicalFuncKind::Lift { info, .. },
+                ..
+            })
+            | ComponentField::Func(wast::component::Func {
+                kind: wast::component::FuncKind::Lift { info, .. },
+                ..
+            }) => &info.opts,
+            ComponentField::Component(c) => {
+                if let Some(nested) = Scope::nested(c) {
+                    let mut stack = scopes.to_vec();
+                    stack.push(&nested);
+                    find_realloc_funcs(&stack, out, noise_level)?;
                 }
+                continue;
             }
-            ComponentField::Func(f) => {
-                if let FuncKind::Lift { ty: _, info } = &f.kind {
-                    total_increment.increment_idx(output, info.func.idx, None);
-                }
+            _ => continue,
+        };
+        for opt in opts {
+            if let CanonOpt::Realloc(func) | CanonOpt::PostReturn(func) = opt {
+                out.extend(map_idx_to_module(scopes, func, noise_level)?);
             }
-            _ => {}
         }
     }
-
-    Ok(bl)
+    Ok(())
 }
 
 /// Take an initial function blacklist and extend it
@@ -513,99 +721,23 @@ pub fn bump_core_func_idxs<'a, 'b: 'a, 'c>(
 /// This also means that functions they call should *also* not make external calls
 /// This function adds functions to the blacklist if they are called by blacklisted functions
 /// This happens recursively until all blacklisted functions are listed
-pub fn process_blacklist<'a, 'b: 'a>(
-    wat: &'a Wat,
-    blacklist: Vec<Index<'a>>,
+pub fn process_blacklist<'a>(
+    wat: &'a Wat<'a>,
     noise_level: NoiseLevel,
-) -> parser::Result<Vec<(Index<'a>, &'a Func<'a>)>> {
+) -> parser::Result<Vec<&'a Func<'a>>> {
     // logic:
-    // we have a vector of export references
-    // that label the name of the export and the module where it was defined
+    // we find every realloc function, and the module where it was defined
     // we have a queue of functions to get done
     // we go through every function in the queue
-    // finding its moduledef and which function it corresponds to
-    // by finding the export statement that exports the name we'ere looking for
+    // finding the functions it calls in the same module
+
+    let root = Scope::root(wat)?;
+    let mut queue = Vec::new();
+    find_realloc_funcs(&[&root], &mut queue, noise_level)?;
+    let mut blacklist: Vec<&Func> = Vec::new();
 
-    let queue = map_idx_to_module(wat, blacklist, noise_level)?;
-    let mut blacklist: Vec<(Index, &Func)> = Vec::new();
-    let fields = get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))?;
-    // create a module map
-    let mut mods = Vec::new();
-    for field in fields {
-        if let ComponentField::CoreModule(m) = field {
-            mods.push(m);
-        }
-    }
-    // helper function
-    fn idx_to_func<'a, 'b: 'a, 'c: 'b>(
-        idx: Index<'a>,
-        fields: &'c Vec<ModuleField<'a>>,
-    ) -> Option<&'a Func<'a>> {
-        let mut func = None;
-        match idx {
-            Index::Id(id) => {
-                for field in fields {
-                    if let ModuleField::Func(f) = field {
-                        if let Some(f_id) = f.id {
-                            if f_id == id {
-                                func = Some(f);
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
-            Index::Num(num, _) => {
-                let mut idx = 0;
-                for field in fields {
-                    if let ModuleField::Func(f) = field {
-                        if idx == num {
-                            func = Some(f);
-                            break;
-                        }
-                        idx += 1;
-                    }
-                }
-            }
-        }
-        func
-    }
-    // map exports to function idxs
-    let mut queue = queue
-        .into_iter()
-        .map(|(mod_idx, export_name)| {
-            if let Index::Num(num, _) = mod_idx {
-                if let CoreModuleKind::Inline { fields } = &mods[num as usize].kind {
-                    // find the export we're looking for
-                    let mut func = None;
-                    for field in fields {
-                        if let ModuleField::Export(exp) = field {
-                            if exp.name == export_name && exp.kind == ExportKind::Func {
-                                // do code
-                                let func_idx = exp.item;
-                                func = idx_to_func(func_idx, fields);
-                                if func.is_some() {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    let func = func.unwrap();
-                    (mod_idx, func)
-                } else {
-                    todo!()
-                }
-            } else {
-                todo!()
-            }
-        })
-        .collect::<Vec<_>>();
-    // Now we have an iterator of module references and functions
     // Now we go through each func,
-    while let Some((mod_idx, func)) = queue.pop() {
+    while let Some((fields, func)) = queue.pop() {
         if noise_level.debug() {
             println_annotate_dbg(format!(
                 "Blacklisting func id: {:?}, name: {:?}",
@@ -613,51 +745,31 @@ pub fn process_blacklist<'a, 'b: 'a>(
             ));
         }
 
-        if let wast::core::FuncKind::Inline {
+        if let FuncKind::Inline {
             locals: _,
             expression,
         } = &func.kind
         {
             // we can do this comparison based on spans i think
             // bc they should be unique to the function
-            if blacklist.iter().any(|(_, f)| f.span == func.span) {
+            if blacklist.iter().any(|f| f.span == func.span) {
                 continue;
             }
 
-            //  the todos can be here bc like
-            // if we already have a funcref then this kinda has to be a core inline module
-            // but we can rewrite later
-            if let Index::Num(num, _) = mod_idx {
-                if let CoreModuleKind::Inline { fields } = &mods[num as usize].kind {
-                    for instr in &*expression.instrs {
-                        if let Instruction::Call(f_idx) = instr {
-                            // find the function reference, which will be part of the current module
-                            let new_func = idx_to_func(*f_idx, fields);
-                            if new_func.is_none() {
-                                // Most likely an import
-                                // For now, we can just decide to skip adding it
-                                // We would do this anyway bc once it calls out to an import it's leaving the instance anyway
-                                continue;
-                            }
-                            let new_func = new_func.unwrap();
-                            queue.push((mod_idx, new_func));
-                        }
-                    }
-                } else {
-                    todo!()
+            for instr in &*expression.instrs {
+                if let Instruction::Call(f_idx) = instr {
+                    // find the function reference, which will be part of the current module
+                    let Some(new_func) = idx_to_func(*f_idx, fields) else {
+                        // Most likely an import
+                        // For now, we can just decide to skip adding it
+                        // We would do this anyway bc once it calls out to an import it's leaving the instance anyway
+                        continue;
+                    };
+                    queue.push((fields, new_func));
                 }
-            } else {
-                if noise_level.err() {
-                    println_annotate_error(format!(
-                        "error: module index was an id. module index: ${:?}",
-                        mod_idx
-                    ));
-                }
-
-                todo!()
             }
 
-            blacklist.push((mod_idx, func));
+            blacklist.push(func);
             if noise_level.debug() {
                 println_annotate_dbg(format!(
                     "Finished blacklisting func id: {:?}, name: {:?}",
@@ -670,213 +782,537 @@ pub fn process_blacklist<'a, 'b: 'a>(
     Ok(blacklist)
 }
 
-/// Given a blacklist of export indices, map them to a blacklist of indices and functio names
-fn map_idx_to_module<'a, 'b: 'a>(
-    wat: &'a Wat,
-    blacklist: Vec<Index<'a>>,
-    noise_level: NoiseLevel,
-) -> parser::Result<Vec<(Index<'a>, &'a str)>> {
-    let mut out = Vec::new();
-    let mut core_func_idx = 0;
-    let mut core_instances: Vec<Option<&ItemRef<_>>> = Vec::new();
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
+/// Find the function defined in a module's fields that an index refers to
+/// Returns `None` if the index refers to an imported function
+fn idx_to_func<'a>(idx: Index<'a>, fields: &'a [ModuleField<'a>]) -> Option<&'a Func<'a>> {
+    // Imported functions come first in the function index space
+    let mut func_idx = 0;
+    for field in fields {
         match field {
-            ComponentField::Alias(a) => {
-                if let AliasTarget::CoreExport {
-                    instance,
-                    name,
-                    kind,
-                } = a.target
-                {
-                    if kind == ExportKind::Func {
-                        if blacklist.iter().any(|idx| match idx {
-                            Index::Num(num, _) => *num == core_func_idx,
-                            Index::Id(_) => todo!(),
-                        }) {
-                            // do something with the instance info
-                            let idx = match instance {
-                                Index::Num(num, _) => num,
-                                Index::Id(_) => todo!(),
-                            } as usize;
-                            let instance_ref: &ItemRef<_> = core_instances[idx].unwrap();
-                            out.push((instance_ref.idx, name));
-                        }
-                        core_func_idx += 1;
+            ModuleField::Import(i) => {
+                if let ItemKind::Func(_) = i.item.kind {
+                    match idx {
+                        Index::Id(id) if i.item.id == Some(id) => return None,
+                        Index::Num(num, _) if num == func_idx => return None,
+                        _ => {}
                     }
+                    func_idx += 1;
                 }
             }
-            ComponentField::CoreFunc(_) => {
-                if noise_level.err() {
-                    println_annotate_error("TODO: parse core func");
+            ModuleField::Func(f) => {
+                let found = match idx {
+                    Index::Id(id) => f.id == Some(id),
+                    Index::Num(num, _) => num == func_idx,
+                };
+                if found {
+                    return match f.kind {
+                        FuncKind::Import(_) => None,
+                        FuncKind::Inline { .. } => Some(f),
+                    };
                 }
-
-                core_func_idx += 1;
+                func_idx += 1;
             }
-            ComponentField::CoreInstance(i) => match &i.kind {
-                CoreInstanceKind::Instantiate { module, .. } => {
-                    core_instances.push(Some(module));
-                }
-                CoreInstanceKind::BundleOfExports(_) => {
-                    core_instances.push(None);
-                }
-            },
             _ => {}
         }
     }
-    Ok(out)
+    None
 }
 
-/// Increase all type indices to ensure they point to the correct types
-pub fn bump_type_idxs(
-    wat: &Wat,
-    output: &mut String,
-    total_increment: &mut OffsetTracker,
-    lower_bound: u32,
-) -> parser::Result<()> {
-    // Things to bump
-    // canon resource.drop
-    // type( func (result $ty))
-    // func (type $ty)
-    // i think thats it
-
-    // TODO compute this instead of hardcoding
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        // function to recurse for type structs
-        fn idxs_in_type<'a>(ty: &'a Type) -> Vec<Index<'a>> {
-            let mut idxs = Vec::new();
-            match &ty.def {
-                TypeDef::Defined(_) => (),
-                TypeDef::Func(f) => {
-                    for param in f.params.iter() {
-                        if let ComponentValType::Ref(idx) = param.ty {
-                            idxs.push(idx);
-                        }
-                    }
-                    for result in f.results.iter() {
-                        if let ComponentValType::Ref(idx) = result.ty {
-                            idxs.push(idx);
-                        }
-                    }
-                }
-                TypeDef::Component(c) => {
-                    for decl in &c.decls {
-                        match decl {
-                            ComponentTypeDecl::CoreType(_ct) => {}
-                            ComponentTypeDecl::Type(ty) => idxs.append(&mut idxs_in_type(ty)),
-                            ComponentTypeDecl::Alias(_) => {}
-                            ComponentTypeDecl::Import(_i) => {}
-                            ComponentTypeDecl::Export(_e) => {}
-                        }
-                    }
+/// Find the module and function that a core function in the innermost scope is aliased from
+/// Functions re-exported by a bundle of exports are followed back to the instance they come from
+/// Returns `None` for functions without module code of their own, like canonical functions and functions a module imports
+/// It's an error if the function can't be traced back, since instrumenting it like any other function makes the component trap
+fn map_idx_to_module<'a>(
+    scopes: &[&Scope<'a>],
+    func: &'a CoreItemRef<'a, kw::func>,
+    noise_level: NoiseLevel,
+) -> parser::Result<Option<(&'a Vec<ModuleField<'a>>, &'a Func<'a>)>> {
+    resolve_core_func(scopes, func.idx, func.export_name, noise_level)
+}
+
+/// Find the module and function that the core function at `idx` is aliased from, or the function that `export_name` names in the core instance at `idx`
+fn resolve_core_func<'a>(
+    scopes: &[&Scope<'a>],
+    idx: Index<'a>,
+    export_name: Option<&'a str>,
+    noise_level: NoiseLevel,
+) -> parser::Result<Option<(&'a Vec<ModuleField<'a>>, &'a Func<'a>)>> {
+    let scope = scopes.last().unwrap();
+    // `(realloc $instance "name")` refers to the instance export directly
+    let (instance, name) = match export_name {
+        Some(name) => (idx, name),
+        None => match scope.index_map.field(IndexSpace::CoreFunc, idx) {
+            Some(ComponentField::Alias(Alias {
+                target:
+                    AliasTarget::CoreExport {
+                        instance,
+                        name,
+                        kind: ExportKind::Func,
+                    },
+                ..
+            })) => (*instance, *name),
+            Some(ComponentField::CoreFunc(CoreFunc {
+                kind: CoreFuncKind::Alias(alias),
+                ..
+            })) => (alias.instance, alias.name),
+            // Canonical functions don't run any module code themselves
+            Some(ComponentField::CoreFunc(_) | ComponentField::CanonicalFunc(_)) => {
+                if noise_level.debug() {
+                    println_annotate_dbg(format!(
+                        "Core func {:?} is a canonical function, so it has no code to blacklist",
+                        idx
+                    ));
                 }
-                TypeDef::Instance(_i) => {}
-                TypeDef::Resource(_r) => {}
+                return Ok(None);
             }
-            idxs
+            _ => {
+                return Err(Error::new(
+                    idx.span(),
+                    format!(
+                        "Couldn't find core func {:?}, which is used as a realloc or post-return function",
+                        idx
+                    ),
+                ))
+            }
+        },
+    };
+    let module = match scope.index_map.field(IndexSpace::CoreInstance, instance) {
+        Some(ComponentField::CoreInstance(CoreInstance {
+            kind: CoreInstanceKind::Instantiate { module, .. },
+            ..
+        })) if module.export_names.is_empty() => module,
+        Some(ComponentField::CoreInstance(CoreInstance {
+            kind: CoreInstanceKind::BundleOfExports(exports),
+            ..
+        })) => {
+            let Some(export) = exports
+                .iter()
+                .find(|export| export.name == name && export.item.kind == ExportKind::Func)
+            else {
+                return Err(Error::new(
+                    instance.span(),
+                    format!(
+                        "Core instance {:?} doesn't export a function named {:?}, which is used as a realloc or post-return function",
+                        instance, name
+                    ),
+                ));
+            };
+            return resolve_core_func(scopes, export.item.idx, export.item.export_name, noise_level);
+        }
+        _ => {
+            return Err(Error::new(
+                instance.span(),
+                format!(
+                    "Couldn't find the module instantiated by core instance {:?}, which a realloc or post-return function is exported from",
+                    instance
+                ),
+            ))
         }
+    };
+    let Some(fields) = resolve_module(scopes, module.idx) else {
+        // Only inline modules are instrumented, so there's nothing to keep from calling out
+        if noise_level.debug() {
+            println_annotate_dbg(format!(
+                "Core module {:?} isn't defined inline, so functions it exports can't be blacklisted",
+                module.idx
+            ));
+        }
+        return Ok(None);
+    };
+    // find the export we're looking for
+    let Some(export) = fields.iter().find_map(|field| match field {
+        ModuleField::Export(exp) if exp.name == name && exp.kind == ExportKind::Func => {
+            Some(exp.item)
+        }
+        _ => None,
+    }) else {
+        return Err(Error::new(
+            instance.span(),
+            format!(
+                "Core module {:?} doesn't export a function named {:?}, which is used as a realloc or post-return function",
+                module.idx, name
+            ),
+        ));
+    };
+    Ok(idx_to_func(export, fields).map(|func| (fields, func)))
+}
+
+/// Wire the counter function into every component that instantiates core modules
+/// The root component imports the function from the host, and nested components import it from the component that instantiates them
+pub fn add_counter_plumbing<'a>(
+    wat: &'a Wat<'a>,
+    text: &str,
+    total_increment: &mut OffsetTracker,
+    noise_level: NoiseLevel,
+) -> parser::Result<()> {
+    let root = Scope::root(wat)?;
+    let mut plumbed = HashSet::new();
+    if !add_scope_plumbing(&[&root], &mut plumbed, text, total_increment, noise_level) {
+        return Err(Error::new(
+            wat.span(),
+            "Input component doesn't instantiate any core modules".to_string(),
+        ));
+    }
+    Ok(())
+}
 
+/// Wire the counter function into the innermost scope, after wiring it into the components nested in it
+/// `plumbed` holds the offsets of nested components that import the counter function, which have to be passed it when they're instantiated
+/// Returns whether the scope needed the counter function
+fn add_scope_plumbing<'a>(
+    scopes: &[&Scope<'a>],
+    plumbed: &mut HashSet<usize>,
+    text: &str,
+    total_increment: &mut OffsetTracker,
+    noise_level: NoiseLevel,
+) -> bool {
+    let scope = *scopes.last().unwrap();
+    let mut instrumented_instantiations = Vec::new();
+    let mut plumbed_instantiations = Vec::new();
+    for (position, field) in scope.fields.iter().enumerate() {
         match field {
-            ComponentField::CoreFunc(f) => {
-                if let CoreFuncKind::ResourceDrop(rd) = &f.kind {
-                    total_increment.increment_idx(output, rd.ty, Some(lower_bound));
+            ComponentField::Component(c) => {
+                if let Some(nested) = Scope::nested(c) {
+                    let mut stack = scopes.to_vec();
+                    stack.push(&nested);
+                    if add_scope_plumbing(&stack, plumbed, text, total_increment, noise_level) {
+                        plumbed.insert(c.span.offset());
+                    }
                 }
             }
-            // make this a function in this scope ig
-            ComponentField::Type(t) => {
-                let idxs = idxs_in_type(t);
-                for idx in idxs {
-                    total_increment.increment_idx(output, idx, Some(lower_bound));
-                }
+            ComponentField::CoreInstance(
+                ci @ CoreInstance {
+                    kind: CoreInstanceKind::Instantiate { module, .. },
+                    ..
+                },
+            ) if module.export_names.is_empty()
+                && resolve_module(scopes, module.idx).is_some_and(imports_inc_func) =>
+            {
+                instrumented_instantiations.push((position, ci));
             }
-            ComponentField::Func(f) => {
-                if let FuncKind::Lift {
-                    ty: ComponentTypeUse::Ref(r),
+            ComponentField::Instance(
+                i @ Instance {
+                    kind: InstanceKind::Instantiate { component, .. },
                     ..
-                } = &f.kind
-                {
-                    total_increment.increment_idx(output, r.idx, Some(lower_bound));
-                }
+                },
+            ) if component.export_names.is_empty()
+                && resolve_component(scopes, component.idx)
+                    .is_some_and(|(_, c)| plumbed.contains(&c.span.offset())) =>
+            {
+                plumbed_instantiations.push((position, i));
+            }
+            ComponentField::CoreModule(CoreModule {
+                id,
+                span,
+                kind: CoreModuleKind::Import { .. },
+                ..
+            })
+            | ComponentField::Import(ComponentImport {
+                span,
+                item:
+                    ItemSig {
+                        id,
+                        kind: ItemSigKind::CoreModule(_),
+                        ..
+                    },
+                ..
+            }) if noise_level.err() => {
+                println_annotate_error(format!(
+                    "Core module {} is imported, so its functions can't be instrumented",
+                    describe(*id, *span)
+                ));
+            }
+            ComponentField::Alias(Alias {
+                id,
+                span,
+                target:
+                    AliasTarget::Export {
+                        instance,
+                        name,
+                        kind: ComponentExportAliasKind::CoreModule,
+                    },
+                ..
+            }) if noise_level.err() && resolve_module_export(scopes, *instance, name).is_none() => {
+                println_annotate_error(format!(
+                    "Core module {} is aliased from an instance of a component that isn't defined inline, so its functions can't be instrumented",
+                    describe(*id, *span)
+                ));
             }
             _ => {}
         }
     }
 
-    Ok(())
+    let first_core_instantiation = instrumented_instantiations
+        .first()
+        .map(|(position, _)| *position);
+    // The import has to come before anything that uses the counter function
+    let Some(import_position) = [
+        inc_import_position(scope.fields),
+        first_core_instantiation,
+        plumbed_instantiations
+            .first()
+            .map(|(position, _)| *position),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+    .filter(|_| first_core_instantiation.is_some() || !plumbed_instantiations.is_empty()) else {
+        return false;
+    };
+
+    let mut shifts = IndexShifts::new();
+    add_inc_import_section(scope.fields, import_position, total_increment, &mut shifts);
+    if let Some(position) = first_core_instantiation {
+        for (_, instance) in instrumented_instantiations {
+            add_instantiaion_arg(instance, text, total_increment, noise_level);
+        }
+        add_canon_lower_and_instance(scope.fields, position, total_increment, &mut shifts);
+    }
+    for (_, instance) in plumbed_instantiations {
+        add_component_instantiation_arg(instance, text, total_increment, noise_level);
+    }
+    total_increment.set_pass("index shifts");
+    shifts.apply(scope.id, scope.fields, total_increment);
+    total_increment.set_pass("counter plumbing");
+    true
 }
 
-/// Add the wrapper instance to the instatiation calls of all other instances
-pub fn add_instantiaion_arg(
-    wat: &Wat,
-    output: &mut String,
+/// Export the flush function of every instance of a flushed module from the root component, so the runner can call them once the component is done
+/// Instances in nested components are exported from their component, and then re-exported through every instance of it up to the root
+pub fn add_flush_exports<'a>(
+    wat: &'a Wat<'a>,
+    flushed_modules: &[(Span, &'a Vec<ModuleField<'a>>)],
+    names: &FlushNames,
     total_increment: &mut OffsetTracker,
+    text: &str,
     noise_level: NoiseLevel,
 ) -> parser::Result<()> {
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        if let ComponentField::CoreInstance(ci) = field {
-            if let CoreInstanceKind::Instantiate { .. } = &ci.kind {
-                if noise_level.debug() {
-                    println_annotate_dbg(format!(
-                        "Core instance: {ci:?}, offset: {}",
-                        ci.span.offset()
-                    ));
+    if flushed_modules.is_empty() {
+        return Ok(());
+    }
+    let root = Scope::root(wat)?;
+    let mut exporter = FlushExporter {
+        flushed_modules,
+        names,
+        total_increment,
+        text,
+        components: HashMap::new(),
+        named_instances: 0,
+    };
+    let exported = exporter
+        .export_scope(&[&root], wat.span())?
+        .into_iter()
+        .map(|(_, module_offset)| module_offset)
+        .collect::<HashSet<_>>();
+
+    for (module_span, _) in flushed_modules {
+        if !exported.contains(&module_span.offset()) && noise_level.err() {
+            println_annotate_error(format!(
+                "Core module at offset {} isn't instantiated by the root component or any component instance in it, so the coverage of its `realloc` and post-return functions can't be collected",
+                module_span.offset()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the exports of flush functions to a component and the components nested in it
+struct FlushExporter<'a, 'b> {
+    flushed_modules: &'b [(Span, &'a Vec<ModuleField<'a>>)],
+    names: &'b FlushNames,
+    total_increment: &'b mut OffsetTracker,
+    text: &'b str,
+    /// The flush exports of each nested component that's been visited, by the offset of its definition
+    components: HashMap<usize, Vec<(String, usize)>>,
+    named_instances: usize,
+}
+
+impl<'a> FlushExporter<'a, '_> {
+    /// Export a flush function from the innermost scope for each instance of a flushed module in it, or in an instance of a nested component
+    /// The exports are added to the end of the component, so no indices have to be shifted
+    /// Returns the name of each export, along with the offset of the module it flushes
+    fn export_scope(
+        &mut self,
+        scopes: &[&Scope<'a>],
+        span: Span,
+    ) -> parser::Result<Vec<(String, usize)>> {
+        let scope = scopes.last().unwrap();
+        let mut exports = Vec::new();
+        let mut msg = String::new();
+        for field in scope.fields {
+            match field {
+                ComponentField::CoreInstance(
+                    ci @ CoreInstance {
+                        kind: CoreInstanceKind::Instantiate { module, .. },
+                        ..
+                    },
+                ) if module.export_names.is_empty() => {
+                    let Some((module_span, _)) =
+                        resolve_module(scopes, module.idx).and_then(|fields| {
+                            self.flushed_modules
+                                .iter()
+                                .find(|(_, f)| std::ptr::eq(*f, fields))
+                        })
+                    else {
+                        continue;
+                    };
+                    let instance_id =
+                        self.instance_id(ci.id, ci.span.offset() + "core instance".len());
+                    let name = format!("{}{}", self.names.export, exports.len());
+                    msg += &format!(
+                        "(func (export \"{}\") (canon lift (core func ${} \"{}\")))\n",
+                        name, instance_id, self.names.func
+                    );
+                    exports.push((name, module_span.offset()));
                 }
-                // parse with regex
-                let msg = format!(
-                    "(with \"{}\" (instance ${}))",
-                    INC_MODULE_NAME, INC_MODULE_NAME
-                );
-                let re = Regex::new(INSTANTIATION_REGEX_STR).unwrap();
-                let c = |s: &mut String, _, end| {
-                    s.insert_str(end, &msg);
-                    (end, msg.len())
-                };
-                total_increment.modify_with_regex_match(output, &re, ci.span.offset(), c);
+                ComponentField::Instance(
+                    i @ Instance {
+                        kind: InstanceKind::Instantiate { component, .. },
+                        ..
+                    },
+                ) if component.export_names.is_empty() => {
+                    let Some((depth, nested_component)) = resolve_component(scopes, component.idx)
+                    else {
+                        continue;
+                    };
+                    let key = nested_component.span.offset();
+                    let nested_exports = match self.components.get(&key) {
+                        Some(nested_exports) => nested_exports.clone(),
+                        None => {
+                            let Some(nested) = Scope::nested(nested_component) else {
+                                continue;
+                            };
+                            let mut stack = scopes[..=depth].to_vec();
+                            stack.push(&nested);
+                            let nested_exports =
+                                self.export_scope(&stack, nested_component.span)?;
+                            self.components.insert(key, nested_exports.clone());
+                            nested_exports
+                        }
+                    };
+                    if nested_exports.is_empty() {
+                        continue;
+                    }
+                    let instance_id = self.instance_id(i.id, i.span.offset() + "instance".len());
+                    for (nested_name, module_offset) in nested_exports {
+                        let name = format!("{}{}", self.names.export, exports.len());
+                        msg += &format!(
+                            "(export \"{}\" (func ${} \"{}\"))\n",
+                            name, instance_id, nested_name
+                        );
+                        exports.push((name, module_offset));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !msg.is_empty() {
+            let end = find_closing_paren(self.text, span.offset() - 1).ok_or(Error::new(
+                span,
+                "Couldn't find the end of the component".to_string(),
+            ))?;
+            self.total_increment.insert(end, &msg);
+        }
+        Ok(exports)
+    }
+
+    /// Get the id of an instance, giving it one at `id_offset` if it doesn't have one
+    /// Unnamed instances are given a name, so the exports don't depend on index shifts
+    fn instance_id(&mut self, id: Option<Id>, id_offset: usize) -> String {
+        match id {
+            Some(id) => id.name().to_string(),
+            None => {
+                let id = format!("{}{}", self.names.instance, self.named_instances);
+                self.named_instances += 1;
+                self.total_increment.insert(id_offset, &format!(" ${}", id));
+                id
             }
         }
     }
-    Ok(())
 }
 
-/// Ad the functions to lower the imported function and wrap it in an instance
-pub fn add_canon_lower_and_instance(
-    wat: &Wat,
-    output: &mut String,
+/// Add the wrapper instance to the instantiation of a module that imports the counter function
+pub fn add_instantiaion_arg(
+    ci: &CoreInstance,
+    text: &str,
     total_increment: &mut OffsetTracker,
-) -> parser::Result<()> {
+    noise_level: NoiseLevel,
+) {
+    let re = Regex::new(INSTANTIATION_REGEX_STR).unwrap();
+    if noise_level.debug() {
+        println_annotate_dbg(format!(
+            "Core instance: {ci:?}, offset: {}",
+            ci.span.offset()
+        ));
+    }
+    // parse with regex
+    let msg = format!(
+        "(with \"{}\" (instance ${}))",
+        INC_MODULE_NAME, INC_MODULE_NAME
+    );
+    let c = |_, end| (end, msg);
+    if !total_increment.modify_with_regex_match(text, &re, ci.span.offset(), c) && noise_level.err()
+    {
+        println_annotate_error(format!(
+            "Couldn't pass the counter function to the core instance at offset {}",
+            ci.span.offset()
+        ));
+    }
+}
+
+/// Pass the counter function to an instance of a nested component that imports it
+pub fn add_component_instantiation_arg(
+    instance: &Instance,
+    text: &str,
+    total_increment: &mut OffsetTracker,
+    noise_level: NoiseLevel,
+) {
+    let re = Regex::new(COMPONENT_INSTANTIATION_REGEX_STR).unwrap();
+    let msg = format!("(with \"{0}\" (func ${0}))", INC_FUNC_NAME);
+    let c = |_, end| (end, msg);
+    if !total_increment.modify_with_regex_match(text, &re, instance.span.offset(), c)
+        && noise_level.err()
+    {
+        println_annotate_error(format!(
+            "Couldn't pass the counter function to the component instance at offset {}",
+            instance.span.offset()
+        ));
+    }
+}
+
+/// Ad the functions to lower the imported function and wrap it in an instance, in front of `fields[position]`
+pub fn add_canon_lower_and_instance<'a>(
+    fields: &'a [ComponentField<'a>],
+    position: usize,
+    total_increment: &mut OffsetTracker,
+    shifts: &mut IndexShifts,
+) {
     let canon_lower = format!("(core func ${0} (canon lower (func ${0})))", INC_FUNC_NAME);
     let instantiate = format!(
         "(core instance ${0} (export \"{1}\" (func ${1})))",
         INC_MODULE_NAME, INC_FUNC_NAME
     );
 
-    let mut has_passed_modules = false;
-    let mut offset = None;
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        match field {
-            ComponentField::CoreModule(_) => has_passed_modules = true,
-            _ => {
-                if has_passed_modules && get_span(field).is_some() {
-                    offset = Some(get_span(field).unwrap().offset());
-                    break;
-                }
-            }
-        }
-    }
-    let offset = offset.unwrap() - 1;
+    let offset = get_span(&fields[position]).unwrap().offset() - 1;
     let msg = format!("{}\n{}\n", canon_lower, instantiate);
-    total_increment.add_to_string(output, offset, &msg);
+    total_increment.insert(offset, &msg);
+    shifts.insert(fields, position, IndexSpace::CoreFunc);
+    shifts.insert(fields, position, IndexSpace::CoreInstance);
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_names_that_are_not_used() {
+        let text = "(func $wcov-flush (export \"wcov-counter7\"))";
+        let names = FlushNames::new(text);
+        assert_eq!(names.func, "wcov-flush1");
+        // Nothing may start with a prefix, so counters can't clash either
+        assert_eq!(names.counter, "wcov-counter1");
+        assert_eq!(names.instance, FLUSH_INSTANCE_NAME);
+        assert_eq!(names.export, FLUSH_EXPORT_PREFIX);
+    }
 }
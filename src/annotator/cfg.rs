@@ -0,0 +1,72 @@
+use wast::core::Instruction;
+
+use crate::annotator::CounterType;
+
+/// A basic block in a function body, which always runs from its first instruction to its last
+#[derive(Clone, Copy, Debug)]
+pub struct BasicBlock {
+    /// The index of the first instruction in the block
+    pub start: usize,
+    /// The control flow instruction that enters the block
+    pub kind: CounterType,
+}
+
+/// Split the instructions of a function body into basic blocks, in the order they appear
+/// A new block starts after every instruction that branches or can be branched to, and the first block starts at the beginning of the body
+/// Blocks that would be empty, because they start right at the end of a control flow structure, are left out
+pub fn basic_blocks(instrs: &[Instruction]) -> Vec<BasicBlock> {
+    let mut blocks = Vec::new();
+    let mut next_kind = Some(CounterType::Block);
+    for (idx, instr) in instrs.iter().enumerate() {
+        if let Some(kind) = next_kind.take() {
+            if !ends_structure(instr) {
+                blocks.push(BasicBlock { start: idx, kind });
+            }
+        }
+        next_kind = leader_kind(instr);
+    }
+    blocks
+}
+
+/// Get the kind of block that starts right after an instruction, if one does
+fn leader_kind(instr: &Instruction) -> Option<CounterType> {
+    match instr {
+        Instruction::If(_) => Some(CounterType::If),
+        Instruction::Else(_) => Some(CounterType::Else),
+        // Branches back to a loop go to the start of its body
+        Instruction::Loop(_) => Some(CounterType::Loop),
+        // Branches out of a block, and the code following a branch, start a new block
+        Instruction::End(_)
+        | Instruction::Br(_)
+        | Instruction::BrIf(_)
+        | Instruction::BrTable(_)
+        | Instruction::BrOnNull(_)
+        | Instruction::BrOnNonNull(_)
+        | Instruction::BrOnCast(_)
+        | Instruction::BrOnCastFail(_)
+        | Instruction::Return
+        | Instruction::ReturnCall(_)
+        | Instruction::ReturnCallIndirect(_)
+        | Instruction::ReturnCallRef(_)
+        | Instruction::Unreachable
+        | Instruction::Throw(_)
+        | Instruction::ThrowRef
+        | Instruction::Rethrow(_)
+        | Instruction::Catch(_)
+        | Instruction::CatchAll
+        | Instruction::Delegate(_) => Some(CounterType::Block),
+        _ => None,
+    }
+}
+
+/// Check whether an instruction ends the body of a control flow structure
+fn ends_structure(instr: &Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::Else(_)
+            | Instruction::End(_)
+            | Instruction::Catch(_)
+            | Instruction::CatchAll
+            | Instruction::Delegate(_)
+    )
+}
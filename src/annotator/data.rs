@@ -1,13 +1,13 @@
 use std::{
-    collections::HashMap,
-    ops::Deref,
+    collections::{BTreeMap, HashMap},
+    ops::{Deref, Range},
     path::{self, Path},
     sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::annotator::debug::SourceDebugInfo;
+use crate::annotator::debug::{DebugFuncInfo, SourceDebugInfo, SourceLocation};
 
 /// A trait to consildate functions across the two types of DebugData structs
 pub trait DebugData {
@@ -17,6 +17,8 @@ pub trait DebugData {
     fn blocks_per_line(&self) -> &HashMap<usize, Vec<(u64, u64)>>;
     /// Access the vector of `SourceDebugInfo` structs in this debug data struct
     fn sdi_vec(&self) -> &Vec<SourceDebugInfo>;
+    /// Returns a hashmap mapping file map indices to the MD5 checksums DWARF records for them, as hex strings
+    fn file_checksums(&self) -> &HashMap<usize, String>;
 
     /// Display the counters on each line for every file in the map
     fn print_idxs_for_file(&self) {
@@ -33,6 +35,13 @@ pub trait DebugData {
             }
         }
     }
+    /// Get the MD5 checksum of the file at a file path, if the debug info records one
+    fn get_checksum_from_file(&self, file: &Path) -> Option<&str> {
+        self.file_checksums()
+            .iter()
+            .find(|(idx, _)| self.file_map_idx(**idx).is_some_and(|f| &**f == file))
+            .map(|(_, checksum)| checksum.as_str())
+    }
     /// Get the SDI matching a file path, if one exists
     fn get_sdi_from_file(&self, file: &Path) -> Option<&SourceDebugInfo> {
         self.sdi_vec().iter().find(|sdi| {
@@ -47,10 +56,16 @@ pub trait DebugData {
 pub struct DebugDataOwned {
     /// Maps indices to file paths
     pub file_map: Vec<path::PathBuf>,
+    /// Maps file map indices to the MD5 checksums of the files, for files DWARF records one for
+    pub file_checksums: HashMap<usize, String>,
     /// Contains the number of blocks in a specific line of code
     pub blocks_per_line: HashMap<usize, Vec<(u64, u64)>>, // maps file indxs to lines and number of counters
+    /// The source locations covered by each counter, indexed by the counter's index
+    pub counters: Vec<Vec<SourceLocation>>,
     /// A list of source debug information structs
     pub sdi_vec: Vec<SourceDebugInfo>, // A list of source debug information structs
+    /// The line info of each code module, for mapping the frames of a trap back to source
+    pub line_tables: Vec<CodeLineTable>,
 }
 
 impl DebugData for DebugDataOwned {
@@ -69,16 +84,26 @@ impl DebugData for DebugDataOwned {
     fn sdi_vec(&self) -> &Vec<SourceDebugInfo> {
         &self.sdi_vec
     }
+
+    fn file_checksums(&self) -> &HashMap<usize, String> {
+        &self.file_checksums
+    }
 }
 
 /// Like `DebugData`, but with an `Arc` wrapper around the paths to prevent excessive cloning
 pub struct DebugDataArc {
     /// Maps indices to file paths
     pub file_map: Vec<Arc<path::PathBuf>>,
+    /// Maps file map indices to the MD5 checksums of the files, for files DWARF records one for
+    pub file_checksums: HashMap<usize, String>,
     /// Contains the number of blocks in a specific line of code
     pub blocks_per_line: HashMap<usize, Vec<(u64, u64)>>, // maps file indxs to lines and number of counters
+    /// The source locations covered by each counter, indexed by the counter's index
+    pub counters: Vec<Vec<SourceLocation>>,
     /// A list of source debug information structs
     pub sdi_vec: Vec<SourceDebugInfo>, // A list of source debug information structs
+    /// The line info of each code module, for mapping the frames of a trap back to source
+    pub line_tables: Vec<CodeLineTable>,
 }
 
 impl DebugData for DebugDataArc {
@@ -96,14 +121,125 @@ impl DebugData for DebugDataArc {
     fn sdi_vec(&self) -> &Vec<SourceDebugInfo> {
         &self.sdi_vec
     }
+
+    fn file_checksums(&self) -> &HashMap<usize, String> {
+        &self.file_checksums
+    }
 }
 
 impl From<DebugDataOwned> for DebugDataArc {
     fn from(value: DebugDataOwned) -> Self {
         DebugDataArc {
             file_map: value.file_map.into_iter().map(Arc::new).collect(),
+            file_checksums: value.file_checksums,
             blocks_per_line: value.blocks_per_line,
+            counters: value.counters,
             sdi_vec: value.sdi_vec,
+            line_tables: value.line_tables,
+        }
+    }
+}
+
+/// The line info of a code module, along with where its code ended up once it was instrumented
+#[derive(Serialize, Deserialize, Default)]
+pub struct CodeLineTable {
+    /// Where the module's instrumented code is, as offsets in the binary that runs
+    /// This is empty until the instrumented binary has been encoded
+    pub code_range: Range<usize>,
+    /// The original address of each instruction in the instrumented code, both relative to the `code` section
+    /// The probe in front of an instruction maps to the same original address as the instruction
+    pub probe_offsets: BTreeMap<u64, u64>,
+    /// The source location of each line row in the original code, by address
+    pub rows: BTreeMap<u64, SourceLocation>,
+    /// The functions in the original code, sorted by address
+    pub functions: Vec<DebugFuncInfo>,
+}
+
+impl CodeLineTable {
+    /// Get the original address of an offset in the binary that runs, if it's in this module's instrumented code
+    pub fn original_address(&self, offset: usize) -> Option<u64> {
+        if !self.code_range.contains(&offset) {
+            return None;
         }
+        let address = (offset - self.code_range.start) as u64;
+        let (new_address, original) = self.probe_offsets.range(..=address).next_back()?;
+        Some(original + (address - new_address))
+    }
+    /// Get the source location of the original instruction at `address`, which is the one of the last row at or before it
+    /// Rows on line 0 aren't attributed to any source line, so they don't have a location
+    pub fn location_at(&self, address: u64) -> Option<&SourceLocation> {
+        self.rows
+            .range(..=address)
+            .next_back()
+            .map(|(_, location)| location)
+            .filter(|(_, line, _, _)| *line != 0)
+    }
+    /// Get the function containing the original instruction at `address`
+    pub fn function_at(&self, address: u64) -> Option<&DebugFuncInfo> {
+        let idx = self
+            .functions
+            .partition_point(|func| func.low_pc <= address);
+        let func = &self.functions[idx.checked_sub(1)?];
+        func.high_pc
+            .is_none_or(|high_pc| address <= high_pc)
+            .then_some(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_table() -> CodeLineTable {
+        CodeLineTable {
+            code_range: 100..200,
+            // A four byte probe was added in front of the instruction at 10
+            probe_offsets: BTreeMap::from([(0, 0), (10, 10), (14, 10)]),
+            rows: BTreeMap::from([(0, (0, 1, 1, 0)), (8, (0, 0, 0, 0)), (10, (0, 3, 5, 0))]),
+            functions: vec![
+                DebugFuncInfo {
+                    low_pc: 0,
+                    high_pc: Some(11),
+                    path_idx: 0,
+                    name: "first".to_string(),
+                },
+                DebugFuncInfo {
+                    low_pc: 20,
+                    high_pc: None,
+                    path_idx: 0,
+                    name: "second".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn maps_offsets_back_to_original_addresses() {
+        let table = line_table();
+        assert_eq!(table.original_address(99), None);
+        assert_eq!(table.original_address(200), None);
+        assert_eq!(table.original_address(105), Some(5));
+        // Both the probe and the instruction after it map to the instruction
+        assert_eq!(table.original_address(112), Some(12));
+        assert_eq!(table.original_address(114), Some(10));
+        assert_eq!(table.original_address(116), Some(12));
+    }
+
+    #[test]
+    fn looks_up_locations_and_functions() {
+        let table = line_table();
+        assert_eq!(table.location_at(4), Some(&(0, 1, 1, 0)));
+        // Rows on line 0 have no location
+        assert_eq!(table.location_at(9), None);
+        assert_eq!(table.location_at(12), Some(&(0, 3, 5, 0)));
+        assert_eq!(
+            table.function_at(11).map(|f| f.name.as_str()),
+            Some("first")
+        );
+        assert!(table.function_at(15).is_none());
+        assert_eq!(
+            table.function_at(500).map(|f| f.name.as_str()),
+            Some("second")
+        );
     }
 }
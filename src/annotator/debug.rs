@@ -1,16 +1,19 @@
 use core::str;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::{Range, RangeBounds};
 use std::path::{self, PathBuf};
 
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use wasmparser::{BinaryReaderError, Parser, Payload::*};
 use wast::core::{Custom, ModuleField};
-use wast::{component::*, Wat};
+use wast::Wat;
 use wast::{parser, Error};
 
-use crate::annotator::data::DebugDataOwned;
+use crate::annotator::data::{CodeLineTable, DebugDataOwned};
+use crate::annotator::filters::{FunctionFilters, PathGlob};
+use crate::annotator::profiles::{excluding_profile, ExclusionProfile};
 use crate::annotator::utils::*;
 use crate::noise::NoiseLevel;
 use crate::printer::{println_annotate_dbg, println_annotate_error};
@@ -26,37 +29,169 @@ pub struct DebugLineInfo {
     pub line: u64,
     /// The source column
     pub column: u64,
-    /// The index of the inline core module where the compiled code this represents is located
+    /// Which block on the source line this row is part of, so blocks on the same line and column can be told apart
+    pub discriminator: u64,
+    /// Whether the row is a recommended breakpoint location, which is where a statement starts
+    pub is_stmt: bool,
+    /// Whether the row is where the function's prologue ends
+    pub prologue_end: bool,
+    /// Whether the row is where an epilogue of the function begins
+    pub epilogue_begin: bool,
+    /// The index of the code module where the compiled code this represents is located, out of the core modules with a `code` section
     pub code_module_idx: usize,
 }
 
+/// A function described by DWARF
+#[derive(Serialize, Deserialize)]
+pub struct DebugFuncInfo {
+    /// The address of the function's first instruction within the `code` section of the module
+    pub low_pc: u64,
+    /// The address of the function's last instruction, if DWARF records where it ends
+    pub high_pc: Option<u64>,
+    /// The index in the path table corresponding to the source file the function is declared in
+    pub path_idx: usize,
+    /// The function's name
+    pub name: String,
+}
+
+/// The debugging information of a single code module, indexed by address
+#[derive(Default)]
+pub struct ModuleDebugInfo {
+    rows: BTreeMap<u64, Vec<DebugLineInfo>>,
+    functions: Vec<DebugFuncInfo>,
+    entries_only: bool,
+}
+
+impl ModuleDebugInfo {
+    /// Whether the rows only mark where functions start, so a block without rows isn't covered by the row before it
+    pub fn entries_only(&self) -> bool {
+        self.entries_only
+    }
+    /// Get the line rows with addresses in `range`, sorted by address
+    /// Rows at the same address are kept in the order they were added
+    pub fn rows_in(&self, range: impl RangeBounds<u64>) -> impl Iterator<Item = &DebugLineInfo> {
+        self.rows.range(range).flat_map(|(_, rows)| rows)
+    }
+    /// Get the row that the instruction at `address` belongs to, which is the last row at or before it
+    pub fn row_at(&self, address: u64) -> Option<&DebugLineInfo> {
+        self.rows
+            .range(..=address)
+            .next_back()
+            .and_then(|(_, rows)| rows.last())
+    }
+    /// Get the last statement row at or before `address`
+    pub fn statement_row_at(&self, address: u64) -> Option<&DebugLineInfo> {
+        self.rows
+            .range(..=address)
+            .rev()
+            .flat_map(|(_, rows)| rows.iter().rev())
+            .find(|dli| dli.is_stmt)
+    }
+    /// Pick the rows in `range` that a counter for the basic block there should cover
+    /// Only statement rows are covered, and the rows inside a prologue or an epilogue are left to the row that starts it
+    /// The prologue runs from the first row of a function to the row marked as its end, so it's only looked for in the function's first block
+    /// An epilogue runs from the row marked as its beginning to the return that ends its block
+    pub fn block_rows(&self, range: Range<u64>, first_block: bool) -> Vec<&DebugLineInfo> {
+        let rows = self
+            .rows_in(range)
+            .filter(|dli| dli.is_stmt)
+            .collect::<Vec<_>>();
+        let mut in_prologue = first_block && rows.iter().any(|dli| dli.prologue_end);
+        let mut in_epilogue = false;
+        let mut picked = Vec::new();
+        for (idx, dli) in rows.into_iter().enumerate() {
+            if in_epilogue {
+                continue;
+            }
+            if in_prologue {
+                if dli.prologue_end {
+                    in_prologue = false;
+                } else if idx > 0 {
+                    continue;
+                }
+            }
+            in_epilogue = dli.epilogue_begin;
+            picked.push(dli);
+        }
+        picked
+    }
+    /// Get the function containing `address`
+    pub fn function_at(&self, address: u64) -> Option<&DebugFuncInfo> {
+        let idx = self
+            .functions
+            .partition_point(|func| func.low_pc <= address);
+        let func = &self.functions[idx.checked_sub(1)?];
+        func.high_pc
+            .is_none_or(|high_pc| address <= high_pc)
+            .then_some(func)
+    }
+    /// Get every function in the module, sorted by address
+    pub fn functions(&self) -> &[DebugFuncInfo] {
+        &self.functions
+    }
+}
+
 /// This struct contains overall debugging information for a Webassembly file
 // TODO: Rename!
 pub struct WatLineMapper {
     code_offsets: Vec<usize>,
-    lines: Vec<DebugLineInfo>,
+    /// The code module index of each inline module, or `None` for modules without a `code` section
+    module_code_sections: Vec<Option<usize>>,
+    modules: Vec<ModuleDebugInfo>,
     file_map: Vec<path::PathBuf>,
+    file_checksums: HashMap<usize, String>,
+    counters: Vec<Vec<SourceLocation>>,
+    excluded_files: BTreeSet<usize>,
+    function_filters: FunctionFilters,
     /// A list of `SourceDebugInfo` structs
     pub sdi_vec: Vec<SourceDebugInfo>,
 }
 
 impl WatLineMapper {
-    /// Create a new `WatLineMapper` from a list of offsets of code sections
-    pub fn new(offsets: Vec<usize>) -> WatLineMapper {
+    /// Create a new `WatLineMapper` from a list of offsets of code sections, and the code section of each inline module
+    pub fn new(offsets: Vec<usize>, module_code_sections: Vec<Option<usize>>) -> WatLineMapper {
         WatLineMapper {
             code_offsets: offsets,
-            lines: Vec::new(),
+            module_code_sections,
+            modules: Vec::new(),
             file_map: Vec::new(),
+            file_checksums: HashMap::new(),
+            counters: Vec::new(),
+            excluded_files: BTreeSet::new(),
+            function_filters: FunctionFilters::default(),
             sdi_vec: Vec::new(),
         }
     }
+    fn module_mut(&mut self, code_module_idx: usize) -> &mut ModuleDebugInfo {
+        if self.modules.len() <= code_module_idx {
+            self.modules
+                .resize_with(code_module_idx + 1, ModuleDebugInfo::default);
+        }
+        &mut self.modules[code_module_idx]
+    }
     /// Add a debug line
-    pub fn add_line(&mut self, line: DebugLineInfo) {
-        if !self.lines().contains(&line) {
-            self.lines.push(line);
-        } else {
-            panic!("duplicate lines???");
+    /// Returns false if the exact same line was already added, in which case it's ignored
+    pub fn add_line(&mut self, line: DebugLineInfo) -> bool {
+        let rows = self
+            .module_mut(line.code_module_idx)
+            .rows
+            .entry(line.address)
+            .or_default();
+        if rows.contains(&line) {
+            return false;
         }
+        rows.push(line);
+        true
+    }
+    /// Mark a code module's rows as only marking where its functions start
+    pub fn set_entries_only(&mut self, code_module_idx: usize) {
+        self.module_mut(code_module_idx).entries_only = true;
+    }
+    /// Add a function to a code module's function index
+    pub fn add_function(&mut self, code_module_idx: usize, func: DebugFuncInfo) {
+        let functions = &mut self.module_mut(code_module_idx).functions;
+        let idx = functions.partition_point(|f| f.low_pc <= func.low_pc);
+        functions.insert(idx, func);
     }
 
     /// Add a file to the file map, and return its index
@@ -81,9 +216,59 @@ impl WatLineMapper {
                 self.file_map.len() - 1
             })
     }
-    /// Gets the all the currently held debug lines
-    pub fn lines(&self) -> &Vec<DebugLineInfo> {
-        &self.lines
+    /// Exclude every file in the file map that belongs to an exclusion profile, apart from the `included` ones, or that matches one of the `excluded_paths`, so its code isn't instrumented
+    pub fn exclude_files(
+        &mut self,
+        included: &[ExclusionProfile],
+        excluded_paths: &[PathGlob],
+        noise_level: NoiseLevel,
+    ) {
+        for (path_idx, path) in self.file_map.iter().enumerate() {
+            let reason = match excluding_profile(path, included) {
+                Some(profile) => format!("the {} profile", profile),
+                None => match excluded_paths.iter().find(|glob| glob.matches(path)) {
+                    Some(glob) => format!("the excluded path `{}`", glob),
+                    None => continue,
+                },
+            };
+            if noise_level.debug() {
+                println_annotate_dbg(format!("Excluding {} with {}", path.display(), reason));
+            }
+            self.excluded_files.insert(path_idx);
+        }
+    }
+    /// Check whether a file's code is excluded from instrumentation
+    pub fn excludes_file(&self, path_idx: usize) -> bool {
+        self.excluded_files.contains(&path_idx)
+    }
+    /// Set the filters that pick which functions are instrumented
+    pub fn set_function_filters(&mut self, filters: FunctionFilters) {
+        self.function_filters = filters;
+    }
+    /// Check whether the function described by `func` is instrumented, going by the function filters
+    pub fn instruments(&self, func: Option<&DebugFuncInfo>) -> bool {
+        self.function_filters.instruments(func, &self.file_map)
+    }
+    /// Add a counter that covers the source locations in `locations`, and return its index
+    pub fn add_counter(&mut self, locations: Vec<SourceLocation>) -> usize {
+        self.counters.push(locations);
+        self.counters.len() - 1
+    }
+    /// Get the code module index of an inline module, which is how many inline modules have been seen before it
+    /// Modules without a `code` section don't have one, so the two indices can differ
+    pub fn code_module_idx(&self, inline_module_idx: usize) -> Option<usize> {
+        self.module_code_sections
+            .get(inline_module_idx)
+            .copied()
+            .flatten()
+    }
+    /// Gets the debugging information of a code module, if it has any
+    pub fn module(&self, code_module_idx: usize) -> Option<&ModuleDebugInfo> {
+        self.modules.get(code_module_idx)
+    }
+    /// Gets the debug lines of every code module, sorted by module and then address
+    pub fn lines(&self) -> impl Iterator<Item = &DebugLineInfo> {
+        self.modules.iter().flat_map(|module| module.rows_in(..))
     }
     /// The function gets the source triplet (file, line, column) of an instruction in code, given
     /// The inline module idx is how many inline modules have been seen before this, and the binary offset is grabbed from the comments at the start of each line
@@ -92,49 +277,75 @@ impl WatLineMapper {
         inline_module_idx: usize,
         binary_offset: u64,
     ) -> Option<&DebugLineInfo> {
-        let pc_offset = binary_offset - self.code_offsets[inline_module_idx] as u64;
-        self.lines
-            .iter()
-            .filter(|info| info.code_module_idx == inline_module_idx && info.address <= pc_offset)
-            .max_by(|i1, i2| i1.address.cmp(&i2.address))
+        let code_module_idx = self.code_module_idx(inline_module_idx)?;
+        let pc_offset = binary_offset.checked_sub(self.get_code_addr(code_module_idx)? as u64)?;
+        self.module(code_module_idx)?.row_at(pc_offset)
     }
     /// Consumes this struct and returns a `DebugData` struct representing information that should be passed to other programs
     pub fn into_debug_data(self) -> DebugDataOwned {
         assert_eq!(self.file_map.len(), self.file_map.iter().unique().count());
 
-        let mut blocks_per_line: HashMap<usize, Vec<_>> = HashMap::new();
-        self.lines
+        // A block on a line is a column and discriminator that some counter covers
+        let mut blocks: BTreeMap<usize, BTreeMap<u64, BTreeSet<(u64, u64)>>> = BTreeMap::new();
+        for (path_idx, line, column, discriminator) in self.counters.iter().flatten() {
+            blocks
+                .entry(*path_idx)
+                .or_default()
+                .entry(*line)
+                .or_default()
+                .insert((*column, *discriminator));
+        }
+        let blocks_per_line = blocks
             .into_iter()
-            .map(|dli| (dli.path_idx, dli.line))
-            .for_each(|(path_idx, this_line)| {
-                if let Some(Some(pos)) = blocks_per_line.get_mut(&path_idx).map(|v| {
-                    v.iter()
-                        .map(|(line, _)| line)
-                        .position(|line| *line == this_line)
-                }) {
-                    blocks_per_line.get_mut(&path_idx).unwrap()[pos] = (
-                        this_line,
-                        blocks_per_line.get(&path_idx).unwrap()[pos].1 + 1,
-                    );
-                } else if let Some(v) = blocks_per_line.get_mut(&path_idx) {
-                    v.push((this_line, 1));
-                } else {
-                    blocks_per_line.insert(path_idx, vec![(this_line, 1)]);
-                }
-            });
+            .map(|(path_idx, lines)| {
+                let lines = lines
+                    .into_iter()
+                    .map(|(line, blocks)| (line, blocks.len() as u64))
+                    .collect();
+                (path_idx, lines)
+            })
+            .collect();
 
         let sdi_vec = self.sdi_vec; //.into_iter().map(|(start, end, str, _addr)| (start, end, str)).collect::<Vec<_>>();
+                                    // Both the modules and the offsets are indexed by code module
+        let mut modules = self.modules.into_iter();
+        let line_tables = self
+            .code_offsets
+            .iter()
+            .map(|_| {
+                let module = modules.next().unwrap_or_default();
+                let rows = module
+                    .rows
+                    .into_iter()
+                    .filter_map(|(address, rows)| {
+                        let dli = rows.last()?;
+                        Some((
+                            address,
+                            (dli.path_idx, dli.line, dli.column, dli.discriminator),
+                        ))
+                    })
+                    .collect();
+                CodeLineTable {
+                    rows,
+                    functions: module.functions,
+                    ..Default::default()
+                }
+            })
+            .collect();
         DebugDataOwned {
             file_map: self.file_map,
+            file_checksums: self.file_checksums,
             blocks_per_line,
+            counters: self.counters,
             sdi_vec,
+            line_tables,
         }
     }
 
-    /// Get the code section offset for a particular module
+    /// Get the code section offset for a particular code module
     /// If the module index is out of bounds, `None` is returned
-    pub fn get_code_addr(&self, mod_idx: usize) -> Option<usize> {
-        self.code_offsets.get(mod_idx).copied()
+    pub fn get_code_addr(&self, code_module_idx: usize) -> Option<usize> {
+        self.code_offsets.get(code_module_idx).copied()
     }
 }
 
@@ -157,287 +368,327 @@ pub fn read_dbg_info(
     map: &mut WatLineMapper,
     noise_level: NoiseLevel,
 ) -> parser::Result<()> {
-    let mut code_module_idx = 0;
-    // todo: refactor!
-    // This implementation uses *a lot* of cloning, so it's very inefficient
-    let mut file_entry_map: HashMap<_, usize> = HashMap::new();
-    for field in get_fields(wat).ok_or(Error::new(
-        wat.span(),
-        "Input WAT file could not be parsed (may be binary or module)".to_string(),
-    ))? {
-        if let ComponentField::CoreModule(m) = field {
-            if let CoreModuleKind::Inline { fields } = &m.kind {
-                let mut section_map = HashMap::new();
-                for field in fields {
-                    if let ModuleField::Custom(Custom::Raw(c)) = field {
-                        let flattened_slice: Vec<u8> =
-                            c.data.iter().flat_map(|a| Vec::from(*a)).collect(); // is there a way to do this without allocating?
-                        section_map.insert(c.name, flattened_slice);
-                    }
-                }
-                let dwarf_sections = gimli::DwarfSections::load(|sec| {
-                    Ok(section_map
-                        .get(sec.name())
-                        .map(|v| v.as_slice())
-                        .unwrap_or(Default::default()))
-                })?;
-                let dwarf = dwarf_sections
-                    .borrow(|section| gimli::EndianSlice::new(section, gimli::LittleEndian));
-                let mut iter = dwarf.units();
-                while let Some(header) = iter.next().unwrap() {
+    for (inline_module_idx, (module_span, fields)) in get_inline_modules(wat)
+        .ok_or(Error::new(
+            wat.span(),
+            "Input WAT file could not be parsed (may be binary or module)".to_string(),
+        ))?
+        .into_iter()
+        .enumerate()
+    {
+        // Modules without code have no lines to map
+        let Some(code_module_idx) = map.code_module_idx(inline_module_idx) else {
+            continue;
+        };
+        let mut section_map = HashMap::new();
+        for field in fields {
+            if let ModuleField::Custom(Custom::Raw(c)) = field {
+                section_map.insert(c.name, c.data.concat());
+            }
+        }
+        let dwarf_sections = gimli::DwarfSections::load(|sec| {
+            Ok(section_map
+                .get(sec.name())
+                .map(|v| v.as_slice())
+                .unwrap_or(Default::default()))
+        })?;
+        let dwarf =
+            dwarf_sections.borrow(|section| gimli::EndianSlice::new(section, gimli::LittleEndian));
+        let dwarf_error = |e: gimli::Error| {
+            Error::new(
+                module_span,
+                format!(
+                    "Couldn't read the DWARF of core module {}: {}",
+                    inline_module_idx, e
+                ),
+            )
+        };
+        let mut iter = dwarf.units();
+        while let Some(header) = iter.next().map_err(dwarf_error)? {
+            if noise_level.debug() {
+                println_annotate_dbg(format!(
+                    "Unit at <.debug_info+0x{:x}>",
+                    header.offset().as_debug_info_offset().unwrap().0
+                ));
+            }
+            let unit = dwarf.unit(header).map_err(dwarf_error)?;
+            let unit = unit.unit_ref(&dwarf);
+
+            let mut entries = unit.entries();
+            let mut funcs = Vec::new();
+            while let Some((_, entry)) = entries.next_dfs().map_err(dwarf_error)? {
+                if entry.tag() == gimli::DW_TAG_subprogram {
                     if noise_level.debug() {
+                        println_annotate_dbg(format!("Found a function: {:?}", entry));
+                    }
+                    // DWARF 5 can give addresses and names as indices into other sections, which the unit resolves
+                    let low_pc = entry
+                        .attr_value(gimli::DW_AT_low_pc)
+                        .ok()
+                        .flatten()
+                        .and_then(|pc| unit.attr_address(pc).ok().flatten());
+                    // The high pc is either an address, or an offset from the low pc
+                    // The DWARF offset seems to include the 2-byte `Return` instruction
+                    // In order to get the end to point to the end of the function, we subtract 2 bytes
+                    let high_pc = entry
+                        .attr_value(gimli::DW_AT_high_pc)
+                        .ok()
+                        .flatten()
+                        .and_then(|pc| match pc.udata_value() {
+                            Some(offset) => low_pc.map(|low_pc| low_pc + offset),
+                            None => unit.attr_address(pc).ok().flatten(),
+                        })
+                        .map(|pc| pc.saturating_sub(2));
+                    let file = entry
+                        .attr_value(gimli::DW_AT_decl_file)
+                        .ok()
+                        .flatten()
+                        .and_then(|f| match f {
+                            gimli::AttributeValue::FileIndex(idx) => Some(idx),
+                            f => f.udata_value(),
+                        });
+                    let name = entry
+                        .attr_value(gimli::DW_AT_name)
+                        .ok()
+                        .flatten()
+                        .and_then(|name| unit.attr_string(name).ok())
+                        .map(|name| name.to_string_lossy().into_owned());
+                    if let Some(low_pc) = low_pc.filter(|_| noise_level.debug()) {
                         println_annotate_dbg(format!(
-                            "Unit at <.debug_info+0x{:x}>",
-                            header.offset().as_debug_info_offset().unwrap().0
+                            "low pc: {:x}, high pc: {:x?}, name: {:?}, file: {:?}",
+                            low_pc, high_pc, name, file
                         ));
                     }
-                    let unit = dwarf.unit(header).unwrap();
-                    let unit = unit.unit_ref(&dwarf);
-
-                    let mut entries = unit.entries();
-                    let mut funcs = Vec::new();
-                    while let Some((_, entry)) = entries.next_dfs().unwrap() {
-                        if entry.tag() == gimli::DW_TAG_subprogram {
-                            if noise_level.debug() {
-                                println_annotate_dbg(format!("Found a function: {:?}", entry));
-                            }
-                            let low_pc =
-                                entry.attr(gimli::DW_AT_low_pc).unwrap().map(|pc| {
-                                    match pc.value() {
-                                        gimli::AttributeValue::Addr(addr) => addr,
-                                        _ => panic!(),
-                                    }
-                                });
-                            // The DWARF offset seems to include the 2-byte `Return` instruction
-                            // In order to get the end to point to the end of the function, we subtract 2 bytes
-                            let offset = entry
-                                .attr(gimli::DW_AT_high_pc)
-                                .unwrap()
-                                .map(|pc| pc.value().udata_value().unwrap() - 2);
-                            let file = entry
-                                .attr(gimli::DW_AT_decl_file)
-                                .unwrap()
-                                .map(|f| f.udata_value());
-                            let name = entry.attr(gimli::DW_AT_name).unwrap().map(|name| {
-                                dwarf
-                                    .debug_str
-                                    .get_str(match name.value() {
-                                        gimli::AttributeValue::DebugStrRef(offset) => offset,
-                                        _ => panic!(),
-                                    })
-                                    .map(|s| str::from_utf8(s.slice()).unwrap())
-                            });
-                            if low_pc.is_some() && noise_level.debug() {
-                                println_annotate_dbg(format!(
-                                    "low pc: {:x}, high pc: {:x}, name: {:?}, file: {:?}",
-                                    low_pc.unwrap(),
-                                    low_pc.unwrap() + offset.unwrap(),
-                                    name,
-                                    file
-                                ));
-                            }
-                            // we can maybe just say file is the current vec len? othrwise map the map a hash
-                            if low_pc.is_some()
-                                && name.is_some_and(|n| n.is_ok())
-                                && file.is_some_and(|f| f.is_some())
-                            {
-                                let func_pair = (
-                                    file.unwrap().unwrap(),
-                                    (
-                                        low_pc.unwrap(),
-                                        offset.map(|off| low_pc.unwrap() + off),
-                                        name.unwrap().unwrap().to_string(),
-                                    ),
+                    // we can maybe just say file is the current vec len? othrwise map the map a hash
+                    if let (Some(low_pc), Some(file), Some(name)) = (low_pc, file, name) {
+                        funcs.push((file, (low_pc, high_pc, name)));
+                    }
+                    if noise_level.debug() {
+                        println_annotate_dbg(format!("SDI DWARF IDX: {:?}", file));
+                    }
+                }
+            }
+
+            if let Some(program) = unit.line_program.clone() {
+                let comp_dir = if let Some(ref dir) = unit.comp_dir {
+                    path::PathBuf::from(dir.to_string_lossy().into_owned())
+                } else {
+                    path::PathBuf::new()
+                };
+                // Maps indices in this unit's file table to indices in the file map
+                let mut file_paths: HashMap<u64, Option<usize>> = HashMap::new();
+
+                // Iterate over the line program rows.
+                let mut rows = program.rows();
+
+                while let Some((header, row)) = rows.next_row().map_err(dwarf_error)? {
+                    if row.end_sequence() {
+                        // End of sequence indicates a possible gap in addresses.
+                        if noise_level.debug() {
+                            println_annotate_dbg(format!("{:x} end-sequence", row.address()));
+                        }
+                    } else {
+                        let path_idx = *file_paths.entry(row.file_index()).or_insert_with(|| {
+                            add_unit_file(unit, header, row.file_index(), &comp_dir, map)
+                        });
+                        let Some(path_idx) = path_idx else {
+                            if noise_level.err() {
+                                println_annotate_error(
+                                    "Error: Unable to resolved source file path",
                                 );
-                                funcs.push(func_pair);
-                            }
-                            if noise_level.debug() {
-                                println_annotate_dbg(format!("SDI DWARF IDX: {:?}", file));
                             }
-                        }
-                    }
 
-                    if let Some(program) = unit.line_program.clone() {
-                        let comp_dir = if let Some(ref dir) = unit.comp_dir {
-                            path::PathBuf::from(dir.to_string_lossy().into_owned())
-                        } else {
-                            path::PathBuf::new()
+                            continue;
                         };
 
-                        // Iterate over the line program rows.
-                        let mut rows = program.clone().rows();
-
-                        while let Some((header, row)) = rows.next_row().unwrap() {
-                            if row.end_sequence() {
-                                // End of sequence indicates a possible gap in addresses.
-                                if noise_level.debug() {
-                                    println_annotate_dbg(format!(
-                                        "{:x} end-sequence",
-                                        row.address()
-                                    ));
-                                }
-                            } else {
-                                // Determine the path. Real applications should cache this for performance.
-                                let mut path_idx = None;
-                                if let Some(file) = row.file(header) {
-                                    let file_name = unit
-                                        .attr_string(file.path_name())
-                                        .unwrap()
-                                        .to_string_lossy();
-
-                                    if let Some(map_path_idx) = file_entry_map
-                                        .get(&(file.directory_index(), file_name.to_string()))
-                                    {
-                                        path_idx = Some(*map_path_idx);
-                                    } else {
-                                        let mut path = path::PathBuf::new();
-                                        path.clone_from(&comp_dir);
-
-                                        // The directory index 0 is defined to correspond to the compilation unit directory.
-                                        if file.directory_index() != 0 {
-                                            if let Some(dir) = file.directory(header) {
-                                                path.push(
-                                                    unit.attr_string(dir)
-                                                        .unwrap()
-                                                        .to_string_lossy()
-                                                        .as_ref(),
-                                                );
-                                            }
-                                        }
-
-                                        path.push(file_name.as_ref());
-
-                                        path_idx = Some(map.add_file(path));
-
-                                        file_entry_map.insert(
-                                            (file.directory_index(), file_name.into_owned()),
-                                            path_idx.unwrap(),
-                                        );
-                                    }
-                                }
-                                if path_idx.is_none() {
-                                    if noise_level.err() {
-                                        println_annotate_error(
-                                            "Error: Unable to resolved source file path",
-                                        );
-                                    }
-
-                                    continue;
-                                }
-                                let path_idx = path_idx.unwrap();
-
-                                // Determine line/column. DWARF line/column is never 0, so we use that
-                                // but other applications may want to display this differently.
-                                let line = match row.line() {
-                                    Some(line) => line.get(),
-                                    None => 0,
-                                };
-                                let column = match row.column() {
-                                    gimli::ColumnType::LeftEdge => 0,
-                                    gimli::ColumnType::Column(column) => column.get(),
-                                };
-
-                                if noise_level.debug() {
-                                    println_annotate_dbg(format!(
-                                        "{:x} {}:{}:{}",
-                                        row.address(),
-                                        map.file_map[path_idx].display(),
-                                        line,
-                                        column
-                                    ));
-                                }
-
-                                let info = DebugLineInfo {
-                                    address: row.address(),
-                                    path_idx,
-                                    line,
-                                    column,
-                                    code_module_idx,
-                                };
-                                map.add_line(info);
-                            }
+                        // Determine line/column. DWARF line/column is never 0, so we use that
+                        // but other applications may want to display this differently.
+                        let line = match row.line() {
+                            Some(line) => line.get(),
+                            None => 0,
+                        };
+                        let column = match row.column() {
+                            gimli::ColumnType::LeftEdge => 0,
+                            gimli::ColumnType::Column(column) => column.get(),
+                        };
+
+                        if noise_level.debug() {
+                            println_annotate_dbg(format!(
+                                "{:x} {}:{}:{}, discriminator: {}, is_stmt: {}, prologue_end: {}, epilogue_begin: {}",
+                                row.address(),
+                                map.file_map[path_idx].display(),
+                                line,
+                                column,
+                                row.discriminator(),
+                                row.is_stmt(),
+                                row.prologue_end(),
+                                row.epilogue_begin(),
+                            ));
                         }
 
-                        // Add func refs to sdi
-                        'func: for func in funcs {
-                            let (dwarf_file, func) = func;
-                            // map func addrs to actual lines
-                            // maybe we should make the functions before this processing a different struct?
-
-                            // map dwarf file index
-                            let file = program.header().file(dwarf_file).unwrap();
-                            let file_name = unit
-                                .attr_string(file.path_name())
-                                .unwrap()
-                                .to_string_lossy()
-                                .into_owned();
-
-                            if let Some(path_idx) =
-                                file_entry_map.get(&(file.directory_index(), file_name))
-                            {
-                                let dlis_in_mod = map.lines.iter().filter(|dli| {
-                                    dli.code_module_idx == code_module_idx
-                                        && dli.path_idx == *path_idx
-                                });
-
-                                let start_line = dlis_in_mod
-                                    .clone()
-                                    .filter(|dli| dli.address >= func.0)
-                                    .min_by(|dli1, dli2| dli1.address.cmp(&dli2.address));
-
-                                if start_line.is_none() {
-                                    if noise_level.err() {
-                                        println_annotate_error(
-                                            "Error: no valid dli found for function definition",
-                                        );
-                                    }
-
-                                    continue 'func;
-                                }
-
-                                let start_line = start_line.unwrap();
-                                let end_line = func.1.map(|addr| {
-                                    dlis_in_mod
-                                        .filter(|dli| dli.address <= addr)
-                                        .max_by(|dli1, dli2| dli1.line.cmp(&dli2.line)) // figure out why this gives errors
-                                        .unwrap()
-                                        .line
-                                });
-                                if noise_level.debug() {
-                                    println_annotate_dbg(format!(
-                                        "Mapped to {}, {:?}",
-                                        start_line.line, end_line
-                                    ));
-                                }
-                                let func = (start_line.line, end_line, func.2, start_line.address);
-
-                                // search the SDIs
-                                for sdi in &mut map.sdi_vec {
-                                    if sdi.path_idx == *path_idx {
-                                        sdi.functions.push(func);
-                                        continue 'func;
-                                    }
-                                }
-                                // if we're here, we need to make a new sdi
-                                let sdi = SourceDebugInfo {
-                                    path_idx: *path_idx,
-                                    functions: vec![func],
-                                    branches: Vec::new(),
-                                };
-                                map.sdi_vec.push(sdi);
-                            } else if noise_level.err() {
-                                println_annotate_error("Error: SDI file had no entry in file map")
-                            }
+                        let info = DebugLineInfo {
+                            address: row.address(),
+                            path_idx,
+                            line,
+                            column,
+                            discriminator: row.discriminator(),
+                            is_stmt: row.is_stmt(),
+                            prologue_end: row.prologue_end(),
+                            epilogue_begin: row.epilogue_begin(),
+                            code_module_idx,
+                        };
+                        if !map.add_line(info) && noise_level.debug() {
+                            println_annotate_dbg(format!(
+                                "Ignoring duplicate line row at {:x}",
+                                row.address()
+                            ));
                         }
                     }
                 }
-                code_module_idx += 1;
+
+                // Add funcs to the module's function index
+                for (dwarf_file, (low_pc, high_pc, name)) in funcs {
+                    // Declaration files use the same indices as the line program's file table
+                    let path_idx = *file_paths.entry(dwarf_file).or_insert_with(|| {
+                        add_unit_file(unit, rows.header(), dwarf_file, &comp_dir, map)
+                    });
+                    if let Some(path_idx) = path_idx {
+                        let func = DebugFuncInfo {
+                            low_pc,
+                            high_pc,
+                            path_idx,
+                            name,
+                        };
+                        map.add_function(code_module_idx, func);
+                    } else if noise_level.err() {
+                        println_annotate_error("Error: SDI file had no entry in file map")
+                    }
+                }
             }
         }
+        add_module_functions(map, code_module_idx, noise_level);
     }
     Ok(())
 }
 
+/// Add a file from a unit's line program to the file map, and return its index in the map
+/// Before DWARF 5, files are numbered from 1 and directory 0 means the compilation directory, which `gimli` takes care of when looking them up
+/// From DWARF 5 on, directory 0 is an entry of its own holding the compilation directory, so it's used like any other directory
+fn add_unit_file<'a>(
+    unit: gimli::UnitRef<DwarfSlice<'a>>,
+    header: &gimli::LineProgramHeader<DwarfSlice<'a>>,
+    file_idx: u64,
+    comp_dir: &path::Path,
+    map: &mut WatLineMapper,
+) -> Option<usize> {
+    let file = header.file(file_idx)?;
+    let mut path = comp_dir.to_path_buf();
+    if header.version() >= 5 || file.directory_index() != 0 {
+        if let Some(dir) = file.directory(header) {
+            push_path(&mut path, &unit.attr_string(dir).ok()?.to_string_lossy());
+        }
+    }
+    push_path(
+        &mut path,
+        &unit.attr_string(file.path_name()).ok()?.to_string_lossy(),
+    );
+    let path_idx = map.add_file(path);
+    if header.file_has_md5() {
+        map.file_checksums.entry(path_idx).or_insert_with(|| {
+            file.md5()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        });
+    }
+    Some(path_idx)
+}
+
+/// Add a directory or file name from a line program to a path
+/// Names with a URL scheme, like the `wasisdk://` prefix wasi-sdk remaps its sources to, are absolute just like names starting with `/`
+fn push_path(path: &mut PathBuf, name: &str) {
+    if has_url_scheme(name) {
+        *path = PathBuf::from(name);
+    } else {
+        path.push(name);
+    }
+}
+
+/// Check whether a path starts with a URL scheme, like `wasisdk://` or `file://`
+fn has_url_scheme(name: &str) -> bool {
+    name.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+/// Add the functions of a code module to the SDIs of their source files, once all of its line rows have been read
+/// A function starts at the line of its first statement row in the file it's declared in, and ends at the last line of those rows
+pub fn add_module_functions(
+    map: &mut WatLineMapper,
+    code_module_idx: usize,
+    noise_level: NoiseLevel,
+) {
+    let Some(module) = map.modules.get(code_module_idx) else {
+        return;
+    };
+    'func: for func in &module.functions {
+        // Functions the filters leave out don't get probes, so they aren't reported either
+        if !map.function_filters.instruments(Some(func), &map.file_map) {
+            if noise_level.debug() {
+                println_annotate_dbg(format!(
+                    "Leaving {} out of the report, since the function filters skip it",
+                    func.name
+                ));
+            }
+            continue;
+        }
+        let range = func.low_pc..=func.high_pc.unwrap_or(u64::MAX);
+        let mut rows = module
+            .rows_in(range)
+            .filter(|dli| dli.is_stmt && dli.path_idx == func.path_idx);
+        let Some(start_line) = rows.next() else {
+            if noise_level.err() {
+                println_annotate_error("Error: no valid dli found for function definition");
+            }
+            continue;
+        };
+        let end_line = func.high_pc.map(|_| {
+            rows.map(|dli| dli.line)
+                .max()
+                .map_or(start_line.line, |line| line.max(start_line.line))
+        });
+        if noise_level.debug() {
+            println_annotate_dbg(format!(
+                "Mapped {} to {}, {:?}",
+                func.name, start_line.line, end_line
+            ));
+        }
+        let func_def = (
+            start_line.line,
+            end_line,
+            func.name.clone(),
+            start_line.address,
+        );
+
+        // search the SDIs
+        for sdi in &mut map.sdi_vec {
+            if sdi.path_idx == func.path_idx {
+                sdi.functions.push(func_def);
+                continue 'func;
+            }
+        }
+        // if we're here, we need to make a new sdi
+        map.sdi_vec.push(SourceDebugInfo {
+            path_idx: func.path_idx,
+            functions: vec![func_def],
+            branches: Vec::new(),
+        });
+    }
+}
+
 /// Find the code section offsets in a binary Wasm file
 pub fn find_code_offsets(input: &[u8]) -> Result<Vec<usize>, BinaryReaderError> {
     let mut code_offsets = Vec::new();
@@ -452,5 +703,137 @@ pub fn find_code_offsets(input: &[u8]) -> Result<Vec<usize>, BinaryReaderError>
     Ok(code_offsets)
 }
 
+/// Find the code module index of every core module in a binary Wasm file, in the order they're defined
+/// Modules without a `code` section, like ones that only define a memory or forward imports, have `None`
+pub fn find_module_code_sections(input: &[u8]) -> Result<Vec<Option<usize>>, BinaryReaderError> {
+    let mut modules = Vec::new();
+    let mut code_sections = 0;
+    for payload in Parser::new(0).parse_all(input) {
+        match payload? {
+            Version {
+                encoding: wasmparser::Encoding::Module,
+                ..
+            } => modules.push(None),
+            // Core modules can't be nested, so a code section belongs to the last module that started
+            CodeSectionStart { .. } => {
+                if let Some(module) = modules.last_mut() {
+                    *module = Some(code_sections);
+                }
+                code_sections += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(modules)
+}
+
+/// A function body in a code module
+pub struct CodeFunc {
+    /// The function's index in the module's function index space, which counts imported functions first
+    pub func_idx: u32,
+    /// Where the body is, relative to the start of the `code` section
+    pub range: Range<u64>,
+    /// The function's name from the module's `name` section, if it has one
+    pub name: Option<String>,
+    /// Whether the body has no instructions besides its final `end`, which leaves nowhere to put a counter
+    pub is_empty: bool,
+}
+
+/// A core module with a `code` section, found in a binary Wasm file
+pub struct CodeModule {
+    /// The offset in the binary that the module starts at
+    pub module_offset: usize,
+    /// The offset in the binary that the module's `code` section starts at
+    pub code_offset: usize,
+    /// The module's function bodies
+    pub funcs: Vec<CodeFunc>,
+}
+
+/// Find every core module with a `code` section in a binary Wasm file, in the same order as [`find_code_offsets`]
+pub fn find_code_modules(input: &[u8]) -> Result<Vec<CodeModule>, BinaryReaderError> {
+    let mut code_modules = Vec::new();
+    // The start of each module being parsed, the number of functions it imports, and its index in `code_modules` once its code section is found
+    let mut stack: Vec<Option<(usize, u32, Option<usize>)>> = Vec::new();
+    for payload in Parser::new(0).parse_all(input) {
+        match payload? {
+            Version {
+                encoding, range, ..
+            } => stack.push((encoding == wasmparser::Encoding::Module).then_some((
+                range.start,
+                0,
+                None,
+            ))),
+            ImportSection(imports) => {
+                if let Some(Some((_, imported_funcs, _))) = stack.last_mut() {
+                    for import in imports {
+                        if matches!(import?.ty, wasmparser::TypeRef::Func(_)) {
+                            *imported_funcs += 1;
+                        }
+                    }
+                }
+            }
+            CodeSectionStart { range, .. } => {
+                if let Some(Some((module_offset, _, idx))) = stack.last_mut() {
+                    *idx = Some(code_modules.len());
+                    code_modules.push(CodeModule {
+                        module_offset: *module_offset,
+                        code_offset: range.start,
+                        funcs: Vec::new(),
+                    });
+                }
+            }
+            CodeSectionEntry(body) => {
+                if let Some(Some((_, imported_funcs, Some(idx)))) = stack.last() {
+                    let module = &mut code_modules[*idx];
+                    let start = (body.range().start - module.code_offset) as u64;
+                    let end = (body.range().end - module.code_offset) as u64;
+                    let mut operators = body.get_operators_reader()?;
+                    let is_empty = matches!(operators.read()?, wasmparser::Operator::End);
+                    module.funcs.push(CodeFunc {
+                        func_idx: imported_funcs + module.funcs.len() as u32,
+                        range: start..end,
+                        name: None,
+                        is_empty,
+                    });
+                }
+            }
+            CustomSection(section) => {
+                let (
+                    Some(Some((_, imported_funcs, Some(idx)))),
+                    wasmparser::KnownCustom::Name(names),
+                ) = (stack.last(), section.as_known())
+                else {
+                    continue;
+                };
+                let funcs = &mut code_modules[*idx].funcs;
+                for subsection in names {
+                    let wasmparser::Name::Function(func_names) = subsection? else {
+                        continue;
+                    };
+                    for naming in func_names {
+                        let naming = naming?;
+                        // Bodies are in the same order as the functions they define
+                        let func = naming
+                            .index
+                            .checked_sub(*imported_funcs)
+                            .and_then(|idx| funcs.get_mut(idx as usize));
+                        if let Some(func) = func {
+                            func.name = Some(naming.name.to_string());
+                        }
+                    }
+                }
+            }
+            End(_) => {
+                stack.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(code_modules)
+}
+
+/// A location in a source file, as the index of the file in the file map, the line, the column, and the discriminator of the block
+pub type SourceLocation = (usize, u64, u64, u64);
+type DwarfSlice<'a> = gimli::EndianSlice<'a, gimli::LittleEndian>;
 type FuncDef = (u64, Option<u64>, String, u64); // line num of func start, func end, and name, (and address for other uses)
 type BranchDef = (u64, bool, u64, u64); // line num, is exception, block idx, branch idx,
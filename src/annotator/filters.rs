@@ -0,0 +1,192 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::annotator::debug::DebugFuncInfo;
+
+/// The prefix of a filter that matches the source file a function is declared in
+const PATH_PREFIX: &str = "file:";
+/// The prefix of a filter that matches a function's name
+const NAME_PREFIX: &str = "fn:";
+
+/// A filter that picks out functions by the DWARF describing them
+/// Filters are written as `file:<GLOB>`, which matches the path of the file a function is declared in, or `fn:<REGEX>`, which matches its name
+#[derive(Clone, Debug)]
+pub enum FunctionFilter {
+    /// A glob over the path of the file a function is declared in
+    Path(PathGlob),
+    /// A regex over function names, which is searched for anywhere in the name
+    Name(Regex),
+}
+
+impl FunctionFilter {
+    /// Check whether a function, declared in the file at `path`, matches this filter
+    pub fn matches(&self, func: &DebugFuncInfo, path: Option<&Path>) -> bool {
+        match self {
+            FunctionFilter::Path(glob) => path.is_some_and(|path| glob.matches(path)),
+            FunctionFilter::Name(re) => re.is_match(&func.name),
+        }
+    }
+}
+
+impl Display for FunctionFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FunctionFilter::Path(glob) => write!(f, "{}{}", PATH_PREFIX, glob),
+            FunctionFilter::Name(re) => write!(f, "{}{}", NAME_PREFIX, re),
+        }
+    }
+}
+
+impl FromStr for FunctionFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(glob) = s.strip_prefix(PATH_PREFIX) {
+            glob.parse().map(FunctionFilter::Path)
+        } else if let Some(re) = s.strip_prefix(NAME_PREFIX) {
+            Regex::new(re)
+                .map(FunctionFilter::Name)
+                .map_err(|e| format!("invalid regex `{}`: {}", re, e))
+        } else {
+            Err(format!(
+                "filters start with `{}` for source path globs or `{}` for function name regexes",
+                PATH_PREFIX, NAME_PREFIX
+            ))
+        }
+    }
+}
+
+/// A glob over source paths, along with the regex it was turned into
+/// A relative glob matches the end of a path, so `src/**` matches every file in any `src` directory
+#[derive(Clone, Debug)]
+pub struct PathGlob {
+    glob: String,
+    re: Regex,
+}
+
+impl PathGlob {
+    /// Check whether a source path matches the glob
+    pub fn matches(&self, path: &Path) -> bool {
+        self.re.is_match(&path.to_string_lossy())
+    }
+}
+
+impl Display for PathGlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.glob)
+    }
+}
+
+impl FromStr for PathGlob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(&glob_to_regex(s))
+            .map(|re| PathGlob {
+                glob: s.to_string(),
+                re,
+            })
+            .map_err(|e| format!("invalid glob `{}`: {}", s, e))
+    }
+}
+
+/// Turn a glob into a regex that matches whole paths
+/// `**` matches across directories, `*` and `?` match within one, and a glob that isn't absolute can start at any directory
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from(if glob.starts_with('/') { "^" } else { "(^|/)" });
+    let mut rest = glob;
+    while let Some(c) = rest.chars().next() {
+        let (pattern, len) = if rest.starts_with("**/") {
+            ("(.*/)?".to_string(), 3)
+        } else if rest.starts_with("**") {
+            (".*".to_string(), 2)
+        } else if c == '*' {
+            ("[^/]*".to_string(), 1)
+        } else if c == '?' {
+            ("[^/]".to_string(), 1)
+        } else {
+            (regex::escape(&c.to_string()), c.len_utf8())
+        };
+        re.push_str(&pattern);
+        rest = &rest[len..];
+    }
+    re.push('$');
+    re
+}
+
+/// The `--instrument-only` and `--skip` filters, which decide which functions get probes
+#[derive(Clone, Debug, Default)]
+pub struct FunctionFilters {
+    /// If any are given, only functions matching one of these are instrumented
+    pub instrument_only: Vec<FunctionFilter>,
+    /// Functions matching any of these aren't instrumented, even if they match an `instrument_only` filter
+    pub skip: Vec<FunctionFilter>,
+}
+
+impl FunctionFilters {
+    /// Check whether the function described by `func` should be instrumented
+    /// Functions without DWARF can't match a filter, so they're only instrumented if there are no `instrument_only` filters
+    pub fn instruments(&self, func: Option<&DebugFuncInfo>, files: &[PathBuf]) -> bool {
+        let matches = |filter: &FunctionFilter| {
+            func.is_some_and(|func| {
+                filter.matches(func, files.get(func.path_idx).map(PathBuf::as_path))
+            })
+        };
+        (self.instrument_only.is_empty() || self.instrument_only.iter().any(matches))
+            && !self.skip.iter().any(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_matches(glob: &str, path: &str) -> bool {
+        glob.parse::<PathGlob>().unwrap().matches(Path::new(path))
+    }
+
+    #[test]
+    fn turns_globs_into_regexes() {
+        assert_eq!(glob_to_regex("/src/*.rs"), r"^/src/[^/]*\.rs$");
+        assert_eq!(glob_to_regex("a?/**/b"), r"(^|/)a[^/]/(.*/)?b$");
+        assert_eq!(glob_to_regex("gen/**"), r"(^|/)gen/.*$");
+    }
+
+    #[test]
+    fn matches_paths() {
+        assert!(glob_matches("/src/*.rs", "/src/main.rs"));
+        assert!(!glob_matches("/src/*.rs", "/src/bin/main.rs"));
+        assert!(!glob_matches("/src/*.rs", "/home/src/main.rs"));
+        // Relative globs start at any directory, but not in the middle of a name
+        assert!(glob_matches("src/*.rs", "/home/me/src/main.rs"));
+        assert!(!glob_matches("src/*.rs", "/home/me/mysrc/main.rs"));
+        assert!(glob_matches("src/**/*.rs", "/app/src/main.rs"));
+        assert!(glob_matches("src/**/*.rs", "/app/src/a/b/lib.rs"));
+        assert!(glob_matches("gen/**", "/app/gen/bindings.rs"));
+        assert!(glob_matches("lib?.c", "/c/lib1.c"));
+        assert!(!glob_matches("lib?.c", "/c/lib10.c"));
+        // Regex characters in globs are literal
+        assert!(!glob_matches("main.rs", "/src/main_rs"));
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert!(matches!(
+            "file:src/**".parse::<FunctionFilter>(),
+            Ok(FunctionFilter::Path(_))
+        ));
+        assert!(matches!(
+            "fn:^main$".parse::<FunctionFilter>(),
+            Ok(FunctionFilter::Name(_))
+        ));
+        assert!("fn:(".parse::<FunctionFilter>().is_err());
+        assert!("main".parse::<FunctionFilter>().is_err());
+        assert_eq!(
+            "file:src/**".parse::<FunctionFilter>().unwrap().to_string(),
+            "file:src/**"
+        );
+    }
+}
@@ -0,0 +1,804 @@
+use std::collections::HashMap;
+
+use wast::component::*;
+use wast::core::{self, ElemKind, ElemPayload, ExportKind, Expression, Instruction, ModuleField};
+use wast::token::{Id, Index};
+
+use crate::annotator::offset_tracker::OffsetTracker;
+
+/// The index spaces of a component
+/// Every item defined in a component (by definition, import, alias, or export) gets the next free index in one of these spaces
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum IndexSpace {
+    /// Core functions
+    CoreFunc,
+    /// Core tables
+    CoreTable,
+    /// Core memories
+    CoreMemory,
+    /// Core globals
+    CoreGlobal,
+    /// Core tags
+    CoreTag,
+    /// Core types
+    CoreType,
+    /// Core modules
+    CoreModule,
+    /// Core instances
+    CoreInstance,
+    /// Component functions
+    Func,
+    /// Component values
+    Value,
+    /// Component types
+    Type,
+    /// Nested components
+    Component,
+    /// Component instances
+    Instance,
+}
+
+impl IndexSpace {
+    fn from_core_export(kind: ExportKind) -> IndexSpace {
+        match kind {
+            ExportKind::Func => IndexSpace::CoreFunc,
+            ExportKind::Table => IndexSpace::CoreTable,
+            ExportKind::Memory => IndexSpace::CoreMemory,
+            ExportKind::Global => IndexSpace::CoreGlobal,
+            ExportKind::Tag => IndexSpace::CoreTag,
+        }
+    }
+
+    fn from_export_alias(kind: ComponentExportAliasKind) -> IndexSpace {
+        match kind {
+            ComponentExportAliasKind::CoreModule => IndexSpace::CoreModule,
+            ComponentExportAliasKind::Func => IndexSpace::Func,
+            ComponentExportAliasKind::Value => IndexSpace::Value,
+            ComponentExportAliasKind::Type => IndexSpace::Type,
+            ComponentExportAliasKind::Component => IndexSpace::Component,
+            ComponentExportAliasKind::Instance => IndexSpace::Instance,
+        }
+    }
+
+    fn from_outer_alias(kind: ComponentOuterAliasKind) -> IndexSpace {
+        match kind {
+            ComponentOuterAliasKind::CoreModule => IndexSpace::CoreModule,
+            ComponentOuterAliasKind::CoreType => IndexSpace::CoreType,
+            ComponentOuterAliasKind::Type => IndexSpace::Type,
+            ComponentOuterAliasKind::Component => IndexSpace::Component,
+        }
+    }
+
+    fn from_item_sig(kind: &ItemSigKind) -> IndexSpace {
+        match kind {
+            ItemSigKind::CoreModule(_) => IndexSpace::CoreModule,
+            ItemSigKind::Func(_) => IndexSpace::Func,
+            ItemSigKind::Component(_) => IndexSpace::Component,
+            ItemSigKind::Instance(_) => IndexSpace::Instance,
+            ItemSigKind::Value(_) => IndexSpace::Value,
+            ItemSigKind::Type(_) => IndexSpace::Type,
+        }
+    }
+}
+
+/// A reference to an item in one of the walked component's index spaces
+pub type IndexRef<'a> = (IndexSpace, Index<'a>);
+
+/// Walks component fields, recording every reference into the walked component's index spaces and counting the items each field defines
+/// Sugar that the text format expands into extra items (inline types, inline instantiation arguments, and inline export aliases) is counted as well,
+/// since those items are placed in front of the field that uses them
+#[derive(Default)]
+struct Walker<'a> {
+    refs: Vec<IndexRef<'a>>,
+    /// The field that defines each item, in index order
+    /// Items created by sugar inside another field have no defining field of their own
+    defs: HashMap<IndexSpace, Vec<Option<&'a ComponentField<'a>>>>,
+    /// The index of each named item
+    names: HashMap<(IndexSpace, &'a str), u32>,
+    /// The root field being walked
+    current: Option<&'a ComponentField<'a>>,
+    /// The id of the root component, if it has one
+    root_id: Option<Id<'a>>,
+    /// The ids of the nested scopes (components and component, instance, or module types) enclosing the one being walked
+    /// This is empty while walking the root component's own fields
+    scopes: Vec<Option<Id<'a>>>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(root_id: Option<Id<'a>>) -> Walker<'a> {
+        Walker {
+            root_id,
+            ..Default::default()
+        }
+    }
+
+    /// Define an item created by sugar inside the current field
+    fn define(&mut self, space: IndexSpace) {
+        if self.scopes.is_empty() {
+            self.defs.entry(space).or_default().push(None);
+        }
+    }
+
+    /// Define the item the current field itself creates
+    fn define_named(&mut self, space: IndexSpace, id: Option<Id<'a>>) {
+        if !self.scopes.is_empty() {
+            return;
+        }
+        let defs = self.defs.entry(space).or_default();
+        if let Some(id) = id {
+            self.names.insert((space, id.name()), defs.len() as u32);
+        }
+        defs.push(self.current);
+    }
+
+    fn reference(&mut self, space: IndexSpace, idx: Index<'a>) {
+        if self.scopes.is_empty() {
+            self.refs.push((space, idx));
+        }
+    }
+
+    fn nested<F: FnOnce(&mut Self)>(&mut self, id: Option<Id<'a>>, f: F) {
+        self.scopes.push(id);
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn item_ref<K>(&mut self, space: IndexSpace, item: &ItemRef<'a, K>) {
+        if item.export_names.is_empty() {
+            self.reference(space, item.idx);
+        } else {
+            // `(func $instance "a" "b")` is sugar for a chain of export aliases
+            self.reference(IndexSpace::Instance, item.idx);
+            for _ in 1..item.export_names.len() {
+                self.define(IndexSpace::Instance);
+            }
+            self.define(space);
+        }
+    }
+
+    fn core_item_ref<K>(&mut self, space: IndexSpace, item: &CoreItemRef<'a, K>) {
+        if item.export_name.is_none() {
+            self.reference(space, item.idx);
+        } else {
+            self.reference(IndexSpace::CoreInstance, item.idx);
+            self.define(space);
+        }
+    }
+
+    fn fields(&mut self, fields: &'a [ComponentField<'a>]) {
+        for field in fields {
+            self.field(field);
+        }
+    }
+
+    fn field(&mut self, field: &'a ComponentField<'a>) {
+        if self.scopes.is_empty() {
+            self.current = Some(field);
+        }
+        match field {
+            ComponentField::CoreModule(m) => {
+                match &m.kind {
+                    CoreModuleKind::Import { ty, .. } => self.core_type_use(ty, Self::module_type),
+                    // Inline modules have their own index spaces
+                    CoreModuleKind::Inline { .. } => {}
+                }
+                self.define_named(IndexSpace::CoreModule, m.id);
+            }
+            ComponentField::CoreInstance(i) => {
+                match &i.kind {
+                    CoreInstanceKind::Instantiate { module, args } => {
+                        self.item_ref(IndexSpace::CoreModule, module);
+                        for arg in args {
+                            match &arg.kind {
+                                CoreInstantiationArgKind::Instance(r) => {
+                                    self.core_item_ref(IndexSpace::CoreInstance, r)
+                                }
+                                CoreInstantiationArgKind::BundleOfExports(_, exports) => {
+                                    self.core_exports(exports);
+                                    self.define(IndexSpace::CoreInstance);
+                                }
+                            }
+                        }
+                    }
+                    CoreInstanceKind::BundleOfExports(exports) => self.core_exports(exports),
+                }
+                self.define_named(IndexSpace::CoreInstance, i.id);
+            }
+            ComponentField::CoreType(t) => {
+                self.core_type(t);
+                self.define_named(IndexSpace::CoreType, t.id);
+            }
+            ComponentField::Component(c) => {
+                match &c.kind {
+                    NestedComponentKind::Import { ty, .. } => {
+                        self.type_use(ty, |w, ct| w.component_type(ct))
+                    }
+                    NestedComponentKind::Inline(fields) => self.nested(c.id, |w| w.fields(fields)),
+                }
+                self.define_named(IndexSpace::Component, c.id);
+            }
+            ComponentField::Instance(i) => {
+                match &i.kind {
+                    InstanceKind::Import { ty, .. } => {
+                        self.type_use(ty, |w, it| w.instance_type(it))
+                    }
+                    InstanceKind::Instantiate { component, args } => {
+                        self.item_ref(IndexSpace::Component, component);
+                        for arg in args {
+                            match &arg.kind {
+                                InstantiationArgKind::Item(kind) => self.export_kind(kind),
+                                InstantiationArgKind::BundleOfExports(_, exports) => {
+                                    self.exports(exports);
+                                    self.define(IndexSpace::Instance);
+                                }
+                            }
+                        }
+                    }
+                    InstanceKind::BundleOfExports(exports) => self.exports(exports),
+                }
+                self.define_named(IndexSpace::Instance, i.id);
+            }
+            ComponentField::Alias(a) => self.alias(a),
+            ComponentField::Type(t) => {
+                self.ty(t);
+                self.define_named(IndexSpace::Type, t.id);
+            }
+            ComponentField::CanonicalFunc(cf) => match &cf.kind {
+                CanonicalFuncKind::Lift { ty, info } => {
+                    self.lift(ty, info);
+                    self.define_named(IndexSpace::Func, cf.id);
+                }
+                CanonicalFuncKind::Lower(cl) => {
+                    self.lower(cl);
+                    self.define_named(IndexSpace::CoreFunc, cf.id);
+                }
+                CanonicalFuncKind::ResourceNew(CanonResourceNew { ty })
+                | CanonicalFuncKind::ResourceDrop(CanonResourceDrop { ty })
+                | CanonicalFuncKind::ResourceRep(CanonResourceRep { ty }) => {
+                    self.reference(IndexSpace::Type, *ty);
+                    self.define_named(IndexSpace::CoreFunc, cf.id);
+                }
+            },
+            ComponentField::CoreFunc(cf) => {
+                match &cf.kind {
+                    CoreFuncKind::Lower(cl) => self.lower(cl),
+                    CoreFuncKind::Alias(a) => self.reference(IndexSpace::CoreInstance, a.instance),
+                    CoreFuncKind::ResourceNew(CanonResourceNew { ty })
+                    | CoreFuncKind::ResourceDrop(CanonResourceDrop { ty })
+                    | CoreFuncKind::ResourceRep(CanonResourceRep { ty }) => {
+                        self.reference(IndexSpace::Type, *ty)
+                    }
+                }
+                self.define_named(IndexSpace::CoreFunc, cf.id);
+            }
+            ComponentField::Func(f) => {
+                match &f.kind {
+                    FuncKind::Import { ty, .. } => self.type_use(ty, |w, ft| w.func_type(ft)),
+                    FuncKind::Lift { ty, info } => self.lift(ty, info),
+                    FuncKind::Alias(a) => self.reference(IndexSpace::Instance, a.instance),
+                }
+                self.define_named(IndexSpace::Func, f.id);
+            }
+            ComponentField::Start(s) => {
+                self.reference(IndexSpace::Func, s.func);
+                for arg in &s.args {
+                    self.item_ref(IndexSpace::Value, arg);
+                }
+                for _ in &s.results {
+                    self.define(IndexSpace::Value);
+                }
+            }
+            ComponentField::Import(i) => {
+                self.item_sig(&i.item);
+                self.define_named(IndexSpace::from_item_sig(&i.item.kind), i.item.id);
+            }
+            ComponentField::Export(e) => {
+                self.export_kind(&e.kind);
+                if let Some(sig) = &e.ty {
+                    self.item_sig(&sig.0);
+                }
+                self.define_named(export_space(&e.kind), e.id);
+            }
+            ComponentField::Custom(_) | ComponentField::Producers(_) => {}
+        }
+    }
+
+    fn alias(&mut self, alias: &Alias<'a>) {
+        match alias.target {
+            AliasTarget::Export { instance, kind, .. } => {
+                self.reference(IndexSpace::Instance, instance);
+                self.define_named(IndexSpace::from_export_alias(kind), alias.id);
+            }
+            AliasTarget::CoreExport { instance, kind, .. } => {
+                self.reference(IndexSpace::CoreInstance, instance);
+                self.define_named(IndexSpace::from_core_export(kind), alias.id);
+            }
+            AliasTarget::Outer { outer, index, kind } => {
+                let space = IndexSpace::from_outer_alias(kind);
+                // Outer aliases are how nested scopes refer to the root component's items
+                let target_is_root = match outer {
+                    Index::Num(count, _) => count as usize == self.scopes.len(),
+                    Index::Id(id) => !self.scopes.contains(&Some(id)) && self.root_id == Some(id),
+                };
+                if target_is_root {
+                    self.refs.push((space, index));
+                }
+                self.define_named(space, alias.id);
+            }
+        }
+    }
+
+    fn lift(
+        &mut self,
+        ty: &'a ComponentTypeUse<'a, ComponentFunctionType<'a>>,
+        info: &CanonLift<'a>,
+    ) {
+        self.type_use(ty, |w, ft| w.func_type(ft));
+        self.core_item_ref(IndexSpace::CoreFunc, &info.func);
+        self.canon_opts(&info.opts);
+    }
+
+    fn lower(&mut self, cl: &CanonLower<'a>) {
+        self.item_ref(IndexSpace::Func, &cl.func);
+        self.canon_opts(&cl.opts);
+    }
+
+    fn canon_opts(&mut self, opts: &[CanonOpt<'a>]) {
+        for opt in opts {
+            match opt {
+                CanonOpt::Memory(r) => self.core_item_ref(IndexSpace::CoreMemory, r),
+                CanonOpt::Realloc(r) | CanonOpt::PostReturn(r) => {
+                    self.core_item_ref(IndexSpace::CoreFunc, r)
+                }
+                CanonOpt::StringUtf8 | CanonOpt::StringUtf16 | CanonOpt::StringLatin1Utf16 => {}
+            }
+        }
+    }
+
+    fn core_exports(&mut self, exports: &[CoreInstanceExport<'a>]) {
+        for export in exports {
+            self.core_item_ref(IndexSpace::from_core_export(export.item.kind), &export.item);
+        }
+    }
+
+    fn exports(&mut self, exports: &'a [ComponentExport<'a>]) {
+        for export in exports {
+            self.export_kind(&export.kind);
+            if let Some(sig) = &export.ty {
+                self.item_sig(&sig.0);
+            }
+        }
+    }
+
+    fn export_kind(&mut self, kind: &ComponentExportKind<'a>) {
+        let space = export_space(kind);
+        match kind {
+            ComponentExportKind::CoreModule(r) => self.item_ref(space, r),
+            ComponentExportKind::Func(r) => self.item_ref(space, r),
+            ComponentExportKind::Value(r) => self.item_ref(space, r),
+            ComponentExportKind::Type(r) => self.item_ref(space, r),
+            ComponentExportKind::Component(r) => self.item_ref(space, r),
+            ComponentExportKind::Instance(r) => self.item_ref(space, r),
+        }
+    }
+
+    fn item_sig(&mut self, sig: &'a ItemSig<'a>) {
+        match &sig.kind {
+            ItemSigKind::CoreModule(ty) => self.core_type_use(ty, Self::module_type),
+            ItemSigKind::Func(ty) => self.type_use(ty, |w, ft| w.func_type(ft)),
+            ItemSigKind::Component(ty) => self.type_use(ty, |w, ct| w.component_type(ct)),
+            ItemSigKind::Instance(ty) => self.type_use(ty, |w, it| w.instance_type(it)),
+            ItemSigKind::Value(ty) => self.val_type(&ty.0),
+            ItemSigKind::Type(TypeBounds::Eq(idx)) => self.reference(IndexSpace::Type, *idx),
+            ItemSigKind::Type(TypeBounds::SubResource) => {}
+        }
+    }
+
+    fn type_use<T, F: FnOnce(&mut Self, &'a T)>(
+        &mut self,
+        ty: &'a ComponentTypeUse<'a, T>,
+        inline: F,
+    ) {
+        match ty {
+            ComponentTypeUse::Ref(r) => self.item_ref(IndexSpace::Type, r),
+            ComponentTypeUse::Inline(t) => {
+                inline(self, t);
+                // Inline types are expanded into a new type definition
+                self.define(IndexSpace::Type);
+            }
+        }
+    }
+
+    fn core_type_use<T, F: FnOnce(&mut Self, &'a T)>(
+        &mut self,
+        ty: &'a CoreTypeUse<'a, T>,
+        inline: F,
+    ) {
+        match ty {
+            CoreTypeUse::Ref(r) => self.core_item_ref(IndexSpace::CoreType, r),
+            CoreTypeUse::Inline(t) => {
+                inline(self, t);
+                self.define(IndexSpace::CoreType);
+            }
+        }
+    }
+
+    fn core_type(&mut self, ty: &'a CoreType<'a>) {
+        if let CoreTypeDef::Module(mt) = &ty.def {
+            self.nested(ty.id, |w| w.module_type(mt));
+        }
+    }
+
+    fn module_type(&mut self, mt: &'a ModuleType<'a>) {
+        for decl in &mt.decls {
+            if let ModuleTypeDecl::Alias(a) = decl {
+                self.alias(a);
+            }
+        }
+    }
+
+    fn ty(&mut self, ty: &'a Type<'a>) {
+        match &ty.def {
+            TypeDef::Defined(dt) => self.defined_type(dt),
+            TypeDef::Func(ft) => self.func_type(ft),
+            TypeDef::Component(ct) => self.nested(ty.id, |w| w.component_type(ct)),
+            TypeDef::Instance(it) => self.nested(ty.id, |w| w.instance_type(it)),
+            TypeDef::Resource(rt) => {
+                if let Some(dtor) = &rt.dtor {
+                    self.core_item_ref(IndexSpace::CoreFunc, dtor);
+                }
+            }
+        }
+    }
+
+    fn component_type(&mut self, ct: &'a ComponentType<'a>) {
+        for decl in &ct.decls {
+            match decl {
+                ComponentTypeDecl::CoreType(t) => self.core_type(t),
+                ComponentTypeDecl::Type(t) => self.ty(t),
+                ComponentTypeDecl::Alias(a) => self.alias(a),
+                ComponentTypeDecl::Import(i) => self.item_sig(&i.item),
+                ComponentTypeDecl::Export(e) => self.item_sig(&e.item),
+            }
+        }
+    }
+
+    fn instance_type(&mut self, it: &'a InstanceType<'a>) {
+        for decl in &it.decls {
+            match decl {
+                InstanceTypeDecl::CoreType(t) => self.core_type(t),
+                InstanceTypeDecl::Type(t) => self.ty(t),
+                InstanceTypeDecl::Alias(a) => self.alias(a),
+                InstanceTypeDecl::Export(e) => self.item_sig(&e.item),
+            }
+        }
+    }
+
+    fn func_type(&mut self, ft: &'a ComponentFunctionType<'a>) {
+        for param in ft.params.iter() {
+            self.val_type(&param.ty);
+        }
+        for result in ft.results.iter() {
+            self.val_type(&result.ty);
+        }
+    }
+
+    fn val_type(&mut self, ty: &'a ComponentValType<'a>) {
+        match ty {
+            ComponentValType::Ref(idx) => self.reference(IndexSpace::Type, *idx),
+            ComponentValType::Inline(ComponentDefinedType::Primitive(_)) => {}
+            ComponentValType::Inline(dt) => {
+                self.defined_type(dt);
+                self.define(IndexSpace::Type);
+            }
+        }
+    }
+
+    fn defined_type(&mut self, dt: &'a ComponentDefinedType<'a>) {
+        match dt {
+            ComponentDefinedType::Primitive(_)
+            | ComponentDefinedType::Flags(_)
+            | ComponentDefinedType::Enum(_) => {}
+            ComponentDefinedType::Record(r) => {
+                for field in &r.fields {
+                    self.val_type(&field.ty);
+                }
+            }
+            ComponentDefinedType::Variant(v) => {
+                for case in &v.cases {
+                    if let Some(ty) = &case.ty {
+                        self.val_type(ty);
+                    }
+                }
+            }
+            ComponentDefinedType::List(l) => self.val_type(&l.element),
+            ComponentDefinedType::Tuple(t) => {
+                for field in &t.fields {
+                    self.val_type(field);
+                }
+            }
+            ComponentDefinedType::Option(o) => self.val_type(&o.element),
+            ComponentDefinedType::Result(r) => {
+                if let Some(ok) = &r.ok {
+                    self.val_type(ok);
+                }
+                if let Some(err) = &r.err {
+                    self.val_type(err);
+                }
+            }
+            ComponentDefinedType::Own(idx) | ComponentDefinedType::Borrow(idx) => {
+                self.reference(IndexSpace::Type, *idx)
+            }
+        }
+    }
+}
+
+fn export_space(kind: &ComponentExportKind) -> IndexSpace {
+    match kind {
+        ComponentExportKind::CoreModule(_) => IndexSpace::CoreModule,
+        ComponentExportKind::Func(_) => IndexSpace::Func,
+        ComponentExportKind::Value(_) => IndexSpace::Value,
+        ComponentExportKind::Type(_) => IndexSpace::Type,
+        ComponentExportKind::Component(_) => IndexSpace::Component,
+        ComponentExportKind::Instance(_) => IndexSpace::Instance,
+    }
+}
+
+/// Count the number of items defined in each of the root component's index spaces by a list of fields
+pub fn count_definitions<'a>(fields: &'a [ComponentField<'a>]) -> HashMap<IndexSpace, u32> {
+    let mut walker = Walker::default();
+    walker.fields(fields);
+    walker
+        .defs
+        .into_iter()
+        .map(|(space, defs)| (space, defs.len() as u32))
+        .collect()
+}
+
+/// Find every reference into a component's index spaces, including references from nested scopes through outer aliases
+/// `id` is the component's own id, which outer aliases can refer to it by
+pub fn find_references<'a>(
+    id: Option<Id<'a>>,
+    fields: &'a [ComponentField<'a>],
+) -> Vec<IndexRef<'a>> {
+    let mut walker = Walker::new(id);
+    walker.fields(fields);
+    walker.refs
+}
+
+/// Find every reference to a function in a core module's function index space
+pub fn find_module_func_references<'a>(fields: &'a [ModuleField<'a>]) -> Vec<Index<'a>> {
+    fn expression<'a>(expr: &'a Expression<'a>, refs: &mut Vec<Index<'a>>) {
+        for instr in expr.instrs.iter() {
+            match instr {
+                Instruction::Call(idx)
+                | Instruction::ReturnCall(idx)
+                | Instruction::RefFunc(idx) => refs.push(*idx),
+                _ => {}
+            }
+        }
+    }
+    fn payload<'a>(payload: &'a ElemPayload<'a>, refs: &mut Vec<Index<'a>>) {
+        match payload {
+            ElemPayload::Indices(idxs) => refs.extend(idxs.iter().copied()),
+            ElemPayload::Exprs { exprs, .. } => {
+                for expr in exprs {
+                    expression(expr, refs);
+                }
+            }
+        }
+    }
+
+    let mut refs = Vec::new();
+    for field in fields {
+        match field {
+            ModuleField::Func(f) => {
+                if let core::FuncKind::Inline { expression: e, .. } = &f.kind {
+                    expression(e, &mut refs);
+                }
+            }
+            ModuleField::Table(t) => match &t.kind {
+                core::TableKind::Normal {
+                    init_expr: Some(e), ..
+                } => expression(e, &mut refs),
+                core::TableKind::Inline { payload: p, .. } => payload(p, &mut refs),
+                _ => {}
+            },
+            ModuleField::Global(g) => {
+                if let core::GlobalKind::Inline(e) = &g.kind {
+                    expression(e, &mut refs);
+                }
+            }
+            ModuleField::Elem(e) => {
+                if let ElemKind::Active { offset, .. } = &e.kind {
+                    expression(offset, &mut refs);
+                }
+                payload(&e.payload, &mut refs);
+            }
+            ModuleField::Export(e) if e.kind == ExportKind::Func => refs.push(e.item),
+            ModuleField::Start(idx) => refs.push(*idx),
+            _ => {}
+        }
+    }
+    refs
+}
+
+/// Resolves references into a component's index spaces, whether they are numbered or named
+pub struct IndexMap<'a> {
+    defs: HashMap<IndexSpace, Vec<Option<&'a ComponentField<'a>>>>,
+    names: HashMap<(IndexSpace, &'a str), u32>,
+}
+
+impl<'a> IndexMap<'a> {
+    /// Create a new `IndexMap` from the fields of a component
+    pub fn new(fields: &'a [ComponentField<'a>]) -> IndexMap<'a> {
+        let mut walker = Walker::default();
+        walker.fields(fields);
+        IndexMap {
+            defs: walker.defs,
+            names: walker.names,
+        }
+    }
+
+    /// Get the numeric index an `Index` refers to, or `None` if it names an item that doesn't exist
+    pub fn resolve(&self, space: IndexSpace, idx: Index) -> Option<u32> {
+        match idx {
+            Index::Num(num, _) => Some(num),
+            Index::Id(id) => self.names.get(&(space, id.name())).copied(),
+        }
+    }
+
+    /// Get the field that defines the item an `Index` refers to
+    /// Items created by text format sugar inside another field have no field of their own, so they resolve to `None`
+    pub fn field(&self, space: IndexSpace, idx: Index) -> Option<&'a ComponentField<'a>> {
+        self.nth(space, self.resolve(space, idx)?)
+    }
+
+    /// Get the field that defines the item at a numeric index
+    pub fn nth(&self, space: IndexSpace, idx: u32) -> Option<&'a ComponentField<'a>> {
+        self.defs.get(&space)?.get(idx as usize).copied().flatten()
+    }
+}
+
+/// Tracks the items inserted into a component's index spaces, and shifts references to existing items to match
+#[derive(Default)]
+pub struct IndexShifts {
+    bounds: HashMap<IndexSpace, Vec<u32>>,
+}
+
+impl IndexShifts {
+    /// Create a new `IndexShifts` with no insertions
+    pub fn new() -> IndexShifts {
+        IndexShifts::default()
+    }
+
+    /// Record that a new item in `space` is inserted in front of `fields[position]`
+    /// Returns the index the new item will have
+    pub fn insert<'a>(
+        &mut self,
+        fields: &'a [ComponentField<'a>],
+        position: usize,
+        space: IndexSpace,
+    ) -> u32 {
+        let idx = count_definitions(&fields[..position])
+            .get(&space)
+            .copied()
+            .unwrap_or(0);
+        self.bounds.entry(space).or_default().push(idx);
+        idx
+    }
+
+    /// Get the amount an index into an original index space needs to increase by to point to the same item
+    pub fn shift(&self, space: IndexSpace, idx: u32) -> u32 {
+        self.bounds
+            .get(&space)
+            .map(|bounds| bounds.iter().filter(|b| idx >= **b).count() as u32)
+            .unwrap_or(0)
+    }
+
+    /// Rewrite every numeric reference in a component's fields so it points to the same item after the insertions
+    /// Named references don't need rewriting, since names are resolved after the new items are in place
+    pub fn apply<'a>(
+        &self,
+        id: Option<Id<'a>>,
+        fields: &'a [ComponentField<'a>],
+        total_increment: &mut OffsetTracker,
+    ) {
+        for (space, idx) in find_references(id, fields) {
+            if let Index::Num(num, _) = idx {
+                let shift = self.shift(space, num);
+                if shift > 0 {
+                    total_increment.shift_idx(idx, shift);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wast::parser::{parse, ParseBuffer};
+    use wast::Wat;
+
+    use super::*;
+    use crate::annotator::utils::get_fields;
+
+    const COMPONENT: &str = r#"(component $c
+        (core module $m
+            (func (export "f"))
+            (func (export "g"))
+        )
+        (core instance $i (instantiate $m))
+        (core func $f (alias core export $i "f"))
+        (core instance (instantiate 0))
+        (type $t (func))
+        (func (type $t) (canon lift (core func $i "g")))
+    )"#;
+
+    #[test]
+    fn counts_items_defined_by_sugar() {
+        let buf = ParseBuffer::new(COMPONENT).unwrap();
+        let wat = parse::<Wat>(&buf).unwrap();
+        let fields = get_fields(&wat).unwrap();
+        let counts = count_definitions(fields);
+        assert_eq!(counts[&IndexSpace::CoreModule], 1);
+        assert_eq!(counts[&IndexSpace::CoreInstance], 2);
+        // The inline `(core func $i "g")` is an alias placed in front of the lift
+        assert_eq!(counts[&IndexSpace::CoreFunc], 2);
+        assert_eq!(counts[&IndexSpace::Type], 1);
+        assert_eq!(counts[&IndexSpace::Func], 1);
+    }
+
+    #[test]
+    fn resolves_names_and_sugar() {
+        let buf = ParseBuffer::new(COMPONENT).unwrap();
+        let wat = parse::<Wat>(&buf).unwrap();
+        let fields = get_fields(&wat).unwrap();
+        let map = IndexMap::new(fields);
+        let f = Id::new("f", wast::token::Span::from_offset(0));
+        assert_eq!(map.resolve(IndexSpace::CoreFunc, Index::Id(f)), Some(0));
+        assert!(matches!(
+            map.nth(IndexSpace::CoreFunc, 0),
+            Some(ComponentField::CoreFunc(_))
+        ));
+        // Sugar has no field of its own
+        assert!(map.nth(IndexSpace::CoreFunc, 1).is_none());
+        assert!(map.nth(IndexSpace::CoreFunc, 2).is_none());
+    }
+
+    #[test]
+    fn shifts_indices_at_or_after_insertions() {
+        let buf = ParseBuffer::new(COMPONENT).unwrap();
+        let wat = parse::<Wat>(&buf).unwrap();
+        let fields = get_fields(&wat).unwrap();
+        let mut shifts = IndexShifts::new();
+        // In front of the second core instance, so after `$i`
+        assert_eq!(shifts.insert(fields, 3, IndexSpace::CoreInstance), 1);
+        assert_eq!(shifts.insert(fields, 0, IndexSpace::CoreModule), 0);
+        assert_eq!(shifts.shift(IndexSpace::CoreInstance, 0), 0);
+        assert_eq!(shifts.shift(IndexSpace::CoreInstance, 1), 1);
+        assert_eq!(shifts.shift(IndexSpace::CoreModule, 0), 1);
+        assert_eq!(shifts.shift(IndexSpace::CoreFunc, 0), 0);
+        // Two insertions at the same place shift by two
+        shifts.insert(fields, 0, IndexSpace::CoreModule);
+        assert_eq!(shifts.shift(IndexSpace::CoreModule, 0), 2);
+    }
+
+    #[test]
+    fn rewrites_numeric_references() {
+        let buf = ParseBuffer::new(COMPONENT).unwrap();
+        let wat = parse::<Wat>(&buf).unwrap();
+        let Wat::Component(component) = &wat else {
+            panic!("not a component");
+        };
+        let fields = get_fields(&wat).unwrap();
+        let mut shifts = IndexShifts::new();
+        shifts.insert(fields, 0, IndexSpace::CoreModule);
+        let mut tracker = OffsetTracker::new();
+        shifts.apply(component.id, fields, &mut tracker);
+        let output = tracker.apply(COMPONENT);
+        assert!(output.contains("(core instance (instantiate 1))"));
+        // Named references are left alone
+        assert!(output.contains("(core instance $i (instantiate $m))"));
+    }
+}
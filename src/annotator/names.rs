@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::annotator::debug::{
+    add_module_functions, CodeModule, DebugFuncInfo, DebugLineInfo, WatLineMapper,
+};
+use crate::noise::NoiseLevel;
+use crate::printer::println_annotate_dbg;
+
+/// The name of the file that lists the functions covered by name, one per line
+pub const FUNCTION_LISTING_FILE: &str = "wasm-functions.txt";
+
+/// Cover every named function by how often it's entered, if no code module has line info from DWARF or a source map
+/// The functions are written to `listing_path`, one per line, so that line N of that file reports the Nth function
+/// Modules without a `name` section are skipped, since their functions can't be told apart in a report, and so are empty functions, since they can't be instrumented
+/// Returns whether any functions were found, in which case the listing was written
+pub fn add_name_section_functions(
+    code_modules: &[CodeModule],
+    map: &mut WatLineMapper,
+    listing_path: &Path,
+    noise_level: NoiseLevel,
+) -> io::Result<bool> {
+    if map.lines().next().is_some() {
+        return Ok(false);
+    }
+    let mut listing_idx = None;
+    let mut listing = String::new();
+    let mut line = 0;
+    for (code_module_idx, code_module) in code_modules.iter().enumerate() {
+        if code_module.funcs.iter().all(|func| func.name.is_none()) {
+            if noise_level.debug() {
+                println_annotate_dbg(format!(
+                    "Core module {} has no function names, so it isn't covered",
+                    code_module_idx
+                ));
+            }
+            continue;
+        }
+        let path_idx = *listing_idx.get_or_insert_with(|| map.add_file(listing_path.to_path_buf()));
+        map.set_entries_only(code_module_idx);
+        for func in code_module.funcs.iter().filter(|func| !func.is_empty) {
+            line += 1;
+            let name = func
+                .name
+                .as_deref()
+                .map(demangle)
+                .unwrap_or_else(|| format!("wasm-function[{}]", func.func_idx));
+            listing.push_str(&name);
+            listing.push('\n');
+            // A single row at the start of the body puts one counter at the function's entry
+            map.add_line(DebugLineInfo {
+                address: func.range.start,
+                path_idx,
+                line,
+                column: 1,
+                discriminator: 0,
+                is_stmt: true,
+                prologue_end: false,
+                epilogue_begin: false,
+                code_module_idx,
+            });
+            map.add_function(
+                code_module_idx,
+                DebugFuncInfo {
+                    low_pc: func.range.start,
+                    high_pc: Some(func.range.end - 1),
+                    path_idx,
+                    name,
+                },
+            );
+        }
+        add_module_functions(map, code_module_idx, noise_level);
+    }
+    if line == 0 {
+        return Ok(false);
+    }
+    if noise_level.debug() {
+        println_annotate_dbg(format!(
+            "No line info found, covering {} functions by name instead",
+            line
+        ));
+    }
+    fs::write(listing_path, listing)?;
+    Ok(true)
+}
+
+/// Demangle a Rust or C++ symbol name, or return it unchanged if it isn't mangled
+/// Rust hashes are left out, since they only make names harder to read
+pub fn demangle(name: &str) -> String {
+    if let Ok(symbol) = rustc_demangle::try_demangle(name) {
+        return format!("{:#}", symbol);
+    }
+    cpp_demangle::Symbol::new(name)
+        .ok()
+        .and_then(|symbol| symbol.demangle(&Default::default()).ok())
+        .unwrap_or_else(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangles_rust_and_cpp_names() {
+        assert_eq!(
+            demangle("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "core::fmt::write"
+        );
+        assert_eq!(demangle("_RNvCs1234_7mycrate3foo"), "mycrate::foo");
+        assert_eq!(demangle("_Z3addii"), "add(int, int)");
+        // Names that aren't mangled are kept as they are
+        assert_eq!(demangle("main"), "main");
+        assert_eq!(demangle("wasm-function[3]"), "wasm-function[3]");
+    }
+}
@@ -1,100 +1,167 @@
-use core::str;
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
 use regex::Regex;
 use wast::token::Index;
 
 // Makes it more readable?
 type Location = usize;
-type Offset = usize;
 
-/// A struct for tracking modifications to a file to map offsets between the original and modified versions
+/// A modification to the original string, which replaces `removed` bytes with `text`
+struct Edit {
+    text: String,
+    removed: usize,
+    pass: &'static str,
+}
+
+/// Where a modification ended up in the modified string
+struct AppliedEdit {
+    original: Location,
+    /// The location in the original string that unmodified text picks up from after this modification
+    original_after: Location,
+    start: Location,
+    end: Location,
+    pass: &'static str,
+}
+
+/// A struct for collecting modifications to a file, and mapping offsets between the original and modified versions
+/// Modifications are kept sorted by where they go in the original file, and are all made at once by [`OffsetTracker::apply`]
+/// Every modification is tagged with the annotator pass that made it, so problems in the modified file can be traced back
 pub struct OffsetTracker {
-    offsets: Vec<(Location, Offset)>,
+    edits: BTreeMap<Location, Vec<Edit>>,
+    applied: Vec<AppliedEdit>,
+    pass: &'static str,
 }
 
 impl OffsetTracker {
     /// create a new `OffsetTracker`
     pub fn new() -> OffsetTracker {
         OffsetTracker {
-            offsets: Vec::new(),
+            edits: BTreeMap::new(),
+            applied: Vec::new(),
+            pass: "unknown",
         }
     }
 
-    fn get_real_loc(&self, start: Location) -> Location {
-        start
-            + self
-                .offsets
-                .iter()
-                .filter(|(loc, _)| *loc <= start)
-                .map(|(_, offset)| *offset)
-                .sum::<usize>()
+    /// Set the name of the annotator pass that following modifications belong to
+    pub fn set_pass(&mut self, pass: &'static str) {
+        self.pass = pass;
+    }
+
+    fn push(&mut self, original_loc: Location, removed: usize, text: String) {
+        self.edits.entry(original_loc).or_default().push(Edit {
+            text,
+            removed,
+            pass: self.pass,
+        });
     }
 
-    // Should somehow link this struct to specific string
-    // TODO!
-    /// Insert a string `msg` into another string `s` at position `original_loc`
-    pub fn add_to_string(&mut self, s: &mut String, original_loc: Location, msg: &str) {
-        let loc = self.get_real_loc(original_loc);
-        s.insert_str(loc, msg);
-        self.offsets.push((original_loc, msg.as_bytes().len()));
+    /// Insert the string `msg` at position `original_loc` in the original string
+    /// Strings inserted at the same position are placed in the order they were inserted
+    pub fn insert(&mut self, original_loc: Location, msg: &str) {
+        self.push(original_loc, 0, msg.to_string());
     }
 
     // I feel kinda gross putting very implementation specific code here, but like
     // idk
     /// Increment a specific `Index`, with a lower bound to control whether the index should be increased or not
-    pub fn increment_idx(&mut self, output: &mut String, idx: Index, lower_bound: Option<u32>) {
-        match idx {
-            Index::Num(num, _) => {
-                if num >= lower_bound.unwrap_or(0) {
-                    let loc = self.get_real_loc(idx.span().offset());
-
-                    let old = num.to_string();
-                    for _ in 0..old.as_bytes().len() {
-                        output.remove(loc);
-                    }
-                    let new = (num + 1).to_string();
-                    output.insert_str(loc, &new);
-                    let tup = (
-                        idx.span().offset(),
-                        new.as_bytes().len() - old.as_bytes().len(),
-                    );
-                    self.offsets.push(tup);
-                }
+    pub fn increment_idx(&mut self, idx: Index, lower_bound: Option<u32>) {
+        if let Index::Num(num, _) = idx {
+            if num >= lower_bound.unwrap_or(0) {
+                self.shift_idx(idx, 1);
             }
-            Index::Id(_) => {}
         }
     }
 
-    /// Match a regex against a modified string, and use the regex information to change the string
-    /// The provided function should take in the string, the start of the match, and the end of the match, and should return a (location, offset) tuple telling the tracker how the string was modified
+    /// Increase a specific numeric `Index` by `amount`
+    pub fn shift_idx(&mut self, idx: Index, amount: u32) {
+        if let Index::Num(num, _) = idx {
+            let old = num.to_string();
+            let new = (num + amount).to_string();
+            self.push(idx.span().offset(), old.len(), new);
+        }
+    }
+
+    /// Match a regex against the original string `text` at exactly `loc`, and use the match to modify it
+    /// The provided function should take in the start and end of the match, and return where to insert a string along with the string itself
+    /// Returns false without modifying anything if the regex doesn't match at `loc`
     pub fn modify_with_regex_match<F>(
         &mut self,
-        output: &mut String,
+        text: &str,
         re: &Regex,
         loc: Location,
         f: F,
-    ) where
-        F: FnOnce(&mut String, Location, Location) -> (Location, Offset),
+    ) -> bool
+    where
+        F: FnOnce(Location, Location) -> (Location, String),
     {
-        let loc = self.get_real_loc(loc);
-        let slice = str::from_utf8_mut(unsafe { output[loc..].as_bytes_mut() }).unwrap();
-        let m = re.find(slice);
-        if let Some(m) = m {
-            let (start, end) = (m.start(), m.end());
-            let pair = f(output, loc + start, loc + end);
-            self.offsets.push(pair);
+        // A later match would belong to some other item
+        let Some(m) = re.find_at(text, loc).filter(|m| m.start() == loc) else {
+            return false;
+        };
+        let (modified_at, msg) = f(m.start(), m.end());
+        self.push(modified_at, 0, msg);
+        true
+    }
+
+    /// Make every modification to the original string `text` in a single pass, and return the modified string
+    /// Afterwards, locations in the modified string can be mapped back with [`OffsetTracker::get_original_loc`]
+    pub fn apply(&mut self, text: &str) -> String {
+        let added = self
+            .edits
+            .values()
+            .flatten()
+            .map(|edit| edit.text.len())
+            .sum::<usize>();
+        let mut output = String::with_capacity(text.len() + added);
+        let mut applied = Vec::new();
+        let mut copied_to = 0;
+        for (&loc, edits) in &self.edits {
+            if loc > copied_to {
+                output.push_str(&text[copied_to..loc]);
+                copied_to = loc;
+            }
+            for edit in edits {
+                let start = output.len();
+                output.push_str(&edit.text);
+                // If two modifications replace the same text, it's only removed once
+                copied_to = copied_to.max(loc + edit.removed);
+                applied.push(AppliedEdit {
+                    original: loc,
+                    original_after: copied_to,
+                    start,
+                    end: output.len(),
+                    pass: edit.pass,
+                });
+            }
+        }
+        output.push_str(&text[copied_to..]);
+        self.applied = applied;
+        output
+    }
+
+    /// Map a location in the modified string back to the original one
+    /// If the location is inside text that was inserted, the location it was inserted at is returned, along with the pass that inserted it
+    /// This only takes modifications into account once they've been made with [`OffsetTracker::apply`]
+    pub fn get_original_loc(&self, modified: Location) -> (Location, Option<&'static str>) {
+        let idx = self.applied.partition_point(|edit| edit.start <= modified);
+        let Some(edit) = idx.checked_sub(1).map(|idx| &self.applied[idx]) else {
+            return (modified, None);
+        };
+        if modified < edit.end {
+            (edit.original, Some(edit.pass))
+        } else {
+            (edit.original_after + (modified - edit.end), None)
         }
     }
 
-    /// Slice the string `output` starting at `start`
-    pub fn get_slice_from<'a>(&self, output: &'a String, start: Location) -> &'a str {
-        let loc = start
-            + self
-                .offsets
-                .iter()
-                .filter(|(loc, _)| *loc <= start)
-                .map(|(_, offset)| *offset)
-                .sum::<usize>();
-        str::from_utf8(&output.as_bytes()[loc..]).unwrap()
+    /// Get the passes that modified the original string between `start` and `end`
+    pub fn passes_between(&self, start: Location, end: Location) -> Vec<&'static str> {
+        self.edits
+            .range(start..=end)
+            .flat_map(|(_, edits)| edits.iter().map(|edit| edit.pass))
+            .unique()
+            .collect()
     }
 }
 
@@ -103,3 +170,69 @@ impl Default for OffsetTracker {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wast::token::Span;
+
+    use super::*;
+
+    #[test]
+    fn applies_edits_in_original_order() {
+        let mut tracker = OffsetTracker::new();
+        tracker.insert(6, "big ");
+        tracker.insert(0, ">");
+        tracker.insert(6, "red ");
+        assert_eq!(tracker.apply("hello world"), ">hello big red world");
+    }
+
+    #[test]
+    fn replaces_shifted_indices() {
+        let text = "(call 9)";
+        let mut tracker = OffsetTracker::new();
+        tracker.shift_idx(Index::Num(9, Span::from_offset(6)), 3);
+        // Only numbers at or above the bound are incremented
+        tracker.increment_idx(Index::Num(0, Span::from_offset(1)), Some(1));
+        assert_eq!(tracker.apply(text), "(call 12)");
+    }
+
+    #[test]
+    fn maps_modified_locations_back() {
+        let text = "(func 9 (nop))";
+        let mut tracker = OffsetTracker::new();
+        tracker.set_pass("first");
+        tracker.insert(0, ";; hi\n");
+        tracker.set_pass("second");
+        tracker.shift_idx(Index::Num(9, Span::from_offset(6)), 1);
+        let output = tracker.apply(text);
+        assert_eq!(output, ";; hi\n(func 10 (nop))");
+        // Inside inserted text, the location it was inserted at is returned
+        assert_eq!(tracker.get_original_loc(2), (0, Some("first")));
+        assert_eq!(tracker.get_original_loc(13), (6, Some("second")));
+        // Unmodified text maps back to where it was
+        assert_eq!(tracker.get_original_loc(6), (0, None));
+        assert_eq!(
+            tracker.get_original_loc(output.find("(nop)").unwrap()),
+            (8, None)
+        );
+        assert_eq!(tracker.passes_between(0, 6), vec!["first", "second"]);
+        assert_eq!(tracker.passes_between(7, text.len()), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn only_matches_regexes_at_the_location() {
+        let text = "(a 1) (b 2) (a 3)";
+        let re = Regex::new(r"\(a [0-9]").unwrap();
+        let mut tracker = OffsetTracker::new();
+        assert!(!tracker.modify_with_regex_match(text, &re, 6, |_, end| (end, "!".to_string())));
+        assert!(tracker.modify_with_regex_match(text, &re, 12, |_, end| (end, "!".to_string())));
+        assert_eq!(tracker.apply(text), "(a 1) (b 2) (a 3!)");
+    }
+
+    #[test]
+    fn maps_nothing_back_before_applying() {
+        let mut tracker = OffsetTracker::new();
+        tracker.insert(0, "abc");
+        assert_eq!(tracker.get_original_loc(2), (2, None));
+    }
+}
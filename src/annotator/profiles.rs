@@ -0,0 +1,175 @@
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The text wit-bindgen puts in the header comment of every file it generates
+const WIT_BINDGEN_HEADER: &str = "Generated by `wit-bindgen`";
+/// How many lines at the start of a file are searched for the wit-bindgen header
+const HEADER_LINES: usize = 5;
+
+/// A group of source files that belong to the toolchain or are generated, rather than written by the user
+/// Code from these files isn't instrumented, unless the profile is included with `AnnotateOptions::included_profiles`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExclusionProfile {
+    /// The Rust standard library, the crates it vendors, and crates from the Cargo registry
+    RustStd,
+    /// Bindings generated by wit-bindgen, which are recognized by their header
+    WitBindgen,
+    /// The C standard library and runtime from the WASI sysroot
+    CSysroot,
+}
+
+impl ExclusionProfile {
+    /// Every profile, all of which are excluded by default
+    pub const ALL: [ExclusionProfile; 3] = [
+        ExclusionProfile::RustStd,
+        ExclusionProfile::WitBindgen,
+        ExclusionProfile::CSysroot,
+    ];
+
+    /// Get the name of the profile, as it's passed on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExclusionProfile::RustStd => "rust-std",
+            ExclusionProfile::WitBindgen => "wit-bindgen",
+            ExclusionProfile::CSysroot => "c-sysroot",
+        }
+    }
+
+    /// Check whether the source file at `path` belongs to this profile
+    /// The standard libraries are recognized by the paths their toolchains remap them to, so they don't have to exist on this machine
+    pub fn matches(&self, path: &Path) -> bool {
+        let text = path.to_string_lossy();
+        match self {
+            ExclusionProfile::RustStd => {
+                (text.starts_with("/rustc/") && text.contains("/library/"))
+                    || text.starts_with("/rust/deps/")
+                    || text.contains("/.cargo/registry/")
+                    || cargo_home().is_some_and(|home| path.starts_with(home.join("registry")))
+            }
+            ExclusionProfile::WitBindgen => fs::read_to_string(path).is_ok_and(|source| {
+                source
+                    .lines()
+                    .take(HEADER_LINES)
+                    .any(|line| line.contains(WIT_BINDGEN_HEADER))
+            }),
+            ExclusionProfile::CSysroot => {
+                text.starts_with("wasisdk://")
+                    || [
+                        "wasi-libc",
+                        "wasi-sysroot",
+                        "libc-top-half",
+                        "libc-bottom-half",
+                        "compiler-rt",
+                    ]
+                    .iter()
+                    .any(|dir| path.components().any(|c| c.as_os_str() == *dir))
+            }
+        }
+    }
+}
+
+impl Display for ExclusionProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for ExclusionProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExclusionProfile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown profile `{}`, expected one of: {}",
+                    s,
+                    ExclusionProfile::ALL.map(|p| p.name()).join(", ")
+                )
+            })
+    }
+}
+
+/// Get the Cargo home directory, if it's been moved from `~/.cargo` with `CARGO_HOME`
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME").map(PathBuf::from)
+}
+
+/// Find the first of the excluded profiles that the source file at `path` belongs to
+pub fn excluding_profile(path: &Path, included: &[ExclusionProfile]) -> Option<ExclusionProfile> {
+    ExclusionProfile::ALL
+        .into_iter()
+        .filter(|profile| !included.contains(profile))
+        .find(|profile| profile.matches(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_toolchain_paths() {
+        let rust_std = [
+            "/rustc/9b00956e56009bab2aa15d7bff10916599e3d6d6/library/core/src/panicking.rs",
+            "/rust/deps/dlmalloc-0.2.6/src/dlmalloc.rs",
+            "/home/me/.cargo/registry/src/index.crates.io-6f17d22bba15001f/log-0.4.22/src/lib.rs",
+        ];
+        for path in rust_std {
+            assert!(
+                ExclusionProfile::RustStd.matches(Path::new(path)),
+                "{}",
+                path
+            );
+        }
+        assert!(!ExclusionProfile::RustStd.matches(Path::new("/rustc/src/main.rs")));
+        assert!(!ExclusionProfile::RustStd.matches(Path::new("/home/me/app/src/main.rs")));
+
+        let c_sysroot = [
+            "wasisdk://v24.0/build/sysroot/wasi-libc/libc-bottom-half/sources/__main_void.c",
+            "/opt/wasi-sdk/src/wasi-libc/libc-top-half/musl/src/stdio/printf.c",
+        ];
+        for path in c_sysroot {
+            assert!(
+                ExclusionProfile::CSysroot.matches(Path::new(path)),
+                "{}",
+                path
+            );
+        }
+        // Only whole directory names count
+        assert!(!ExclusionProfile::CSysroot.matches(Path::new("/home/me/my-wasi-libc-app/main.c")));
+    }
+
+    #[test]
+    fn matches_files_with_the_wit_bindgen_header() {
+        let dir = env::temp_dir().join(format!("wcov-profiles-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let generated = dir.join("bindings.rs");
+        fs::write(
+            &generated,
+            "// Generated by `wit-bindgen` 0.30.0. DO NOT EDIT!\nfn f() {}\n",
+        )
+        .unwrap();
+        let written = dir.join("lib.rs");
+        fs::write(&written, "fn f() {}\n").unwrap();
+        assert!(ExclusionProfile::WitBindgen.matches(&generated));
+        assert!(!ExclusionProfile::WitBindgen.matches(&written));
+        assert!(!ExclusionProfile::WitBindgen.matches(&dir.join("missing.rs")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_included_profiles() {
+        let path = Path::new("/rust/deps/dlmalloc-0.2.6/src/dlmalloc.rs");
+        assert_eq!(
+            excluding_profile(path, &[]),
+            Some(ExclusionProfile::RustStd)
+        );
+        assert_eq!(excluding_profile(path, &[ExclusionProfile::RustStd]), None);
+        assert_eq!("c-sysroot".parse(), Ok(ExclusionProfile::CSysroot));
+        assert!("libc".parse::<ExclusionProfile>().is_err());
+    }
+}
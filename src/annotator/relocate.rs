@@ -0,0 +1,741 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::mem::discriminant;
+use std::ops::Range;
+
+use gimli::write::{self, Address, AttributeValue, FileInfo, LineProgram, LineString};
+use gimli::{EndianSlice, LittleEndian, Reader};
+use wasmparser::Payload::*;
+use wasmparser::{Operator, Parser, TypeRef};
+
+use crate::annotator::annotate::{INC_FUNC_NAME, INC_MODULE_NAME};
+use crate::annotator::data::DebugDataOwned;
+use crate::annotator::debug::find_code_modules;
+use crate::noise::NoiseLevel;
+use crate::printer::{println_annotate_dbg, println_annotate_error};
+
+type ReadDwarf<'a> = gimli::Dwarf<EndianSlice<'a, LittleEndian>>;
+/// The range of a function body, and its instructions with their offsets
+type Body<'a> = (Range<usize>, Vec<(Operator<'a>, usize)>);
+/// Custom sections to write, by name
+type CustomSections = Vec<(String, Vec<u8>)>;
+/// The sections of a binary, by id
+type Sections<'a> = Vec<(u8, &'a [u8])>;
+
+/// The id of a custom section
+const CUSTOM_SECTION: u8 = 0;
+/// The id of a component section holding a core module
+const CORE_MODULE_SECTION: u8 = 1;
+/// The id of a component section holding a nested component
+const COMPONENT_SECTION: u8 = 4;
+/// The prefix of every DWARF section name
+const DWARF_SECTION_PREFIX: &str = ".debug_";
+
+/// The code of a core module, along with what's needed to recognize the probes added to it
+struct ModuleCode<'a> {
+    /// The offset in the module that its `code` section starts at, which DWARF addresses are relative to, if it has one
+    code_offset: Option<usize>,
+    /// The range of each function body, and its instructions with their offsets, all relative to the module
+    bodies: Vec<Body<'a>>,
+    /// The number of globals the module imports and defines
+    globals: u32,
+    /// The index of the imported counter function, if the module has one
+    inc_counter: Option<u32>,
+    /// The module's DWARF sections, by name
+    dwarf_sections: HashMap<&'a str, &'a [u8]>,
+}
+
+impl<'a> ModuleCode<'a> {
+    fn parse(module: &'a [u8]) -> Result<ModuleCode<'a>, Box<dyn Error>> {
+        let mut code = ModuleCode {
+            code_offset: None,
+            bodies: Vec::new(),
+            globals: 0,
+            inc_counter: None,
+            dwarf_sections: HashMap::new(),
+        };
+        let mut imported_funcs = 0;
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                ImportSection(imports) => {
+                    for import in imports {
+                        let import = import?;
+                        match import.ty {
+                            TypeRef::Func(_) => {
+                                if import.module == INC_MODULE_NAME && import.name == INC_FUNC_NAME
+                                {
+                                    code.inc_counter = Some(imported_funcs);
+                                }
+                                imported_funcs += 1;
+                            }
+                            TypeRef::Global(_) => code.globals += 1,
+                            _ => {}
+                        }
+                    }
+                }
+                GlobalSection(globals) => code.globals += globals.count(),
+                CodeSectionStart { range, .. } => code.code_offset = Some(range.start),
+                CodeSectionEntry(body) => {
+                    let mut reader = body.get_operators_reader()?;
+                    let mut ops = Vec::new();
+                    while !reader.eof() {
+                        ops.push(reader.read_with_offset()?);
+                    }
+                    code.bodies.push((body.range(), ops));
+                }
+                CustomSection(section) if section.name().starts_with(DWARF_SECTION_PREFIX) => {
+                    code.dwarf_sections.insert(section.name(), section.data());
+                }
+                _ => {}
+            }
+        }
+        Ok(code)
+    }
+
+    /// Get the number of instructions in the probe that starts at `ops[idx]`, if there is one
+    /// A probe either calls the counter function with five constants, or increments a counter global that the instrumentation added
+    fn probe_len(
+        &self,
+        ops: &[(Operator, usize)],
+        idx: usize,
+        original_globals: u32,
+    ) -> Option<usize> {
+        let ops = ops[idx..].iter().map(|(op, _)| op).collect::<Vec<_>>();
+        match ops.as_slice() {
+            [Operator::I32Const { .. }, Operator::I32Const { .. }, Operator::I32Const { .. }, Operator::I32Const { .. }, Operator::I32Const { .. }, Operator::Call { function_index }, ..]
+                if Some(*function_index) == self.inc_counter =>
+            {
+                Some(6)
+            }
+            [Operator::GlobalGet { global_index: get }, Operator::I32Const { value: 1 }, Operator::I32Add, Operator::GlobalSet { global_index: set }, ..]
+                if get == set && *get >= original_globals =>
+            {
+                Some(4)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A map from the code addresses of an original module to the addresses of the same code in its instrumented version
+#[derive(Default)]
+pub struct AddressMap {
+    addresses: BTreeMap<u64, u64>,
+    inverse: BTreeMap<u64, u64>,
+    code_end: u64,
+}
+
+impl AddressMap {
+    /// Map the code of `original` to the code of `instrumented`, which has the same instructions with probes in front of some of them
+    /// An instruction with a probe in front of it is mapped to the start of the probe, so the probe counts as part of its line
+    /// Both the probe and the instruction map back to the original instruction
+    fn new(
+        original: &ModuleCode,
+        instrumented: &ModuleCode,
+        noise_level: NoiseLevel,
+    ) -> AddressMap {
+        let mut map = AddressMap::default();
+        let original_start = original.code_offset.unwrap_or_default();
+        let instrumented_start = instrumented.code_offset.unwrap_or_default();
+        for (func_idx, ((range, ops), (new_range, new_ops))) in
+            original.bodies.iter().zip(&instrumented.bodies).enumerate()
+        {
+            map.insert(
+                (range.start - original_start) as u64,
+                (new_range.start - instrumented_start) as u64,
+            );
+            map.insert(
+                (range.end - original_start) as u64,
+                (new_range.end - instrumented_start) as u64,
+            );
+            map.code_end = (range.end - original_start) as u64;
+
+            let mut matched = Vec::new();
+            let mut new_idx = 0;
+            let mut probe_start = None;
+            for (op, offset) in ops {
+                while let Some(len) = instrumented.probe_len(new_ops, new_idx, original.globals) {
+                    probe_start.get_or_insert(new_ops[new_idx].1);
+                    new_idx += len;
+                }
+                let Some((new_op, new_offset)) = new_ops.get(new_idx) else {
+                    break;
+                };
+                if discriminant(op) != discriminant(new_op) {
+                    break;
+                }
+                matched.push((*offset, probe_start.take(), *new_offset));
+                new_idx += 1;
+            }
+            // Only the function's bounds can be trusted if its instructions couldn't all be matched
+            if matched.len() != ops.len() {
+                if noise_level.debug() {
+                    println_annotate_dbg(format!(
+                        "Couldn't match the instructions of function {} to its instrumented version, so only its bounds are moved",
+                        func_idx
+                    ));
+                }
+                continue;
+            }
+            for (offset, probe_start, new_offset) in matched {
+                let address = (offset - original_start) as u64;
+                map.inverse
+                    .insert((new_offset - instrumented_start) as u64, address);
+                map.insert(
+                    address,
+                    (probe_start.unwrap_or(new_offset) - instrumented_start) as u64,
+                );
+            }
+        }
+        map
+    }
+
+    fn insert(&mut self, address: u64, new_address: u64) {
+        self.addresses.insert(address, new_address);
+        self.inverse.insert(new_address, address);
+    }
+
+    /// Get the instrumented address of an original one
+    /// Addresses between two known ones keep their distance from the one before them, without passing the one after them
+    /// Addresses past the end of the code are left alone, since they're tombstones for removed code
+    pub fn convert(&self, address: u64) -> u64 {
+        if address > self.code_end {
+            return address;
+        }
+        let Some((known, new_known)) = self.addresses.range(..=address).next_back() else {
+            return address;
+        };
+        let converted = new_known + (address - known);
+        match self.addresses.range(address..).next() {
+            Some((_, new_next)) => converted.min(*new_next),
+            None => converted,
+        }
+    }
+
+    /// Get the original address of an instrumented one, if it's exactly one that was mapped
+    pub fn original(&self, new_address: u64) -> Option<u64> {
+        self.inverse.get(&new_address).copied()
+    }
+}
+
+/// Move the DWARF of every core module in an instrumented binary to the addresses its code moved to
+/// Modules are matched up with the ones in the original binary by order, and modules whose DWARF can't be converted have it removed instead, so it can't point to the wrong code
+pub fn relocate_dwarf(
+    original: &[u8],
+    instrumented: &[u8],
+    noise_level: NoiseLevel,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let originals = core_modules(original)?;
+    let mut module_idx = 0;
+    map_core_modules(instrumented, &mut |module| {
+        let original = originals.get(module_idx).copied();
+        module_idx += 1;
+        let Some(original) = original else {
+            return Ok(module.to_vec());
+        };
+        let original = ModuleCode::parse(original)?;
+        let instrumented = ModuleCode::parse(module)?;
+        if original.dwarf_sections.is_empty() {
+            return Ok(module.to_vec());
+        }
+        let map = AddressMap::new(&original, &instrumented, noise_level);
+        match convert_dwarf(&original.dwarf_sections, &map) {
+            Ok(sections) => replace_dwarf_sections(module, sections),
+            Err(e) => {
+                if noise_level.err() {
+                    println_annotate_error(format!(
+                        "Error: Couldn't move the DWARF of core module {}, so it was removed: {}",
+                        module_idx - 1,
+                        e
+                    ));
+                }
+                replace_dwarf_sections(module, Vec::new())
+            }
+        }
+    })
+}
+
+/// Fill in where the code of every code module in `data` ended up in `instrumented`, and the probe offset table that maps it back to `original`
+/// `instrumented` has to be the binary that runs, since traps point to offsets in it
+pub fn add_probe_offsets(
+    original: &[u8],
+    instrumented: &[u8],
+    data: &mut DebugDataOwned,
+    noise_level: NoiseLevel,
+) -> Result<(), Box<dyn Error>> {
+    let parse = |binary| -> Result<Vec<ModuleCode>, Box<dyn Error>> {
+        core_modules(binary)?
+            .into_iter()
+            .map(ModuleCode::parse)
+            .collect()
+    };
+    let originals = parse(original)?;
+    let instrumented_modules = parse(instrumented)?;
+    // Code modules are the core modules with a `code` section, in the order they appear
+    let pairs = originals
+        .iter()
+        .zip(&instrumented_modules)
+        .filter(|(original, _)| original.code_offset.is_some());
+    let code_modules = find_code_modules(instrumented)?;
+    for ((table, (original, instrumented)), code_module) in
+        data.line_tables.iter_mut().zip(pairs).zip(&code_modules)
+    {
+        let code_len = code_module
+            .funcs
+            .last()
+            .map_or(0, |func| func.range.end as usize);
+        table.code_range = code_module.code_offset..code_module.code_offset + code_len;
+        table.probe_offsets = AddressMap::new(original, instrumented, noise_level).inverse;
+    }
+    Ok(())
+}
+
+/// Remove the DWARF sections from every core module in a binary
+pub fn strip_dwarf(binary: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    map_core_modules(binary, &mut |module| {
+        replace_dwarf_sections(module, Vec::new())
+    })
+}
+
+/// Rewrite the DWARF in `sections` so its addresses point to the instrumented code
+/// DIEs are converted by gimli, but line programs, range lists and location lists are rebuilt, since gimli only moves the addresses they start from
+fn convert_dwarf(
+    sections: &HashMap<&str, &[u8]>,
+    map: &AddressMap,
+) -> Result<CustomSections, Box<dyn Error>> {
+    let dwarf_sections = gimli::DwarfSections::load(|id| {
+        Ok::<_, gimli::Error>(sections.get(id.name()).copied().unwrap_or_default())
+    })?;
+    let dwarf = dwarf_sections.borrow(|section| EndianSlice::new(section, LittleEndian));
+    let convert = |address| Some(Address::Constant(map.convert(address)));
+    let mut output = write::Dwarf::from(&dwarf, &convert)?;
+
+    let mut units = dwarf.units();
+    let mut unit_idx = 0;
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let unit_id = output.units.id(unit_idx);
+        unit_idx += 1;
+        let output_unit = output.units.get_mut(unit_id);
+        if let Some(program) = unit.line_program.clone() {
+            output_unit.line_program = convert_line_program(program, &dwarf, &unit, map)?;
+        }
+        let mut tree = unit.entries_tree(None)?;
+        let root = output_unit.root();
+        convert_lists(tree.root()?, root, output_unit, &dwarf, &unit, map)?;
+        fix_high_pcs(output_unit, map);
+    }
+
+    let mut written = write::Sections::new(write::EndianVec::new(LittleEndian));
+    output.write(&mut written)?;
+    let mut converted = Vec::new();
+    written.for_each(|id, data| {
+        if !data.slice().is_empty() {
+            converted.push((id.name().to_string(), data.slice().to_vec()));
+        }
+        Ok::<_, gimli::Error>(())
+    })?;
+    Ok(converted)
+}
+
+/// Rebuild a line program with every row at its instrumented address
+/// Directories and files are added in the same order gimli's own conversion adds them, so the file ids in the converted DIEs still match
+fn convert_line_program(
+    program: gimli::IncompleteLineProgram<EndianSlice<LittleEndian>>,
+    dwarf: &ReadDwarf,
+    unit: &gimli::Unit<EndianSlice<LittleEndian>>,
+    map: &AddressMap,
+) -> Result<LineProgram, Box<dyn Error>> {
+    let line_string = |value| -> Result<LineString, gimli::Error> {
+        Ok(LineString::String(
+            dwarf.attr_string(unit, value)?.to_slice()?.to_vec(),
+        ))
+    };
+    let header = program.header();
+    let encoding = header.encoding();
+    let comp_dir = match header.directory(0) {
+        Some(dir) => line_string(dir)?,
+        None => LineString::String(Vec::new()),
+    };
+    let comp_name = match header.file(0) {
+        Some(file) => line_string(file.path_name())?,
+        None => LineString::String(Vec::new()),
+    };
+    let mut output = LineProgram::new(encoding, header.line_encoding(), comp_dir, comp_name, None);
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    if header.version() <= 4 {
+        dirs.push(output.default_directory());
+        files.push(None);
+    }
+    for dir in header.include_directories() {
+        dirs.push(output.add_directory(line_string(*dir)?));
+    }
+    output.file_has_timestamp = header.file_has_timestamp();
+    output.file_has_size = header.file_has_size();
+    output.file_has_md5 = header.file_has_md5();
+    for file in header.file_names() {
+        let dir = *dirs
+            .get(file.directory_index() as usize)
+            .ok_or("Invalid directory index in line program")?;
+        let info = FileInfo {
+            timestamp: file.timestamp(),
+            size: file.size(),
+            md5: *file.md5(),
+            source: None,
+        };
+        files.push(Some(output.add_file(
+            line_string(file.path_name())?,
+            dir,
+            Some(info),
+        )));
+    }
+
+    let mut rows = program.rows();
+    let mut sequence_start = None;
+    let mut last_offset = 0;
+    while let Some((_, row)) = rows.next_row()? {
+        let address = map.convert(row.address());
+        let start = *sequence_start.get_or_insert_with(|| {
+            output.begin_sequence(Some(Address::Constant(address)));
+            last_offset = 0;
+            address
+        });
+        // Rows can't go backwards within a sequence, even if their addresses were tombstones that weren't moved
+        let offset = address.saturating_sub(start).max(last_offset);
+        last_offset = offset;
+        if row.end_sequence() {
+            output.end_sequence(offset);
+            sequence_start = None;
+            continue;
+        }
+        let file = files
+            .get(row.file_index() as usize)
+            .copied()
+            .flatten()
+            .ok_or("Invalid file index in line program")?;
+        let output_row = output.row();
+        output_row.address_offset = offset;
+        output_row.op_index = row.op_index();
+        output_row.file = file;
+        output_row.line = row.line().map_or(0, |line| line.get());
+        output_row.column = match row.column() {
+            gimli::ColumnType::LeftEdge => 0,
+            gimli::ColumnType::Column(column) => column.get(),
+        };
+        output_row.discriminator = row.discriminator();
+        output_row.is_statement = row.is_stmt();
+        output_row.basic_block = row.basic_block();
+        output_row.prologue_end = row.prologue_end();
+        output_row.epilogue_begin = row.epilogue_begin();
+        output_row.isa = row.isa();
+        output.generate_row();
+    }
+    Ok(output)
+}
+
+/// Rebuild the range and location lists of an entry and its children from the absolute addresses they cover
+/// gimli converts the offsets in DWARF 4 lists as if they were addresses, which only works if the unit's base address is 0
+/// So each list starts over from a base address of 0, and its ranges are offsets from that
+fn convert_lists(
+    node: gimli::EntriesTreeNode<EndianSlice<LittleEndian>>,
+    id: write::UnitEntryId,
+    output_unit: &mut write::Unit,
+    dwarf: &ReadDwarf,
+    unit: &gimli::Unit<EndianSlice<LittleEndian>>,
+    map: &AddressMap,
+) -> Result<(), Box<dyn Error>> {
+    let convert = |address| Some(Address::Constant(map.convert(address)));
+    let mut attrs = node.entry().attrs();
+    while let Some(attr) = attrs.next()? {
+        match output_unit.get(id).get(attr.name()) {
+            Some(AttributeValue::RangeListRef(_)) => {
+                let Some(mut ranges) = dwarf.attr_ranges(unit, attr.value())? else {
+                    continue;
+                };
+                let mut list = vec![write::Range::BaseAddress {
+                    address: Address::Constant(0),
+                }];
+                while let Some(range) = ranges.next()? {
+                    let (begin, end) = (map.convert(range.begin), map.convert(range.end));
+                    if begin < end {
+                        list.push(write::Range::OffsetPair { begin, end });
+                    }
+                }
+                let list_id = output_unit.ranges.add(write::RangeList(list));
+                output_unit
+                    .get_mut(id)
+                    .set(attr.name(), AttributeValue::RangeListRef(list_id));
+            }
+            Some(AttributeValue::LocationListRef(_)) => {
+                let Some(mut locations) = dwarf.attr_locations(unit, attr.value())? else {
+                    continue;
+                };
+                let mut list = vec![write::Location::BaseAddress {
+                    address: Address::Constant(0),
+                }];
+                while let Some(location) = locations.next()? {
+                    let (begin, end) = (
+                        map.convert(location.range.begin),
+                        map.convert(location.range.end),
+                    );
+                    if begin < end {
+                        let data = write::Expression::from(
+                            location.data,
+                            unit.encoding(),
+                            Some(dwarf),
+                            Some(unit),
+                            None,
+                            &convert,
+                        )?;
+                        list.push(write::Location::OffsetPair { begin, end, data });
+                    }
+                }
+                let list_id = output_unit.locations.add(write::LocationList(list));
+                output_unit
+                    .get_mut(id)
+                    .set(attr.name(), AttributeValue::LocationListRef(list_id));
+            }
+            _ => {}
+        }
+    }
+    // gimli converts every entry, so the children of both trees line up
+    let mut output_children = output_unit
+        .get(id)
+        .children()
+        .copied()
+        .collect::<Vec<_>>()
+        .into_iter();
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let output_child = output_children
+            .next()
+            .ok_or("Converted DWARF has fewer entries than the original")?;
+        convert_lists(child, output_child, output_unit, dwarf, unit, map)?;
+    }
+    Ok(())
+}
+
+/// Resize every `DW_AT_high_pc` that's an offset from `DW_AT_low_pc`, since gimli only moves addresses
+fn fix_high_pcs(unit: &mut write::Unit, map: &AddressMap) {
+    let mut entries = vec![unit.root()];
+    while let Some(id) = entries.pop() {
+        let entry = unit.get_mut(id);
+        entries.extend(entry.children().copied());
+        let Some(AttributeValue::Address(Address::Constant(low_pc))) =
+            entry.get(gimli::DW_AT_low_pc).cloned()
+        else {
+            continue;
+        };
+        let Some(original_low_pc) = map.original(low_pc) else {
+            continue;
+        };
+        let resize = |length: u64| map.convert(original_low_pc + length).saturating_sub(low_pc);
+        let high_pc = match entry.get(gimli::DW_AT_high_pc) {
+            Some(AttributeValue::Udata(length)) => AttributeValue::Udata(resize(*length)),
+            Some(AttributeValue::Data1(length)) => AttributeValue::Udata(resize(*length as u64)),
+            Some(AttributeValue::Data2(length)) => AttributeValue::Udata(resize(*length as u64)),
+            Some(AttributeValue::Data4(length)) => AttributeValue::Udata(resize(*length as u64)),
+            Some(AttributeValue::Data8(length)) => AttributeValue::Udata(resize(*length)),
+            _ => continue,
+        };
+        entry.set(gimli::DW_AT_high_pc, high_pc);
+    }
+}
+
+/// Replace the DWARF sections of a core module with `sections`, which go where the first of the old ones was
+/// Tools like `llvm-dwarfdump` expect DWARF to come before the `name` and `producers` sections
+fn replace_dwarf_sections(
+    module: &[u8],
+    sections: CustomSections,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (header, module_sections) = split_sections(module)?;
+    let mut output = header.to_vec();
+    let mut sections = Some(sections);
+    for (id, contents) in module_sections {
+        if id == CUSTOM_SECTION && custom_section_name(contents)?.starts_with(DWARF_SECTION_PREFIX)
+        {
+            if let Some(sections) = sections.take() {
+                write_custom_sections(&mut output, sections);
+            }
+            continue;
+        }
+        write_section(&mut output, id, contents);
+    }
+    if let Some(sections) = sections {
+        write_custom_sections(&mut output, sections);
+    }
+    Ok(output)
+}
+
+/// Write each of `sections` as a custom section with its name
+fn write_custom_sections(output: &mut Vec<u8>, sections: CustomSections) {
+    for (name, data) in sections {
+        let mut contents = Vec::new();
+        write_leb(&mut contents, name.len() as u64);
+        contents.extend(name.as_bytes());
+        contents.extend(data);
+        write_section(output, CUSTOM_SECTION, &contents);
+    }
+}
+
+/// Apply `f` to every core module in a binary, including the ones in nested components, in the order they appear
+/// The binary is rebuilt around the modules `f` returns
+fn map_core_modules<'a>(
+    binary: &'a [u8],
+    f: &mut dyn FnMut(&'a [u8]) -> Result<Vec<u8>, Box<dyn Error>>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !Parser::is_component(binary) {
+        return f(binary);
+    }
+    let (header, sections) = split_sections(binary)?;
+    let mut output = header.to_vec();
+    for (id, contents) in sections {
+        match id {
+            CORE_MODULE_SECTION => write_section(&mut output, id, &f(contents)?),
+            COMPONENT_SECTION => write_section(&mut output, id, &map_core_modules(contents, f)?),
+            _ => write_section(&mut output, id, contents),
+        }
+    }
+    Ok(output)
+}
+
+/// Get every core module in a binary, including the ones in nested components, in the order they appear
+fn core_modules(binary: &[u8]) -> Result<Vec<&[u8]>, Box<dyn Error>> {
+    let mut modules = Vec::new();
+    map_core_modules(binary, &mut |module| {
+        modules.push(module);
+        Ok(module.to_vec())
+    })?;
+    Ok(modules)
+}
+
+/// Split a module or component into its 8 byte header and its sections, as ids and contents
+fn split_sections(binary: &[u8]) -> Result<(&[u8], Sections<'_>), Box<dyn Error>> {
+    let header = binary.get(..8).ok_or("Binary is too short to be Wasm")?;
+    let mut sections = Vec::new();
+    let mut pos = 8;
+    while pos < binary.len() {
+        let id = binary[pos];
+        pos += 1;
+        let (size, read) = read_leb(&binary[pos..]).ok_or("Malformed section size")?;
+        pos += read;
+        let contents = binary
+            .get(pos..pos + size as usize)
+            .ok_or("Section runs past the end of the binary")?;
+        sections.push((id, contents));
+        pos += size as usize;
+    }
+    Ok((header, sections))
+}
+
+fn custom_section_name(contents: &[u8]) -> Result<&str, Box<dyn Error>> {
+    let (len, read) = read_leb(contents).ok_or("Malformed custom section name")?;
+    let name = contents
+        .get(read..read + len as usize)
+        .ok_or("Custom section name runs past the end of the section")?;
+    Ok(std::str::from_utf8(name)?)
+}
+
+fn write_section(output: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    output.push(id);
+    write_leb(output, contents.len() as u64);
+    output.extend(contents);
+}
+
+/// Read an unsigned LEB128 value, returning it along with the number of bytes it took up
+fn read_leb(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (idx, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Some((value, idx + 1));
+        }
+    }
+    None
+}
+
+fn write_leb(output: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wast::parser::{parse, ParseBuffer};
+    use wast::Wat;
+
+    use super::*;
+
+    fn encode(text: &str) -> Vec<u8> {
+        let buf = ParseBuffer::new(text).unwrap();
+        parse::<Wat>(&buf).unwrap().encode().unwrap()
+    }
+
+    #[test]
+    fn converts_between_known_addresses() {
+        let mut map = AddressMap {
+            code_end: 100,
+            ..Default::default()
+        };
+        map.insert(10, 10);
+        map.insert(20, 40);
+        map.insert(30, 45);
+        assert_eq!(map.convert(5), 5);
+        assert_eq!(map.convert(20), 40);
+        // Addresses keep their distance from the one before them
+        assert_eq!(map.convert(22), 42);
+        // But can't pass the next known address
+        assert_eq!(map.convert(29), 45);
+        // Tombstones past the end of the code are left alone
+        assert_eq!(map.convert(101), 101);
+        assert_eq!(map.original(40), Some(20));
+        assert_eq!(map.original(41), None);
+    }
+
+    #[test]
+    fn maps_instructions_to_their_probes() {
+        let original = encode("(module (global (mut i32) (i32.const 0)) (func nop nop))");
+        let instrumented = encode(
+            "(module
+                (global (mut i32) (i32.const 0))
+                (global (mut i32) (i32.const 0))
+                (func nop global.get 1 i32.const 1 i32.add global.set 1 nop)
+            )",
+        );
+        let original = ModuleCode::parse(&original).unwrap();
+        let instrumented = ModuleCode::parse(&instrumented).unwrap();
+        assert_eq!(
+            instrumented.probe_len(&instrumented.bodies[0].1, 1, 1),
+            Some(4)
+        );
+        let map = AddressMap::new(&original, &instrumented, NoiseLevel::Quiet);
+        // Relative to the code section, the body starts after the function count and its size
+        let [first_nop, second_nop, end] = [3, 4, 5];
+        assert_eq!(map.convert(first_nop), 3);
+        // The probe counts as part of the instruction it's in front of
+        assert_eq!(map.convert(second_nop), 4);
+        assert_eq!(map.convert(end), 12);
+        assert_eq!(map.original(11), Some(second_nop));
+        assert_eq!(map.original(4), Some(second_nop));
+        assert_eq!(map.convert(map.code_end), 13);
+    }
+
+    #[test]
+    fn moves_only_bounds_of_unmatched_functions() {
+        let original = encode("(module (func nop nop))");
+        let instrumented = encode("(module (func nop unreachable nop))");
+        let original = ModuleCode::parse(&original).unwrap();
+        let instrumented = ModuleCode::parse(&instrumented).unwrap();
+        let map = AddressMap::new(&original, &instrumented, NoiseLevel::Quiet);
+        assert_eq!(map.original(4), None);
+        assert_eq!(map.convert(map.code_end), 7);
+    }
+}
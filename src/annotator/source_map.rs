@@ -0,0 +1,534 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use wast::core::{Custom, ModuleField};
+use wast::Wat;
+use wast::{parser, Error as WatError};
+
+use crate::annotator::debug::{
+    add_module_functions, CodeModule, DebugFuncInfo, DebugLineInfo, WatLineMapper,
+};
+use crate::annotator::utils::*;
+use crate::noise::NoiseLevel;
+use crate::printer::{println_annotate_dbg, println_annotate_error};
+
+/// The custom section a module uses to point to its source map
+const SOURCE_MAPPING_URL_SECTION: &str = "sourceMappingURL";
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawSourceMap {
+    version: u32,
+    #[serde(default)]
+    source_root: Option<String>,
+    sources: Vec<Option<String>>,
+    #[serde(default)]
+    names: Vec<String>,
+    mappings: String,
+}
+
+/// A mapping from an instruction in a Wasm module to the source location it was compiled from
+pub struct Mapping {
+    /// The offset of the instruction from the start of the module's binary
+    pub offset: u64,
+    /// The index of the source file in the map's sources
+    pub source: usize,
+    /// The source line, starting from 1
+    pub line: u64,
+    /// The source column, starting from 1
+    pub column: u64,
+    /// The index of the name of the mapped item in the map's names, if it has one
+    pub name: Option<usize>,
+}
+
+/// A v3 source map for a Wasm module
+pub struct SourceMap {
+    /// The paths of the source files
+    pub sources: Vec<PathBuf>,
+    /// The names that mappings can refer to
+    pub names: Vec<String>,
+    /// The mappings, sorted by offset
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Parse a v3 source map, where relative source paths are resolved against `base_dir`
+    pub fn parse(json: &str, base_dir: &Path) -> Result<SourceMap, Box<dyn Error>> {
+        let raw: RawSourceMap = serde_json::from_str(json)?;
+        if raw.version != 3 {
+            return Err(format!("Unsupported source map version {}", raw.version).into());
+        }
+        let root = base_dir.join(raw.source_root.unwrap_or_default());
+        let sources = raw
+            .sources
+            .into_iter()
+            .map(|source| root.join(source.unwrap_or_default()))
+            .collect::<Vec<_>>();
+
+        // Wasm modules are a single generated line, where the generated column is the offset into the binary
+        // Every field but the generated column is relative to the last segment that had it, across the whole map
+        let mut mappings = Vec::new();
+        let (mut source, mut line, mut column, mut name) = (0i64, 0i64, 0i64, 0i64);
+        for (generated_line, segments) in raw.mappings.split(';').enumerate() {
+            let mut offset = 0i64;
+            for segment in segments.split(',').filter(|segment| !segment.is_empty()) {
+                let fields = decode_vlq(segment)
+                    .ok_or(format!("Malformed source map segment `{}`", segment))?;
+                let out_of_range = || format!("Source map segment `{}` is out of range", segment);
+                offset = offset.checked_add(fields[0]).ok_or_else(out_of_range)?;
+                // Segments without a source don't map to anything
+                if fields.len() < 4 {
+                    continue;
+                }
+                source = source.checked_add(fields[1]).ok_or_else(out_of_range)?;
+                line = line.checked_add(fields[2]).ok_or_else(out_of_range)?;
+                column = column.checked_add(fields[3]).ok_or_else(out_of_range)?;
+                let name_idx = match fields.get(4) {
+                    Some(delta) => {
+                        name = name.checked_add(*delta).ok_or_else(out_of_range)?;
+                        Some(usize::try_from(name).map_err(|_| out_of_range())?)
+                    }
+                    None => None,
+                };
+                if generated_line == 0 {
+                    let to_u64 = |value: i64| u64::try_from(value).map_err(|_| out_of_range());
+                    mappings.push(Mapping {
+                        offset: to_u64(offset)?,
+                        source: usize::try_from(source).map_err(|_| out_of_range())?,
+                        line: to_u64(line)? + 1,
+                        column: to_u64(column)? + 1,
+                        name: name_idx,
+                    });
+                }
+            }
+        }
+        mappings.sort_by_key(|mapping| mapping.offset);
+
+        Ok(SourceMap {
+            sources,
+            names: raw.names,
+            mappings,
+        })
+    }
+
+    /// Load the source map a `sourceMappingURL` points to
+    /// Data URLs hold the map itself, and anything else is read as a path, relative to `base_dir`
+    pub fn load(url: &str, base_dir: &Path) -> Result<SourceMap, Box<dyn Error>> {
+        if let Some(data) = url.strip_prefix("data:") {
+            let (header, payload) = data.split_once(',').ok_or("Malformed data URL")?;
+            let json = if header.ends_with(";base64") {
+                String::from_utf8(decode_base64(payload).ok_or("Malformed base64 in data URL")?)?
+            } else {
+                payload.to_string()
+            };
+            return SourceMap::parse(&json, base_dir);
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Err(format!(
+                "Source map `{}` is remote, so it has to be downloaded and passed with `--source-map`",
+                url
+            )
+            .into());
+        }
+        let path = base_dir.join(url.strip_prefix("file://").unwrap_or(url));
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read source map {}: {}", path.display(), e))?;
+        SourceMap::parse(&json, path.parent().unwrap_or(base_dir))
+    }
+}
+
+/// Fill in a mapper struct with source map information for every code module that has no DWARF line info
+/// A module's source map is found through its `sourceMappingURL` section, but `source_map` is used instead for the module picked by `pick_source_map_module`
+/// Relative URLs are resolved against `base_dir`
+pub fn read_source_maps(
+    wat: &Wat,
+    code_modules: &[CodeModule],
+    map: &mut WatLineMapper,
+    source_map: Option<&Path>,
+    source_map_module: Option<usize>,
+    base_dir: &Path,
+    noise_level: NoiseLevel,
+) -> parser::Result<()> {
+    let modules = get_inline_modules(wat).ok_or(WatError::new(
+        wat.span(),
+        "Input WAT file could not be parsed (may be binary or module)".to_string(),
+    ))?;
+    // Modules that already have DWARF line info don't need a source map, and modules without code can't use one
+    let without_line_info = (0..modules.len())
+        .filter(|idx| {
+            map.code_module_idx(*idx).is_some_and(|code_module_idx| {
+                map.module(code_module_idx)
+                    .is_none_or(|module| module.rows_in(..).next().is_none())
+            })
+        })
+        .collect::<Vec<_>>();
+    let source_map = match source_map {
+        Some(path) => {
+            let urls = modules
+                .iter()
+                .map(|(_, fields)| source_mapping_url(fields))
+                .collect::<Vec<_>>();
+            let module_idx =
+                pick_source_map_module(path, source_map_module, &without_line_info, &urls)
+                    .map_err(|e| WatError::new(wat.span(), e))?;
+            if noise_level.debug() {
+                println_annotate_dbg(format!(
+                    "Using source map {} for core module {}",
+                    path.display(),
+                    module_idx
+                ));
+            }
+            Some((path, module_idx))
+        }
+        None => None,
+    };
+
+    for (inline_module_idx, (_, fields)) in modules.into_iter().enumerate() {
+        if !without_line_info.contains(&inline_module_idx) {
+            continue;
+        }
+        let Some(code_module_idx) = map.code_module_idx(inline_module_idx) else {
+            continue;
+        };
+        let Some(code_module) = code_modules.get(code_module_idx) else {
+            continue;
+        };
+        let loaded = match (source_map, source_mapping_url(fields)) {
+            (Some((path, module_idx)), _) if module_idx == inline_module_idx => {
+                fs::read_to_string(path)
+                    .map_err(|e| {
+                        format!("Couldn't read source map {}: {}", path.display(), e).into()
+                    })
+                    .and_then(|json| {
+                        SourceMap::parse(&json, path.parent().unwrap_or(Path::new("")))
+                    })
+            }
+            (_, Some(url)) => SourceMap::load(&url, base_dir),
+            (_, None) => continue,
+        };
+        match loaded {
+            Ok(source_map) => {
+                add_source_map(&source_map, code_module, code_module_idx, map, noise_level)
+            }
+            Err(e) => {
+                if noise_level.err() {
+                    println_annotate_error(format!(
+                        "Error: Couldn't use the source map of core module {}: {}",
+                        inline_module_idx, e
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pick the core module a source map passed by the user belongs to, out of the modules without line info
+/// The module the user asked for wins, then the one whose `sourceMappingURL` names the map's file, then the only module without line info
+/// Components often hold adapter or shim modules next to the code that was compiled, so any other case is an error rather than a guess
+fn pick_source_map_module(
+    path: &Path,
+    requested: Option<usize>,
+    without_line_info: &[usize],
+    urls: &[Option<String>],
+) -> Result<usize, String> {
+    if let Some(module_idx) = requested {
+        if module_idx >= urls.len() {
+            return Err(format!(
+                "Core module {} doesn't exist, there are only {} core modules",
+                module_idx,
+                urls.len()
+            ));
+        }
+        if !without_line_info.contains(&module_idx) {
+            return Err(format!(
+                "Core module {} already has DWARF line info, so source map {} can't be used for it",
+                module_idx,
+                path.display()
+            ));
+        }
+        return Ok(module_idx);
+    }
+    let file_name = path.file_name();
+    let named = without_line_info
+        .iter()
+        .copied()
+        .filter(|idx| {
+            urls[*idx]
+                .as_deref()
+                .is_some_and(|url| file_name.is_some() && Path::new(url).file_name() == file_name)
+        })
+        .collect::<Vec<_>>();
+    match (named.as_slice(), without_line_info) {
+        ([module_idx], _) | ([], [module_idx]) => Ok(*module_idx),
+        ([], []) => Err(format!(
+            "Every core module already has DWARF line info, so source map {} isn't needed",
+            path.display()
+        )),
+        _ => Err(format!(
+            "Can't tell which of core modules {:?} source map {} belongs to, pass `--source-map-module` to pick one",
+            if named.is_empty() { without_line_info } else { &named },
+            path.display()
+        )),
+    }
+}
+
+/// Add the line rows of a source map to a code module, along with its functions
+/// Functions are named by the `name` section, or by the mapping at their first instruction if it has a name
+fn add_source_map(
+    source_map: &SourceMap,
+    code_module: &CodeModule,
+    code_module_idx: usize,
+    map: &mut WatLineMapper,
+    noise_level: NoiseLevel,
+) {
+    let path_idxs = source_map
+        .sources
+        .iter()
+        .map(|source| map.add_file(source.clone()))
+        .collect::<Vec<_>>();
+    // Mappings are relative to the start of the module, and line rows to the start of its code section
+    let code_start = (code_module.code_offset - code_module.module_offset) as u64;
+    let mut rows = Vec::new();
+    for mapping in &source_map.mappings {
+        let (Some(address), Some(path_idx)) = (
+            mapping.offset.checked_sub(code_start),
+            path_idxs.get(mapping.source),
+        ) else {
+            continue;
+        };
+        let info = DebugLineInfo {
+            address,
+            path_idx: *path_idx,
+            line: mapping.line,
+            column: mapping.column,
+            discriminator: 0,
+            // Source maps only record statements
+            is_stmt: true,
+            prologue_end: false,
+            epilogue_begin: false,
+            code_module_idx,
+        };
+        if map.add_line(info) {
+            rows.push((address, *path_idx, mapping.name));
+        }
+    }
+    if noise_level.debug() {
+        println_annotate_dbg(format!(
+            "Read {} line rows from the source map of core module {}",
+            rows.len(),
+            code_module_idx
+        ));
+    }
+
+    for func in &code_module.funcs {
+        let first = rows.partition_point(|(address, ..)| *address < func.range.start);
+        let Some((_, path_idx, name)) = rows
+            .get(first)
+            .filter(|(address, ..)| func.range.contains(address))
+        else {
+            continue;
+        };
+        let name = func
+            .name
+            .clone()
+            .or_else(|| name.and_then(|name| source_map.names.get(name).cloned()))
+            .unwrap_or_else(|| format!("wasm-function[{}]", func.func_idx));
+        map.add_function(
+            code_module_idx,
+            DebugFuncInfo {
+                low_pc: func.range.start,
+                high_pc: Some(func.range.end - 1),
+                path_idx: *path_idx,
+                name,
+            },
+        );
+    }
+    add_module_functions(map, code_module_idx, noise_level);
+}
+
+/// Get the URL in a module's `sourceMappingURL` section, if it has one
+/// The section holds the URL as a length-prefixed string
+fn source_mapping_url(fields: &[ModuleField]) -> Option<String> {
+    let data = fields.iter().find_map(|field| match field {
+        ModuleField::Custom(Custom::Raw(c)) if c.name == SOURCE_MAPPING_URL_SECTION => {
+            Some(c.data.concat())
+        }
+        _ => None,
+    })?;
+    let (mut len, mut shift, mut read) = (0usize, 0, 0usize);
+    for byte in &data {
+        read += 1;
+        let bits = (byte & 0x7f) as usize;
+        // Lengths too long for a usize are malformed, rather than something to wrap around
+        len |= bits.checked_shl(shift).filter(|len| len >> shift == bits)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let url = data.get(read..read.checked_add(len)?)?;
+    String::from_utf8(url.to_vec()).ok()
+}
+
+/// Decode a segment of base64 VLQ values
+fn decode_vlq(segment: &str) -> Option<Vec<i64>> {
+    let mut values = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+    for c in segment.bytes() {
+        let digit = base64_value(c)? as i64;
+        let bits = digit & 0b11111;
+        // Values too large for an i64 are malformed, so the segment is rejected rather than overflowing
+        value |= bits
+            .checked_shl(shift)
+            .filter(|value| value >> shift == bits)?;
+        if digit & 0b100000 != 0 {
+            shift += 5;
+        } else {
+            values.push(if value & 1 == 1 {
+                -(value >> 1)
+            } else {
+                value >> 1
+            });
+            (value, shift) = (0, 0);
+        }
+    }
+    (shift == 0 && !values.is_empty()).then_some(values)
+}
+
+/// Decode standard base64, with or without padding
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .trim_end_matches('=')
+        .bytes()
+        .map(base64_value)
+        .collect::<Option<Vec<_>>>()?;
+    let mut bytes = Vec::new();
+    for chunk in digits.chunks(4) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (idx, digit)| {
+            bits | (*digit as u32) << (18 - 6 * idx)
+        });
+        bytes.extend(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(bytes)
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    BASE64_ALPHABET
+        .iter()
+        .position(|digit| *digit == c)
+        .map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use wast::core::ModuleKind;
+    use wast::parser::{parse, ParseBuffer};
+
+    use super::*;
+
+    #[test]
+    fn decodes_vlq() {
+        assert_eq!(decode_vlq("AAAA"), Some(vec![0, 0, 0, 0]));
+        assert_eq!(decode_vlq("CADA"), Some(vec![1, 0, -1, 0]));
+        // A continuation bit carries five more bits into the next digit
+        assert_eq!(decode_vlq("gB"), Some(vec![16]));
+        assert_eq!(decode_vlq("hB"), Some(vec![-16]));
+        // Values that never end, or aren't base64, are malformed
+        assert_eq!(decode_vlq("g"), None);
+        assert_eq!(decode_vlq("A*"), None);
+        assert_eq!(decode_vlq(""), None);
+    }
+
+    #[test]
+    fn rejects_vlq_overflow() {
+        assert_eq!(
+            decode_vlq(&format!("{}B", "g".repeat(12))),
+            Some(vec![1 << 59])
+        );
+        assert_eq!(decode_vlq(&format!("{}B", "g".repeat(13))), None);
+        assert_eq!(decode_vlq(&"g".repeat(40)), None);
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8="), Some(b"hello".to_vec()));
+        assert_eq!(decode_base64("aGVsbG8"), Some(b"hello".to_vec()));
+        assert_eq!(decode_base64("aGk="), Some(b"hi".to_vec()));
+        assert_eq!(decode_base64(""), Some(Vec::new()));
+        assert_eq!(decode_base64("a*=="), None);
+    }
+
+    fn url_of(wat: &str) -> Option<String> {
+        let buf = ParseBuffer::new(wat).unwrap();
+        let Wat::Module(module) = parse::<Wat>(&buf).unwrap() else {
+            panic!("not a module");
+        };
+        let ModuleKind::Text(fields) = &module.kind else {
+            panic!("not a text module");
+        };
+        source_mapping_url(fields)
+    }
+
+    #[test]
+    fn reads_source_mapping_url() {
+        assert_eq!(
+            url_of(r#"(module (@custom "sourceMappingURL" "\08fizz.map"))"#),
+            Some("fizz.map".to_string())
+        );
+        assert_eq!(url_of("(module)"), None);
+        // Lengths past the end of the section, or too long for a usize, are malformed
+        assert_eq!(
+            url_of(r#"(module (@custom "sourceMappingURL" "\09fizz.map"))"#),
+            None
+        );
+        assert_eq!(
+            url_of(r#"(module (@custom "sourceMappingURL" "\ff\ff\ff\ff\ff\ff\ff\ff\ff\ff\01"))"#),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_mappings() {
+        let json =
+            r#"{"version": 3, "sources": ["a.c", "b.c"], "names": [], "mappings": "gBAAE,ECAA"}"#;
+        let map = SourceMap::parse(json, Path::new("/src")).unwrap();
+        assert_eq!(
+            map.sources,
+            [PathBuf::from("/src/a.c"), PathBuf::from("/src/b.c")]
+        );
+        let rows = map
+            .mappings
+            .iter()
+            .map(|m| (m.offset, m.source, m.line, m.column))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [(16, 0, 1, 3), (18, 1, 1, 3)]);
+        // Deltas that take a field below zero are rejected instead of wrapping around
+        let json = r#"{"version": 3, "sources": ["a.c"], "mappings": "AAAA,ADAA"}"#;
+        assert!(SourceMap::parse(json, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn picks_source_map_module() {
+        let path = Path::new("out/app.wasm.map");
+        let urls = [
+            None,
+            Some("app.wasm.map".to_string()),
+            Some("other.map".to_string()),
+        ];
+        assert_eq!(pick_source_map_module(path, None, &[0, 1, 2], &urls), Ok(1));
+        assert_eq!(
+            pick_source_map_module(path, Some(2), &[0, 1, 2], &urls),
+            Ok(2)
+        );
+        assert_eq!(pick_source_map_module(path, None, &[2], &urls), Ok(2));
+        // An adapter without a URL next to the module it belongs to is ambiguous
+        assert!(pick_source_map_module(path, None, &[0, 2], &urls).is_err());
+        assert!(pick_source_map_module(path, Some(1), &[0, 2], &urls).is_err());
+        assert!(pick_source_map_module(path, Some(3), &[0, 1, 2], &urls).is_err());
+        assert!(pick_source_map_module(path, None, &[], &urls).is_err());
+    }
+}
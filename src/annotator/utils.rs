@@ -1,5 +1,5 @@
 use wast::{
-    component::{ComponentField, ComponentKind},
+    component::{ComponentField, ComponentKind, CoreModuleKind, NestedComponentKind},
     core::ModuleField,
     token::Span,
     Wat,
@@ -55,3 +55,83 @@ pub fn get_fields<'a, 'b: 'a>(comp: &'b Wat<'a>) -> Option<&'a Vec<ComponentFiel
         },
     }
 }
+
+/// Get every inline core module in a Wat component, including modules defined in nested components, as the span of the module and its fields
+/// Modules are listed in the same order they appear in the binary
+pub fn get_inline_modules<'a, 'b: 'a>(
+    comp: &'b Wat<'a>,
+) -> Option<Vec<(Span, &'a Vec<ModuleField<'a>>)>> {
+    fn visit<'a>(
+        fields: &'a [ComponentField<'a>],
+        modules: &mut Vec<(Span, &'a Vec<ModuleField<'a>>)>,
+    ) {
+        for field in fields {
+            match field {
+                ComponentField::CoreModule(m) => {
+                    if let CoreModuleKind::Inline { fields } = &m.kind {
+                        modules.push((m.span, fields));
+                    }
+                }
+                ComponentField::Component(c) => {
+                    if let NestedComponentKind::Inline(fields) = &c.kind {
+                        visit(fields, modules);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut modules = Vec::new();
+    visit(get_fields(comp)?, &mut modules);
+    Some(modules)
+}
+
+/// Find the parenthesis that closes the one at `open` in the text of a Wat file
+/// Parentheses inside strings and comments are skipped
+pub fn find_closing_paren(text: &str, open: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut idx = open;
+    while idx < bytes.len() {
+        match (bytes[idx], bytes.get(idx + 1)) {
+            (b'(', Some(b';')) => {
+                // Block comments can be nested
+                let mut comment_depth = 1;
+                idx += 2;
+                while comment_depth > 0 {
+                    match (bytes.get(idx)?, bytes.get(idx + 1)) {
+                        (b'(', Some(b';')) => comment_depth += 1,
+                        (b';', Some(b')')) => comment_depth -= 1,
+                        _ => {
+                            idx += 1;
+                            continue;
+                        }
+                    }
+                    idx += 2;
+                }
+                continue;
+            }
+            (b';', Some(b';')) => {
+                idx += bytes[idx..].iter().position(|b| *b == b'\n')?;
+            }
+            (b'"', _) => {
+                idx += 1;
+                while *bytes.get(idx)? != b'"' {
+                    // Skip the escaped character so an escaped quote doesn't end the string
+                    idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+                }
+            }
+            (b'(', _) => depth += 1,
+            (b')', _) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
@@ -0,0 +1,255 @@
+use core::str;
+
+use wasmparser::{Encoding, Parser, Payload, Validator, WasmFeatures};
+use wasmprinter::{Config, PrintFmtWrite};
+use wast::core::{Func, FuncKind, ModuleField};
+use wast::parser::{parse, ParseBuffer};
+use wast::token::Span;
+use wast::{parser, Error, Wat};
+
+use crate::annotator::offset_tracker::OffsetTracker;
+use crate::annotator::utils::*;
+use crate::noise::NoiseLevel;
+use crate::printer::println_annotate_error;
+
+/// Where a validation error is in the binary encoding of the instrumented component
+enum BinaryLocation {
+    /// An instruction in a function body, or its locals if `instr` is `None`
+    Instr {
+        module_idx: usize,
+        func_idx: usize,
+        instr: Option<usize>,
+    },
+    /// A core module, outside of any function body
+    Module { module_idx: usize },
+    /// A component section
+    Component,
+}
+
+/// Encode instrumented WAT and check that it's valid, with every component model feature enabled
+/// If it isn't, the offending item is reported along with where it came from in the original WAT, and which annotator passes changed it
+/// Returns the encoded binary if it's valid, so it doesn't have to be encoded again
+pub fn validate_output(
+    output: &str,
+    original: &str,
+    tracker: &OffsetTracker,
+    noise_level: NoiseLevel,
+) -> parser::Result<Vec<u8>> {
+    let binary = match parse::<Wat>(&ParseBuffer::new(output)?).and_then(|mut wat| wat.encode()) {
+        Ok(binary) => binary,
+        Err(e) => {
+            // Encoding errors point into the instrumented WAT, which is only useful once they're mapped back
+            let (loc, pass) = tracker.get_original_loc(e.span().offset());
+            let mut err = Error::new(
+                Span::from_offset(loc),
+                format!(
+                    "Instrumented output couldn't be encoded: {}{}",
+                    e.message(),
+                    describe_pass(pass)
+                ),
+            );
+            err.set_text(original);
+            return Err(err);
+        }
+    };
+
+    let features = WasmFeatures::default() | WasmFeatures::COMPONENT_MODEL;
+    let Err(e) = Validator::new_with_features(features).validate_all(&binary) else {
+        return Ok(binary);
+    };
+
+    let summary = format!(
+        "Instrumented output is invalid: {} (at binary offset 0x{:x})",
+        e.message(),
+        e.offset()
+    );
+    let mut explanation = summary.clone();
+    if let Some(item) = printed_item(&binary, e.offset()) {
+        explanation += &format!("\n\tOffending item: {}", item);
+    }
+    let mut buf = ParseBuffer::new(output)?;
+    let wat = parse::<Wat>(buf.track_instr_spans(true))?;
+    let modules = get_inline_modules(&wat).unwrap_or_default();
+    // The item, the offset it's at, and the offsets its definition starts and ends at, all in the instrumented WAT
+    let loc = match locate(&binary, e.offset()) {
+        Some(BinaryLocation::Instr {
+            module_idx,
+            func_idx,
+            instr,
+        }) => modules
+            .get(module_idx)
+            .and_then(|(_, fields)| defined_funcs(fields).nth(func_idx))
+            .and_then(|func| {
+                let FuncKind::Inline { expression, .. } = &func.kind else {
+                    return None;
+                };
+                let spans = expression.instr_spans.as_ref()?;
+                let name = match func.id {
+                    Some(id) => format!("function ${}", id.name()),
+                    None => format!("function {}", func_idx),
+                };
+                let (what, item) = match instr
+                    .and_then(|instr| Some((instr, spans.get(instr).or(spans.last())?)))
+                {
+                    Some((instr, span)) => {
+                        (format!("instruction {} of {}", instr, name), span.offset())
+                    }
+                    None => (format!("the locals of {}", name), func.span.offset()),
+                };
+                let end = spans
+                    .last()
+                    .map_or(func.span.offset(), |span| span.offset());
+                Some((what, item, func.span.offset(), end))
+            }),
+        Some(BinaryLocation::Module { module_idx }) => {
+            modules.get(module_idx).and_then(|(span, _)| {
+                let end = find_closing_paren(output, span.offset() - 1)?;
+                Some((
+                    format!("core module {}", module_idx),
+                    span.offset(),
+                    span.offset(),
+                    end,
+                ))
+            })
+        }
+        Some(BinaryLocation::Component) | None => None,
+    };
+
+    let mut span = Span::from_offset(0);
+    match loc {
+        Some((what, item, start, end)) => {
+            let (original_item, pass) = tracker.get_original_loc(item);
+            span = Span::from_offset(original_item);
+            let (line, col) = span.linecol_in(original);
+            explanation += &format!(
+                "\n\tIn {}, at line {} column {} of the original WAT{}",
+                what,
+                line + 1,
+                col + 1,
+                describe_pass(pass)
+            );
+            let (start, _) = tracker.get_original_loc(start);
+            let (end, _) = tracker.get_original_loc(end);
+            let passes = tracker.passes_between(start, end);
+            if !passes.is_empty() {
+                explanation += &format!(
+                    "\n\tThe {} was changed by these passes: {}",
+                    what,
+                    passes.join(", ")
+                );
+            }
+        }
+        None => {
+            explanation += "\n\tThe item is in a component section, which can't be mapped back to the original WAT";
+        }
+    }
+    if noise_level.err() {
+        println_annotate_error(&explanation);
+    }
+
+    let mut err = Error::new(span, summary);
+    err.set_text(original);
+    Err(err)
+}
+
+/// Describe the pass that inserted text, if there was one
+fn describe_pass(pass: Option<&str>) -> String {
+    match pass {
+        Some(pass) => format!(", in text inserted by the {} pass", pass),
+        None => String::new(),
+    }
+}
+
+/// Find the function body, core module, or component section that a binary offset is in
+/// Core modules are counted in the order they appear, including modules in nested components
+fn locate(binary: &[u8], offset: usize) -> Option<BinaryLocation> {
+    let mut module_count = 0;
+    // The module and the number of function bodies seen in it, for each module or component being parsed
+    let mut stack: Vec<Option<(usize, usize)>> = Vec::new();
+    for payload in Parser::new(0).parse_all(binary) {
+        match payload.ok()? {
+            Payload::Version {
+                encoding, range, ..
+            } => {
+                if encoding == Encoding::Module {
+                    stack.push(Some((module_count, 0)));
+                    module_count += 1;
+                } else {
+                    stack.push(None);
+                }
+                if range.start > offset {
+                    break;
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let (module_idx, func_idx) = stack.last_mut()?.as_mut()?;
+                *func_idx += 1;
+                if body.range().contains(&offset) {
+                    let mut instr = None;
+                    for (idx, op) in body
+                        .get_operators_reader()
+                        .ok()?
+                        .into_iter_with_offsets()
+                        .enumerate()
+                    {
+                        if op.ok()?.1 > offset {
+                            break;
+                        }
+                        instr = Some(idx);
+                    }
+                    return Some(BinaryLocation::Instr {
+                        module_idx: *module_idx,
+                        func_idx: *func_idx - 1,
+                        instr,
+                    });
+                }
+            }
+            Payload::End(end) => {
+                let finished = stack.pop()?;
+                if end >= offset {
+                    return Some(match finished {
+                        Some((module_idx, _)) => BinaryLocation::Module { module_idx },
+                        None => BinaryLocation::Component,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Print the binary with offsets, and find the printed item that a binary offset is in
+fn printed_item(binary: &[u8], offset: usize) -> Option<String> {
+    let mut printed = PrintFmtWrite(String::new());
+    let mut config = Config::new();
+    config.print_offsets(true);
+    config.print(binary, &mut printed).ok()?;
+    printed
+        .0
+        .lines()
+        .filter_map(|line| {
+            let hex = line
+                .strip_prefix("(;@")?
+                .split(|c: char| c.is_whitespace() || c == ';')
+                .next()?;
+            Some((usize::from_str_radix(hex, 16).ok()?, line))
+        })
+        // Items aren't always printed in the order they're encoded in
+        .filter(|(line_offset, _)| *line_offset <= offset)
+        .max_by_key(|(line_offset, _)| *line_offset)
+        .map(|(_, line)| {
+            line.split_once(";)")
+                .map_or(line, |(_, item)| item)
+                .trim()
+                .to_string()
+        })
+}
+
+/// Get the functions a module defines, rather than imports, in the order their bodies are encoded
+fn defined_funcs<'a>(fields: &'a [ModuleField<'a>]) -> impl Iterator<Item = &'a Func<'a>> {
+    fields.iter().filter_map(|field| match field {
+        ModuleField::Func(func) if matches!(func.kind, FuncKind::Inline { .. }) => Some(func),
+        _ => None,
+    })
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+use wast::core::{Func, FuncKind, ItemKind, ModuleField};
+use wast::parser::{self, parse, ParseBuffer};
+use wast::token::Span;
+use wast::Error;
+use wast::Wat;
+
+use crate::annotator::annotate::{printed_offset, BINARY_OFFSET_REGEX_STR};
+use crate::annotator::debug::{add_module_functions, DebugFuncInfo, DebugLineInfo, WatLineMapper};
+use crate::annotator::utils::*;
+use crate::noise::NoiseLevel;
+use crate::printer::println_annotate_dbg;
+
+/// Fill in a mapper struct with line info that points back to the hand-written WAT at `source_path` that a component was encoded from
+/// `wat` and `text` are the printed WAT being annotated, which lists the same functions with the same instructions as the source
+/// So each instruction in the source is matched to the printed one in the same position, and gets the offset printed in front of it
+/// Every instruction gets a row at its line and column, and every function gets a row at its `func` line, so its first block also counts how often it's entered
+pub fn read_wat_source(
+    wat: &Wat,
+    text: &str,
+    source_path: &Path,
+    map: &mut WatLineMapper,
+    noise_level: NoiseLevel,
+) -> parser::Result<()> {
+    let source_text = fs::read_to_string(source_path).map_err(|e| {
+        Error::new(
+            wat.span(),
+            format!("Couldn't read source WAT {}: {}", source_path.display(), e),
+        )
+    })?;
+    // Errors in the source are shown against its own text, rather than the printed WAT
+    let in_source = |mut e: Error| {
+        e.set_path(source_path);
+        e.set_text(&source_text);
+        e
+    };
+    let mut buf = ParseBuffer::new(&source_text).map_err(in_source)?;
+    let source = parse::<Wat>(buf.track_instr_spans(true)).map_err(in_source)?;
+
+    let binary_offset_re = Regex::new(BINARY_OFFSET_REGEX_STR).unwrap();
+    let modules = get_inline_modules(wat).ok_or(Error::new(
+        wat.span(),
+        "Input WAT file could not be parsed (may be binary or module)".to_string(),
+    ))?;
+    let source_modules = get_inline_modules(&source).ok_or(in_source(Error::new(
+        source.span(),
+        "Source WAT file could not be parsed (may be a module)".to_string(),
+    )))?;
+    if modules.len() != source_modules.len() {
+        return Err(in_source(Error::new(
+            source.span(),
+            format!(
+                "Source WAT has {} core modules, but the component has {}",
+                source_modules.len(),
+                modules.len()
+            ),
+        )));
+    }
+
+    let path_idx = map.add_file(source_path.to_path_buf());
+    let linecol = |span: Span| {
+        let (line, col) = span.linecol_in(&source_text);
+        (line as u64 + 1, col as u64 + 1)
+    };
+    for (inline_module_idx, ((_, fields), (source_fields_span, source_fields))) in
+        modules.into_iter().zip(source_modules).enumerate()
+    {
+        let Some(code_module_idx) = map.code_module_idx(inline_module_idx) else {
+            continue;
+        };
+        let Some(code_offset) = map.get_code_addr(code_module_idx) else {
+            continue;
+        };
+        let address = |offset: usize| {
+            printed_offset(text, offset, &binary_offset_re).map(|hex| hex - code_offset as u64)
+        };
+        let imported_funcs = imported_funcs(source_fields);
+        let funcs = inline_funcs(fields);
+        let source_funcs = inline_funcs(source_fields);
+        if funcs.len() != source_funcs.len() {
+            return Err(in_source(Error::new(
+                source_fields_span,
+                format!(
+                    "Core module {} defines {} functions in the source WAT, but {} in the component",
+                    inline_module_idx,
+                    source_funcs.len(),
+                    funcs.len()
+                ),
+            )));
+        }
+
+        // Each function's code ends where the next one starts
+        let func_addrs = funcs
+            .iter()
+            .map(|func| address(func.span.offset()))
+            .collect::<Vec<_>>();
+        for (func_idx, (func, source_func)) in funcs.iter().zip(&source_funcs).enumerate() {
+            let (
+                FuncKind::Inline { expression, .. },
+                FuncKind::Inline {
+                    expression: source_expression,
+                    ..
+                },
+            ) = (&func.kind, &source_func.kind)
+            else {
+                continue;
+            };
+            let Some(func_addr) = func_addrs[func_idx] else {
+                continue;
+            };
+            let spans = expression.instr_spans.as_deref().unwrap_or_default();
+            let source_spans = source_expression.instr_spans.as_deref().unwrap_or_default();
+            if spans.len() != source_spans.len() {
+                if noise_level.debug() {
+                    println_annotate_dbg(format!(
+                        "Function {} of core module {} doesn't have the same instructions as the source WAT, so it isn't covered",
+                        func_idx, inline_module_idx
+                    ));
+                }
+                continue;
+            }
+
+            let (line, column) = linecol(source_func.span);
+            map.add_line(DebugLineInfo {
+                address: func_addr,
+                path_idx,
+                line,
+                column,
+                discriminator: 0,
+                is_stmt: true,
+                prologue_end: false,
+                epilogue_begin: false,
+                code_module_idx,
+            });
+            for (span, source_span) in spans.iter().zip(source_spans) {
+                let Some(address) = address(span.offset()) else {
+                    continue;
+                };
+                let (line, column) = linecol(*source_span);
+                map.add_line(DebugLineInfo {
+                    address,
+                    path_idx,
+                    line,
+                    column,
+                    discriminator: 0,
+                    is_stmt: true,
+                    prologue_end: false,
+                    epilogue_begin: false,
+                    code_module_idx,
+                });
+            }
+
+            let func_end = func_addrs[func_idx + 1..].iter().find_map(|addr| *addr);
+            map.add_function(
+                code_module_idx,
+                DebugFuncInfo {
+                    low_pc: func_addr,
+                    high_pc: func_end.map(|end| end - 1),
+                    path_idx,
+                    name: func_name(source_func, imported_funcs + func_idx),
+                },
+            );
+        }
+        add_module_functions(map, code_module_idx, noise_level);
+    }
+    Ok(())
+}
+
+/// Get the functions a module defines in its code section, in order
+fn inline_funcs<'a>(fields: &'a [ModuleField<'a>]) -> Vec<&'a Func<'a>> {
+    fields
+        .iter()
+        .filter_map(|field| match field {
+            ModuleField::Func(func) if matches!(func.kind, FuncKind::Inline { .. }) => Some(func),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Count the functions a module imports, which come before the ones it defines in the function index space
+fn imported_funcs(fields: &[ModuleField]) -> usize {
+    fields
+        .iter()
+        .filter(|field| match field {
+            ModuleField::Import(import) => matches!(import.item.kind, ItemKind::Func(_)),
+            ModuleField::Func(func) => matches!(func.kind, FuncKind::Import(_)),
+            _ => false,
+        })
+        .count()
+}
+
+/// Name a function by its name annotation, its identifier, or its first export, in that order, falling back to its index
+fn func_name(func: &Func, func_idx: usize) -> String {
+    func.name
+        .map(|name| name.name.to_string())
+        .or_else(|| func.id.map(|id| format!("${}", id.name())))
+        .or_else(|| func.exports.names.first().map(|name| name.to_string()))
+        .unwrap_or_else(|| format!("func[{}]", func_idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use wast::core::ModuleKind;
+
+    use super::*;
+
+    const MODULE: &str = r#"(module
+        (import "env" "log" (func))
+        (func (import "env" "abort"))
+        (func $id (@name "named"))
+        (func $id2)
+        (func (export "exported"))
+        (func)
+    )"#;
+
+    #[test]
+    fn names_functions_in_index_order() {
+        let buf = ParseBuffer::new(MODULE).unwrap();
+        let Wat::Module(module) = parse::<Wat>(&buf).unwrap() else {
+            panic!("not a module");
+        };
+        let ModuleKind::Text(fields) = &module.kind else {
+            panic!("not a text module");
+        };
+        let imported = imported_funcs(fields);
+        assert_eq!(imported, 2);
+        let names = inline_funcs(fields)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, func)| func_name(func, imported + idx))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["named", "$id2", "exported", "func[5]"]);
+    }
+}
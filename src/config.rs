@@ -0,0 +1,87 @@
+//! This module reads the config file, which holds settings that are tedious to pass on the command line every run
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::annotator::filters::PathGlob;
+use crate::annotator::profiles::ExclusionProfile;
+
+/// The config file that's read from the current directory when no other one is given
+pub const DEFAULT_CONFIG_FILE: &str = "wcov.config.json";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    include_profiles: Vec<String>,
+    #[serde(default)]
+    exclude_paths: Vec<String>,
+}
+
+/// Settings read from a config file, which are added to the ones passed on the command line
+#[derive(Default, Debug)]
+pub struct Config {
+    /// The exclusion profiles whose code is instrumented anyway, like `--include-profile`
+    pub include_profiles: Vec<ExclusionProfile>,
+    /// Globs of source paths whose code isn't instrumented, like `--exclude-path`
+    pub exclude_paths: Vec<PathGlob>,
+}
+
+impl Config {
+    /// Parse a JSON config file
+    pub fn parse(json: &str) -> Result<Config, Box<dyn Error>> {
+        let raw: RawConfig = serde_json::from_str(json)?;
+        Ok(Config {
+            include_profiles: raw
+                .include_profiles
+                .iter()
+                .map(|profile| profile.parse())
+                .collect::<Result<_, _>>()?,
+            exclude_paths: raw
+                .exclude_paths
+                .iter()
+                .map(|glob| glob.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Read the config file at `path`, or the default one in the current directory if there's no path
+    /// Without a path or a default file, every setting is left empty
+    pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Path::new(DEFAULT_CONFIG_FILE),
+            None => return Ok(Config::default()),
+        };
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read config file {}: {}", path.display(), e))?;
+        Config::parse(&json)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings() {
+        let config = Config::parse(
+            r#"{"include_profiles": ["wit-bindgen"], "exclude_paths": ["gen/**", "/tmp/*.rs"]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.include_profiles, [ExclusionProfile::WitBindgen]);
+        assert!(config.exclude_paths[0].matches(Path::new("/app/gen/bindings.rs")));
+        assert!(config.exclude_paths[1].matches(Path::new("/tmp/main.rs")));
+        assert!(Config::parse("{}").unwrap().exclude_paths.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::parse(r#"{"include_profiles": ["libc"]}"#).is_err());
+        assert!(Config::parse(r#"{"exclude_path": ["gen/**"]}"#).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod annotator;
+pub mod config;
 pub mod noise;
 pub mod printer;
 pub mod runner;
@@ -7,30 +7,38 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use clap::{ArgGroup, Parser};
 use wasmprinter::{Config, PrintFmtWrite};
-use wast::core::EncodeOptions;
 use wast::parser::{parse, ParseBuffer};
 use wast::Wat;
+use wcov::annotator::filters::{FunctionFilter, FunctionFilters, PathGlob};
+use wcov::annotator::names::FUNCTION_LISTING_FILE;
+use wcov::annotator::profiles::ExclusionProfile;
+use wcov::annotator::relocate::{add_probe_offsets, relocate_dwarf, strip_dwarf};
+use wcov::annotator::AnnotateOptions;
+use wcov::config;
 use wcov::noise::NoiseLevel;
-use wcov::printer::println_wcov_dbg;
+use wcov::printer::{println_wcov_dbg, println_wcov_error};
+use wcov::runner::threshold::Thresholds;
+use wcov::runner::{GuestStatus, ReportOptions, SummaryMode};
 
 const ABOUT: &str = "wcov is a tool for generating lcov/gcov style coverage reports from Wasm modules or components. 
 To test Wasm code, run wcov --path <WASM_FILE> --build-dir <BUILD_DIR> --output-files <OUTPUT_FILES>. 
 wcov will embed debugging host functions into the Wasm code found in <WASM_FILE>, will run the modified code in a debugging environment, 
-collect counter information on the source files listed in <OUTPUT_FILES>, and place generated reports in <BUILD_DIR>"; 
+collect counter information on the source files listed in <OUTPUT_FILES>, and place generated reports in <BUILD_DIR>";
 
 #[derive(Parser)]
 #[command(version, about, long_about = Some(ABOUT))]
-#[clap(group(
-    ArgGroup::new("input")
-        .args(&["path", "bytes"])
-))]
 #[clap(group(
     ArgGroup::new("output_noise_level")
         .args(&["verbose", "quiet"])
 ))]
+#[clap(group(
+    ArgGroup::new("summary_output")
+        .args(&["summary_only", "no_summary"])
+))]
 struct Cli {
     #[arg(short, long, value_name = "VERBOSE")]
     verbose: bool,
@@ -52,9 +60,91 @@ struct Cli {
 
     #[arg(short, long, value_name = "DUMP_DATA")]
     dump_data: bool,
+
+    /// Also write an `llvm-cov export` style JSON report to the build directory
+    #[arg(short, long, value_name = "JSON")]
+    json: bool,
+
+    /// Print the coverage summary table without writing any report files
+    #[arg(long)]
+    summary_only: bool,
+
+    /// Don't print the coverage summary table
+    #[arg(long)]
+    no_summary: bool,
+
+    /// Exit with an error if total line coverage is below this percentage
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percentage)]
+    fail_under_lines: Option<f64>,
+
+    /// Exit with an error if total function coverage is below this percentage
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percentage)]
+    fail_under_functions: Option<f64>,
+
+    /// Exit with an error if total branch coverage is below this percentage (not supported yet, since branch coverage is not collected)
+    #[arg(long, value_name = "PERCENT", value_parser = parse_branch_percentage)]
+    fail_under_branches: Option<f64>,
+
+    /// Exit with an error if the line coverage of any single file is below this percentage
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percentage)]
+    fail_under_file: Option<f64>,
+
+    /// Read line info from this source map, for code compiled without DWARF
+    #[arg(long, value_name = "FILE")]
+    source_map: Option<PathBuf>,
+
+    /// The index of the core module the `--source-map` is for, when the component has more than one without DWARF
+    #[arg(long, value_name = "INDEX", requires = "source_map")]
+    source_map_module: Option<usize>,
+
+    /// Remove DWARF from the instrumented binary, instead of moving it to the instrumented code
+    #[arg(long)]
+    strip_debug: bool,
+
+    /// Instrument the code of an exclusion profile (rust-std, wit-bindgen, or c-sysroot), which is skipped by default
+    #[arg(long, value_name = "PROFILE")]
+    include_profile: Vec<ExclusionProfile>,
+
+    /// Don't instrument code from source files matching this glob, where a relative glob like `src/generated/**` can match in any directory
+    #[arg(long, value_name = "GLOB")]
+    exclude_path: Vec<PathGlob>,
+
+    /// Read settings from this JSON config file, instead of `wcov.config.json` in the current directory
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Only instrument functions matching a filter, either `file:<GLOB>` for the file they're declared in or `fn:<REGEX>` for their name
+    #[arg(long, value_name = "FILTER")]
+    instrument_only: Vec<FunctionFilter>,
+
+    /// Don't instrument functions matching a filter, written the same way as for `--instrument-only`
+    #[arg(long, value_name = "FILTER")]
+    skip: Vec<FunctionFilter>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+/// The exit code used when the component under test reports an error
+const GUEST_FAILURE_EXIT_CODE: u8 = 1;
+/// The exit code used when the component under test traps, matching what the `wasmtime` CLI uses (128 + SIGABRT)
+const GUEST_TRAP_EXIT_CODE: u8 = 134;
+/// The exit code used when coverage falls below one of the `--fail-under-*` minimums
+const THRESHOLD_FAILURE_EXIT_CODE: u8 = 2;
+
+/// Parse a coverage percentage, which has to be between 0 and 100
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let percent = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..=100.0).contains(&percent) {
+        Ok(percent)
+    } else {
+        Err(format!("{} is not a percentage between 0 and 100", s))
+    }
+}
+
+/// Reject any branch coverage minimum, since there's no branch data to check it against
+fn parse_branch_percentage(_: &str) -> Result<f64, String> {
+    Err("branch coverage is not collected yet".to_string())
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     let cli = Cli::parse();
     if !cli.build_dir.exists() {
         if cli.verbose {
@@ -65,11 +155,32 @@ fn main() -> Result<(), Box<dyn Error>> {
         assert!(cli.build_dir.is_dir())
     }
     let noise_level = NoiseLevel::from_settings(cli.verbose, cli.quiet);
+    // Settings from the config file are added to the ones passed on the command line
+    let settings = config::Config::load(cli.config.as_deref())?;
 
     if noise_level.debug() {
         println_wcov_dbg("Converting binary to WAT");
     }
-    let binary = fs::read(cli.path.clone())?;
+    let input = fs::read(cli.path.clone())?;
+    // Hand-written WAT is encoded first, and coverage is reported against its own lines
+    let (binary, wat_source) = if input.starts_with(b"\0asm") {
+        (input, None)
+    } else {
+        if noise_level.debug() {
+            println_wcov_dbg("Encoding WAT input");
+        }
+        let text = String::from_utf8(input)?;
+        let with_text = |mut e: wast::Error| {
+            e.set_path(&cli.path);
+            e.set_text(&text);
+            e
+        };
+        let buf = ParseBuffer::new(&text).map_err(with_text)?;
+        let binary = parse::<Wat>(&buf)
+            .and_then(|mut wat| wat.encode())
+            .map_err(with_text)?;
+        (binary, Some(cli.path.canonicalize()?))
+    };
     let mut wat = PrintFmtWrite(String::new());
     let mut printer_cfg = Config::new();
     printer_cfg.print_offsets(true);
@@ -78,8 +189,37 @@ fn main() -> Result<(), Box<dyn Error>> {
     if noise_level.debug() {
         println_wcov_dbg("Modifying WAT")
     }
-    let (output_wat, data) =
-        wcov::annotator::modify_wasm(None, Some(wat), Some(cli.path), noise_level)?;
+    // The runner looks files up by their canonical path
+    let function_listing = cli.build_dir.canonicalize()?.join(FUNCTION_LISTING_FILE);
+    let options = AnnotateOptions {
+        source_map: cli.source_map,
+        source_map_module: cli.source_map_module,
+        function_listing: Some(function_listing.clone()),
+        wat_source: wat_source.clone(),
+        included_profiles: [settings.include_profiles, cli.include_profile].concat(),
+        excluded_paths: [settings.exclude_paths, cli.exclude_path].concat(),
+        function_filters: FunctionFilters {
+            instrument_only: cli.instrument_only,
+            skip: cli.skip,
+        },
+        ..Default::default()
+    };
+    // The binary is only read from the input path if the input wasn't WAT
+    let binary_path = wat_source.is_none().then_some(cli.path);
+    // The annotator encodes the instrumented WAT to validate it, so the binary it returns is used as is
+    let (output_wat, output_binary, mut data) =
+        wcov::annotator::modify_wasm(None, Some(wat), binary_path, options, noise_level)?;
+    // Without line info, the listing of functions covered by name is the only file there is to report
+    let mut output_files = cli.output_files;
+    if data.file_map.contains(&function_listing) {
+        if noise_level.debug() {
+            println_wcov_dbg("No line info found, reporting function coverage by name");
+        }
+        output_files.push(function_listing);
+    }
+    if let Some(wat_source) = wat_source.filter(|_| output_files.is_empty()) {
+        output_files.push(wat_source);
+    }
 
     if cli.dump_data {
         // output data to build folder
@@ -88,20 +228,20 @@ fn main() -> Result<(), Box<dyn Error>> {
         let wat_path = cli.build_dir.join("src.wat");
         fs::write(wat_path, &output_wat)?;
     }
-    let buf = ParseBuffer::new(&output_wat)?;
-    let mut output_wat = parse::<Wat>(&buf)?;
-    if noise_level.debug() {
-        println_wcov_dbg("Encoding WAT to WASM")
-    }
-    let opts = EncodeOptions::default();
-    let output_binary = opts.encode_wat(&mut output_wat)?;
+    // The DWARF copied over from the input still points to where the code was before it was instrumented
+    let output_binary = if cli.strip_debug {
+        strip_dwarf(&output_binary)?
+    } else {
+        relocate_dwarf(&binary, &output_binary, noise_level)?
+    };
+    // Traps point to offsets in the binary that runs, so they're mapped back to the code the line info describes
+    add_probe_offsets(&binary, &output_binary, &mut data, noise_level)?;
 
     if noise_level.debug() {
         println_wcov_dbg("Creating output paths");
     }
     // create paths
-    let output_paths = cli
-        .output_files
+    let output_paths = output_files
         .iter()
         .map(|f| {
             cli.build_dir
@@ -110,17 +250,52 @@ fn main() -> Result<(), Box<dyn Error>> {
         .collect::<Vec<_>>();
 
     let tracefile_path = cli.build_dir.join("wcov.info");
+    let json_path = cli.json.then(|| cli.build_dir.join("wcov.json"));
 
     if noise_level.debug() {
         println_wcov_dbg("Calling runner");
     }
-    wcov::runner::run(
+    let outcome = wcov::runner::run(
         output_binary,
         Some(data),
         Some(HashMap::new()),
-        Some(cli.output_files),
-        Some(output_paths),
-        Some(tracefile_path),
+        ReportOptions {
+            files_to_output: Some(output_files),
+            output: Some(output_paths),
+            tracefile_path: Some(tracefile_path),
+            json_path,
+            summary: if cli.summary_only {
+                SummaryMode::Only
+            } else if cli.no_summary {
+                SummaryMode::Hide
+            } else {
+                SummaryMode::Show
+            },
+            thresholds: Thresholds {
+                lines: cli.fail_under_lines,
+                functions: cli.fail_under_functions,
+                branches: cli.fail_under_branches,
+                file_lines: cli.fail_under_file,
+            },
+            trap_path: Some(cli.build_dir.join("trap.txt")),
+        },
         noise_level,
-    )
+    )?;
+
+    if noise_level.err() {
+        for failure in &outcome.threshold_failures {
+            println_wcov_error(failure);
+        }
+    }
+    Ok(match outcome.guest_status {
+        GuestStatus::Error => ExitCode::from(GUEST_FAILURE_EXIT_CODE),
+        GuestStatus::Trap { .. } => ExitCode::from(GUEST_TRAP_EXIT_CODE),
+        // Only the low byte of an exit code makes it to the parent process, so codes like 256 would look like success
+        GuestStatus::Exit(code) if code != 0 => match code as u8 {
+            0 => ExitCode::from(GUEST_FAILURE_EXIT_CODE),
+            low_byte => ExitCode::from(low_byte),
+        },
+        _ if !outcome.threshold_failures.is_empty() => ExitCode::from(THRESHOLD_FAILURE_EXIT_CODE),
+        _ => ExitCode::SUCCESS,
+    })
 }
@@ -1,6 +1,8 @@
 use std::fmt::Display;
 
-use colored::{Color, Colorize};
+use colored::{Color, ColoredString, Colorize};
+
+use crate::runner::lcov::{CoverageCount, Summary};
 
 fn print_err<D: Display>(name: &str, msg: D) {
     eprintln!("{}: {}", name.red(), msg);
@@ -37,3 +39,79 @@ pub fn println_runner_error<D: Display>(msg: D) {
 pub fn println_runner_dbg<D: Display>(msg: D) {
     print_dbg("Runner", msg, RUNNER_COLOR);
 }
+
+const HIGH_COVERAGE: f64 = 90.0;
+const MEDIUM_COVERAGE: f64 = 75.0;
+
+fn format_count(count: &CoverageCount) -> (String, ColoredString) {
+    let ratio = format!("{}/{}", count.hit, count.found);
+    let percent = match count.percent() {
+        Some(p) if p >= HIGH_COVERAGE => format!("{:.1}%", p).green(),
+        Some(p) if p >= MEDIUM_COVERAGE => format!("{:.1}%", p).yellow(),
+        Some(p) => format!("{:.1}%", p).red(),
+        None => "-".dimmed(),
+    };
+    (ratio, percent)
+}
+
+/// Print a table of per-file coverage, sorted so the least covered files come first, followed by a total row
+pub fn print_summary_table(files: &[(String, Summary)], totals: &Summary) {
+    let mut rows = files.iter().collect::<Vec<_>>();
+    rows.sort_by(|(n1, s1), (n2, s2)| {
+        let p1 = s1.lines.percent().unwrap_or(100.0);
+        let p2 = s2.lines.percent().unwrap_or(100.0);
+        p1.total_cmp(&p2).then_with(|| n1.cmp(n2))
+    });
+
+    // Branch coverage is left out until branch data is collected
+    let headers = ["Lines", "Functions"];
+    let cells = rows
+        .iter()
+        .map(|(name, s)| (name.as_str(), s))
+        .chain(std::iter::once(("TOTAL", totals)))
+        .map(|(name, s)| {
+            (
+                name,
+                [
+                    format_count(&s.lines),
+                    format_count(&s.functions),
+                ],
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let name_width = cells
+        .iter()
+        .map(|(name, _)| name.len())
+        .chain(std::iter::once("File".len()))
+        .max()
+        .unwrap();
+    let ratio_width = cells
+        .iter()
+        .flat_map(|(_, cols)| cols.iter().map(|(ratio, _)| ratio.len()))
+        .max()
+        .unwrap();
+    // "100.0%" is the widest a percentage can get
+    let col_width = (ratio_width + 1 + 6).max(headers.iter().map(|h| h.len()).max().unwrap());
+    let ratio_width = col_width - 1 - 6;
+
+    print!("{:name_width$}", "File".bold());
+    for header in headers {
+        print!(" | {:>col_width$}", header.bold());
+    }
+    println!();
+    let rule_width = name_width + headers.len() * (col_width + 3);
+    println!("{}", "-".repeat(rule_width).dimmed());
+    for (idx, (name, cols)) in cells.iter().enumerate() {
+        if idx == cells.len() - 1 {
+            println!("{}", "-".repeat(rule_width).dimmed());
+            print!("{:name_width$}", name.bold());
+        } else {
+            print!("{:name_width$}", name);
+        }
+        for (ratio, percent) in cols {
+            print!(" | {:>ratio_width$} {:>6}", ratio, percent);
+        }
+        println!();
+    }
+}
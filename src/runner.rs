@@ -4,22 +4,30 @@
 #![warn(missing_docs)]
 
 use std::error::Error;
+use std::fmt::Display;
 use std::{fs, path::PathBuf};
 
+pub mod backtrace;
+pub mod exclusions;
 pub mod gcov;
 pub mod inc_counter;
+pub mod json;
 pub mod lcov;
 pub mod store;
+pub mod threshold;
 
+use crate::annotator::annotate::FLUSH_EXPORT_PREFIX;
 use crate::annotator::data::*;
 use crate::noise::NoiseLevel;
-use crate::printer::{println_runner_dbg, println_runner_error};
+use crate::printer::{print_summary_table, println_runner_dbg, println_runner_error};
+use backtrace::{panic_site, resolve_backtrace, TrapFrame};
 use component::{Component, ResourceTable};
 use gcov::GCovFile;
 use store::MyState;
+use threshold::{ThresholdFailure, Thresholds};
 use wasmtime::*;
 use wasmtime_wasi::bindings::sync::exports::wasi::cli::run::GuestPre;
-use wasmtime_wasi::WasiCtxBuilder;
+use wasmtime_wasi::{I32Exit, WasiCtxBuilder};
 
 // There's definitely a faster way to write this, but I like writing code :3
 
@@ -62,16 +70,158 @@ use std::collections::HashMap;
 
 use std::sync::Arc;
 
+/// The reports that should be generated after a Wasm component is run
+#[derive(Default)]
+pub struct ReportOptions {
+    /// The source files to generate reports for
+    pub files_to_output: Option<Vec<PathBuf>>,
+    /// The paths to write `.gcov` files to, one for each file in `files_to_output`
+    /// If this isn't provided, the `.gcov` output is printed instead
+    pub output: Option<Vec<PathBuf>>,
+    /// The path to write an lcov tracefile to
+    pub tracefile_path: Option<PathBuf>,
+    /// The path to write an `llvm-cov export` style JSON file to
+    pub json_path: Option<PathBuf>,
+    /// Whether to print a coverage summary table to the terminal
+    pub summary: SummaryMode,
+    /// Minimum coverage percentages the run has to meet
+    pub thresholds: Thresholds,
+    /// The path to write the trap message and backtrace to, if the component traps
+    pub trap_path: Option<PathBuf>,
+}
+
+/// How the component under test finished running
+pub enum GuestStatus {
+    /// The component's `run` export returned successfully
+    Success,
+    /// The component's `run` export returned an error
+    Error,
+    /// The component called `proc_exit` with this exit code
+    Exit(i32),
+    /// The component trapped, while running or while it was being instantiated
+    Trap {
+        /// The trap message
+        message: String,
+        /// The frames of the Wasm backtrace at the trap, innermost first, which are empty if no backtrace was captured
+        frames: Vec<TrapFrame>,
+    },
+}
+
+impl GuestStatus {
+    fn from_run_result(
+        result: wasmtime::Result<Result<(), ()>>,
+        debug_data: Option<&DebugDataArc>,
+    ) -> GuestStatus {
+        match result {
+            Ok(Ok(())) => GuestStatus::Success,
+            Ok(Err(())) => GuestStatus::Error,
+            Err(e) => {
+                if let Some(exit) = e.downcast_ref::<I32Exit>() {
+                    GuestStatus::Exit(exit.0)
+                } else {
+                    GuestStatus::Trap {
+                        message: e.root_cause().to_string(),
+                        frames: e
+                            .downcast_ref::<WasmBacktrace>()
+                            .map(|bt| resolve_backtrace(bt, debug_data))
+                            .unwrap_or_default(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for GuestStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestStatus::Success => write!(f, "Wasm exited successfully"),
+            GuestStatus::Error => write!(f, "Wasm exit code was error"),
+            GuestStatus::Exit(code) => write!(f, "Wasm exited with code {}", code),
+            GuestStatus::Trap { message, frames } => {
+                write!(f, "Wasm trapped: {}", message)?;
+                if !frames.is_empty() {
+                    write!(f, "\nwasm backtrace:")?;
+                }
+                for (idx, frame) in frames.iter().enumerate() {
+                    write!(f, "\n{:>5}: {}", idx, frame)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The result of running a Wasm component under testing
+pub struct RunOutcome {
+    /// How the component finished running
+    pub guest_status: GuestStatus,
+    /// Every coverage minimum that wasn't met
+    pub threshold_failures: Vec<ThresholdFailure>,
+}
+
+/// Controls whether a coverage summary table is printed once the component has finished
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryMode {
+    /// Don't print a summary
+    #[default]
+    Hide,
+    /// Print a summary as well as writing the requested reports
+    Show,
+    /// Print a summary without writing any reports
+    Only,
+}
+
+/// Get the names of the exports that flush counters kept inside the component
+fn flush_export_names(engine: &Engine, component: &Component) -> Vec<String> {
+    component
+        .component_type()
+        .exports(engine)
+        .map(|(name, _)| name.to_string())
+        .filter(|name| name.starts_with(FLUSH_EXPORT_PREFIX))
+        .collect()
+}
+
+/// Call every export that flushes counters kept inside the component, so they're added to the counters in the store
+fn flush_local_counters(
+    store: &mut Store<MyState>,
+    instance: &component::Instance,
+    names: &[String],
+    noise_level: NoiseLevel,
+) {
+    for name in names {
+        let result = instance
+            .get_typed_func::<(), ()>(&mut *store, name)
+            .and_then(|func| {
+                func.call(&mut *store, ())?;
+                func.post_return(&mut *store)
+            });
+        if let Err(e) = result {
+            if noise_level.err() {
+                println_runner_error(format!("Couldn't flush counters with `{}`: {}", name, e));
+            }
+        }
+    }
+}
+
 /// Runs a Wasm component under testing
 pub fn run(
     bytes: Vec<u8>,
     file_map: Option<DebugDataOwned>,
     gcov_files: Option<HashMap<Arc<PathBuf>, GCovFile>>,
-    files_to_output: Option<Vec<PathBuf>>,
-    output: Option<Vec<PathBuf>>,
-    tracefile_path: Option<PathBuf>,
+    reports: ReportOptions,
     noise_level: NoiseLevel,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<RunOutcome, Box<dyn Error>> {
+    let ReportOptions {
+        files_to_output,
+        output,
+        tracefile_path,
+        json_path,
+        summary,
+        thresholds,
+        trap_path,
+    } = reports;
+
     let engine = Engine::default();
 
     let mut linker = component::Linker::<MyState>::new(&engine);
@@ -90,6 +240,7 @@ pub fn run(
             noise_level,
         },
     );
+    store.data_mut().populate_gcov_files();
 
     let inc_counter = |store: StoreContextMut<MyState>, args| {
         let noise_level = store.data().noise_level;
@@ -100,16 +251,91 @@ pub fn run(
 
     let component = Component::new(&engine, &bytes)?;
 
-    let instance = linker.instantiate(&mut store, &component)?;
-    let guest = GuestPre::new(&component)?.load(&mut store, &instance)?;
-
-    let exit_code = guest.call_run(&mut store)?;
-    if exit_code.is_err() && noise_level.err() {
-        println_runner_error("Wasm exit code was error");
+    // Traps and `proc_exit` still leave the counters in the store, so reports are written either way
+    // That includes traps while instantiating, like in a start function, but not errors like missing imports
+    let (instance, result) = match linker.instantiate(&mut store, &component) {
+        Ok(instance) => {
+            let guest = GuestPre::new(&component)?.load(&mut store, &instance)?;
+            let result = guest.call_run(&mut store);
+            (Some(instance), result)
+        }
+        Err(e) if e.is::<Trap>() || e.is::<I32Exit>() => (None, Err(e)),
+        Err(e) => return Err(e.into()),
+    };
+    let guest_status = GuestStatus::from_run_result(result, store.data().debug_data.as_ref());
+    // `realloc` and post-return functions keep their counters inside the component, and they can only be collected if it's still usable
+    // Flushing is still tried after `proc_exit`, and any instance that can't be entered again is reported as an error
+    let flush_exports = flush_export_names(&engine, &component);
+    if let Some(instance) = instance.filter(|_| !matches!(guest_status, GuestStatus::Trap { .. })) {
+        flush_local_counters(&mut store, &instance, &flush_exports, noise_level);
+    } else if !flush_exports.is_empty() && noise_level.err() {
+        // Without a trap, the instance is only missing if the component exited while it was being instantiated
+        let reason = match guest_status {
+            GuestStatus::Trap { .. } => "the component trapped",
+            _ => "the component exited while it was being instantiated",
+        };
+        println_runner_error(format!("Skipping the counters of `realloc` and post-return functions, since {}, so they are missing from the reports", reason));
     }
+    match &guest_status {
+        GuestStatus::Success | GuestStatus::Exit(0) => {}
+        status => {
+            if noise_level.err() {
+                println_runner_error(status);
+            }
+        }
+    }
+    // The line the code under test trapped on is marked in its report
+    let mut trap_report = format!("{}\n", guest_status);
+    if let GuestStatus::Trap { message, frames } = &guest_status {
+        if let Some((path, line, column)) =
+            panic_site(frames, files_to_output.as_deref()).and_then(|frame| frame.location.as_ref())
+        {
+            let site = format!("Panic site: {}:{}:{}", path.display(), line, column);
+            if noise_level.err() {
+                println_runner_error(&site);
+            }
+            trap_report.push_str(&format!("{}\n", site));
+            if let Some(gcov) = store
+                .data_mut()
+                .gcov_files
+                .as_mut()
+                .and_then(|files| files.get_mut(path))
+            {
+                gcov.set_panic_site(*line, *column, message);
+            }
+        }
+    }
+    if let (GuestStatus::Trap { .. }, Some(path)) = (&guest_status, &trap_path) {
+        fs::write(path, trap_report)?;
+    }
+    let mut outcome = RunOutcome {
+        guest_status,
+        threshold_failures: Vec::new(),
+    };
 
     if let Some(outputs) = files_to_output {
-        if let Some(output_files) = output {
+        // Lines marked with `wcov:ignore` or the `LCOV_EXCL_*` markers are left out of every report
+        if let Some(files) = store.data_mut().gcov_files.as_mut() {
+            for file in &outputs {
+                let Some(gcov) = file
+                    .canonicalize()
+                    .ok()
+                    .and_then(|path| files.get_mut(&path))
+                else {
+                    continue;
+                };
+                let excluded = gcov.exclude_marked_lines();
+                if excluded > 0 && noise_level.debug() {
+                    println_runner_dbg(format!(
+                        "Excluded {} lines of {} with markers",
+                        excluded,
+                        file.display()
+                    ));
+                }
+            }
+        }
+        let write_reports = summary != SummaryMode::Only;
+        if let Some(output_files) = output.filter(|_| write_reports) {
             assert_eq!(output_files.len(), outputs.len());
             for (idx, file) in outputs.iter().enumerate() {
                 if let Some(gcov) = store
@@ -125,7 +351,7 @@ pub fn run(
                         file.display(), store.data().gcov_files.as_ref().unwrap().keys()));
                 }
             }
-        } else {
+        } else if write_reports {
             for path in &outputs {
                 let gcov =
                     &store.data().gcov_files.as_ref().unwrap()[&path.canonicalize().unwrap()];
@@ -134,35 +360,106 @@ pub fn run(
         }
 
         // create tracefile
-        if tracefile_path.is_some()
-            && store.data().gcov_files.is_some()
-            && store.data().debug_data.is_some()
-        {
-            let mut source_files = Vec::new();
-
-            let files = store.data().gcov_files.as_ref().unwrap();
-            let debug_data = store.data().debug_data.as_ref().unwrap();
-            for file_path in &outputs {
-                let file_path = file_path.canonicalize().unwrap();
-                let gcov = files.get(&file_path).unwrap();
-                if noise_level.debug() {
-                    println_runner_dbg(format!(
-                        "Adding file to tracefile: {}",
-                        file_path.display()
-                    ));
-                }
-                if let Some(sdi) = debug_data.get_sdi_from_file(&file_path) {
-                    if noise_level.debug() {
-                        println_runner_dbg("Creating SDI");
+        let tracefile = create_tracefile(store.data(), &outputs, noise_level);
+        if let Some(tracefile) = &tracefile {
+            if let Some(path) = tracefile_path.filter(|_| write_reports) {
+                fs::write(path.as_path(), format!("{}", tracefile)).unwrap();
+            }
+            if summary != SummaryMode::Hide {
+                let rows = tracefile
+                    .files()
+                    .iter()
+                    .map(|sf| (sf.path().display().to_string(), sf.summary()))
+                    .collect::<Vec<_>>();
+                print_summary_table(&rows, &tracefile.totals());
+            }
+        }
+        outcome.threshold_failures = thresholds.check(tracefile.as_ref());
+
+        // create json export
+        if let Some(path) = json_path.filter(|_| write_reports) {
+            if let Some(files) = store.data().gcov_files.as_ref() {
+                let debug_data = store.data().debug_data.as_ref();
+                let mut file_exports = Vec::new();
+                for file_path in &outputs {
+                    let file_path = file_path.canonicalize().unwrap();
+                    if let Some(gcov) = files.get(&file_path) {
+                        if noise_level.debug() {
+                            println_runner_dbg(format!(
+                                "Adding file to JSON export: {}",
+                                file_path.display()
+                            ));
+                        }
+                        let sdi = debug_data.and_then(|d| d.get_sdi_from_file(&file_path));
+                        file_exports.push(json::FileExport::new(gcov, sdi));
                     }
-                    let source_file = lcov::SourceFile::new(gcov, sdi, noise_level);
-                    source_files.push(source_file);
                 }
+                let export = json::CoverageExport::new(file_exports);
+                fs::write(path.as_path(), export.to_json()?)?;
             }
-            let tracefile = lcov::TraceFile::new(Some("tracefile"), source_files);
-            let path = tracefile_path.unwrap();
-            fs::write(path.as_path(), format!("{}", tracefile)).unwrap();
         }
+    } else {
+        outcome.threshold_failures = thresholds.check(None);
+    }
+    Ok(outcome)
+}
+
+/// Build an lcov tracefile for the requested source files out of the counters collected while running
+/// Returns `None` if the run didn't collect any debug data
+fn create_tracefile(
+    state: &MyState,
+    outputs: &[PathBuf],
+    noise_level: NoiseLevel,
+) -> Option<lcov::TraceFile> {
+    let files = state.gcov_files.as_ref()?;
+    let debug_data = state.debug_data.as_ref()?;
+
+    let mut source_files = Vec::new();
+    for file_path in outputs {
+        let file_path = file_path.canonicalize().unwrap();
+        let Some(gcov) = files.get(&file_path) else {
+            continue;
+        };
+        if noise_level.debug() {
+            println_runner_dbg(format!("Adding file to tracefile: {}", file_path.display()));
+        }
+        let sdi = debug_data.get_sdi_from_file(&file_path);
+        if sdi.is_none() && noise_level.debug() {
+            println_runner_dbg("File has no function information");
+        }
+        let version = debug_data
+            .get_checksum_from_file(&file_path)
+            .map(str::to_string);
+        let source_file = lcov::SourceFile::new(gcov, sdi, version, noise_level);
+        source_files.push(source_file);
+    }
+    Some(lcov::TraceFile::new(Some("tracefile"), source_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_guest_status_from_the_run_result() {
+        assert!(matches!(
+            GuestStatus::from_run_result(Ok(Ok(())), None),
+            GuestStatus::Success
+        ));
+        assert!(matches!(
+            GuestStatus::from_run_result(Ok(Err(())), None),
+            GuestStatus::Error
+        ));
+        assert!(matches!(
+            GuestStatus::from_run_result(Err(I32Exit(3).into()), None),
+            GuestStatus::Exit(3)
+        ));
+        let status = GuestStatus::from_run_result(Err(wasmtime::Error::msg("unreachable")), None);
+        let GuestStatus::Trap { message, frames } = &status else {
+            panic!("not a trap");
+        };
+        assert_eq!(message, "unreachable");
+        assert!(frames.is_empty());
+        assert_eq!(status.to_string(), "Wasm trapped: unreachable");
     }
-    Ok(())
 }
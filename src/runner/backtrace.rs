@@ -0,0 +1,145 @@
+//! This module maps the backtrace of a trap in an instrumented component back to the source it was compiled from
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use wasmtime::{FrameInfo, Module, WasmBacktrace};
+
+use crate::annotator::data::{CodeLineTable, DebugDataArc};
+use crate::annotator::names::demangle;
+use crate::runner::gcov::{ColumnIndex, LineIndex};
+
+/// A frame of a trap's backtrace, with the source location of its instruction if the line info covers it
+pub struct TrapFrame {
+    /// The offset of the frame's instruction in the binary that ran
+    pub offset: Option<usize>,
+    /// The name of the frame's function
+    pub function: String,
+    /// The source file, line, and column of the frame's instruction
+    pub location: Option<(Arc<PathBuf>, LineIndex, ColumnIndex)>,
+}
+
+impl TrapFrame {
+    /// Map a frame back through the probe offset table of its module to the original code, and look up its line row and function there
+    /// Frames outside the line info keep the name from the `name` section, or the function's index if there isn't one
+    pub fn new(frame: &FrameInfo, debug_data: Option<&DebugDataArc>) -> TrapFrame {
+        let offset = frame.module_offset();
+        let original = offset.zip(debug_data).and_then(|(offset, debug_data)| {
+            let table = module_line_table(frame.module(), debug_data)?;
+            let address = table.original_address(offset)?;
+            Some((debug_data, table, address))
+        });
+        let function = original
+            .and_then(|(_, table, address)| table.function_at(address))
+            .map(|func| func.name.clone())
+            .or_else(|| frame.func_name().map(demangle))
+            .unwrap_or_else(|| format!("<wasm function {}>", frame.func_index()));
+        let location = original.and_then(|(debug_data, table, address)| {
+            let (path_idx, line, column, _) = table.location_at(address)?;
+            Some((debug_data.file_map.get(*path_idx)?.clone(), *line, *column))
+        });
+        TrapFrame {
+            offset,
+            function,
+            location,
+        }
+    }
+}
+
+impl Display for TrapFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{:#8x} - {}", offset, self.function)?,
+            None => write!(f, "<unknown> - {}", self.function)?,
+        }
+        if let Some((path, line, column)) = &self.location {
+            write!(f, "\n                at {}:{}", path.display(), line)?;
+            // Column 0 means the row doesn't say which column it's at
+            if *column != 0 {
+                write!(f, ":{}", column)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the line table of the core module that `module` was compiled from, which is the one whose code contains the module's first instruction
+fn module_line_table<'a>(
+    module: &Module,
+    debug_data: &'a DebugDataArc,
+) -> Option<&'a CodeLineTable> {
+    let module_offset = module
+        .address_map()?
+        .find_map(|(_, wasm_offset)| wasm_offset)? as usize;
+    debug_data
+        .line_tables
+        .iter()
+        .find(|table| table.code_range.contains(&module_offset))
+}
+
+/// Map every frame of a backtrace back to source, innermost first
+pub fn resolve_backtrace(
+    backtrace: &WasmBacktrace,
+    debug_data: Option<&DebugDataArc>,
+) -> Vec<TrapFrame> {
+    backtrace
+        .frames()
+        .iter()
+        .map(|frame| TrapFrame::new(frame, debug_data))
+        .collect()
+}
+
+/// Find where the code under test trapped, which is the innermost frame in one of `files`
+/// A panic traps inside the standard library, so the frames before the one that called into it are skipped
+/// If no files are given, the innermost frame with a location is used
+pub fn panic_site<'a>(frames: &'a [TrapFrame], files: Option<&[PathBuf]>) -> Option<&'a TrapFrame> {
+    frames.iter().find(|frame| {
+        frame.location.as_ref().is_some_and(|(path, _, _)| {
+            files.is_none_or(|files| {
+                files
+                    .iter()
+                    .any(|file| file.canonicalize().is_ok_and(|file| file == **path))
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(function: &str, location: Option<(&std::path::Path, LineIndex)>) -> TrapFrame {
+        TrapFrame {
+            offset: Some(0x2a),
+            function: function.to_string(),
+            location: location.map(|(path, line)| (Arc::new(path.to_path_buf()), line, 0)),
+        }
+    }
+
+    #[test]
+    fn finds_the_panic_site_in_the_given_files() {
+        let manifest = PathBuf::from("Cargo.toml").canonicalize().unwrap();
+        let frames = [
+            frame("abort", None),
+            frame(
+                "panic",
+                Some((std::path::Path::new("/rustc/panicking.rs"), 10)),
+            ),
+            frame("main", Some((&manifest, 3))),
+        ];
+        assert_eq!(panic_site(&frames, None).unwrap().function, "panic");
+        let site = panic_site(&frames, Some(&[PathBuf::from("Cargo.toml")])).unwrap();
+        assert_eq!(site.function, "main");
+        assert!(panic_site(&frames, Some(&[])).is_none());
+    }
+
+    #[test]
+    fn displays_frames() {
+        let path = std::path::Path::new("src/main.rs");
+        assert_eq!(frame("main", None).to_string(), "    0x2a - main");
+        assert_eq!(
+            frame("main", Some((path, 3))).to_string(),
+            "    0x2a - main\n                at src/main.rs:3"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! This module finds the lines of a source file that markers in its comments exclude from coverage
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use crate::runner::gcov::LineIndex;
+
+/// Markers that exclude the line they're on
+const LINE_MARKERS: [&str; 2] = ["LCOV_EXCL_LINE", "wcov:ignore"];
+/// The marker that starts a region of excluded lines, which includes the line it's on
+const START_MARKER: &str = "LCOV_EXCL_START";
+/// The marker that ends a region of excluded lines, which includes the line it's on
+const STOP_MARKER: &str = "LCOV_EXCL_STOP";
+
+/// The lines of a source file that are excluded from coverage
+#[derive(Default)]
+pub struct Exclusions {
+    lines: BTreeSet<LineIndex>,
+}
+
+impl Exclusions {
+    /// Find the lines excluded by markers in `source`
+    /// A region that's started but never stopped runs to the end of the file, like it does in lcov
+    pub fn scan(source: &str) -> Exclusions {
+        let mut exclusions = Exclusions::default();
+        let mut in_region = false;
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx as u64 + 1;
+            if text.contains(START_MARKER) {
+                in_region = true;
+            }
+            if in_region || LINE_MARKERS.iter().any(|marker| text.contains(marker)) {
+                exclusions.lines.insert(line);
+            }
+            if text.contains(STOP_MARKER) {
+                in_region = false;
+            }
+        }
+        exclusions
+    }
+
+    /// Find the lines excluded by markers in the file at `path`
+    /// Files that can't be read, like the standard library's sources on another machine, don't exclude anything
+    pub fn read(path: &Path) -> Exclusions {
+        fs::read_to_string(path)
+            .map(|source| Exclusions::scan(&source))
+            .unwrap_or_default()
+    }
+
+    /// Whether a line is excluded
+    pub fn excludes_line(&self, line: LineIndex) -> bool {
+        self.lines.contains(&line)
+    }
+
+    /// Get the number of excluded lines
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Whether no lines are excluded
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_marked_lines() {
+        let exclusions = Exclusions::scan(
+            "fn main() {\n    debug(); // LCOV_EXCL_LINE\n    log(); // wcov:ignore\n    run();\n}\n",
+        );
+        assert!(!exclusions.excludes_line(1));
+        assert!(exclusions.excludes_line(2));
+        assert!(exclusions.excludes_line(3));
+        assert!(!exclusions.excludes_line(4));
+        assert_eq!(exclusions.len(), 2);
+    }
+
+    #[test]
+    fn excludes_regions() {
+        let exclusions = Exclusions::scan("a\n// LCOV_EXCL_START\nb\n// LCOV_EXCL_STOP\nc\n");
+        assert_eq!(
+            exclusions.lines.iter().copied().collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        // A region that's never stopped runs to the end of the file
+        let exclusions = Exclusions::scan("a\n// LCOV_EXCL_START\nb\nc");
+        assert_eq!(
+            exclusions.lines.iter().copied().collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        // A region can start and stop on the same line
+        let exclusions = Exclusions::scan("a /* LCOV_EXCL_START */ b /* LCOV_EXCL_STOP */\nc");
+        assert_eq!(exclusions.lines.iter().copied().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn excludes_nothing_without_markers() {
+        assert!(Exclusions::scan("a\nb\n").is_empty());
+        assert!(Exclusions::read(Path::new("/nonexistent/wcov/source.rs")).is_empty());
+    }
+}
@@ -2,12 +2,19 @@
 
 use std::{collections::HashMap, fmt::Display, fs, path::PathBuf, sync::Arc};
 
+use itertools::Itertools;
+
 use crate::annotator::data::DebugDataArc;
+use crate::runner::exclusions::Exclusions;
 
 /// A type alias for a u64, used to make what certain arguments are used for more obvious. This type is used for the line number in a source file.
 pub type LineIndex = u64;
 /// The same idea as before, but for column indices
 pub type ColumnIndex = u64;
+/// The DWARF discriminator of a block, which tells apart blocks that start at the same column
+pub type Discriminator = u64;
+/// A block on a line, identified by its column and discriminator
+pub type BlockIndex = (ColumnIndex, Discriminator);
 
 /// A line in a GCov program
 /// This is an enum of a bunch of diffetent states, but it could (and will!) be rewritten to be simpler
@@ -16,9 +23,9 @@ pub enum Line {
     /// A Line with no counter blocks
     Empty,
     /// A line with one counter blocks
-    Singlet((ColumnIndex, u64)),
+    Singlet((BlockIndex, u64)),
     /// A line with many counter blocks
-    Plural(HashMap<ColumnIndex, u64>),
+    Plural(HashMap<BlockIndex, u64>),
 }
 
 impl Line {
@@ -26,22 +33,22 @@ impl Line {
     pub fn empty() -> Line {
         Line::Empty
     }
-    /// Create a line with a specified column index as the first block
-    pub fn new(idx: ColumnIndex) -> Line {
+    /// Create a line with a specified block as the first block
+    pub fn new(idx: BlockIndex) -> Line {
         Line::Singlet((idx, 1))
     }
-    /// Increment the number of counters for the block at `idx`
-    pub fn increment(&mut self, idx: ColumnIndex) {
+    /// Increase the number of counters for the block at `idx` by `count`
+    pub fn increment(&mut self, idx: BlockIndex, count: u64) {
         match self {
-            Line::Plural(map) => drop(map.entry(idx).and_modify(|e| *e += 1).or_insert(1)),
+            Line::Plural(map) => *map.entry(idx).or_insert(0) += count,
             Line::Singlet((self_idx, counter)) => {
                 if *self_idx == idx {
-                    *counter += 1;
+                    *counter += count;
                 } else {
-                    *self = Line::Plural(HashMap::from([(*self_idx, *counter), (idx, 1)]))
+                    *self = Line::Plural(HashMap::from([(*self_idx, *counter), (idx, count)]))
                 }
             }
-            Line::Empty => *self = Line::Singlet((idx, 1)),
+            Line::Empty => *self = Line::Singlet((idx, count)),
         }
     }
     /// Get the total number of counters for all blocks in this line
@@ -60,12 +67,34 @@ impl Line {
             Line::Plural(map) => map.keys().count() as u64,
         }
     }
+    /// Get the column and counter of every block on this line that has been hit, sorted by column
+    /// Blocks that only differ by discriminator are merged, since they start at the same place
+    pub fn blocks(&self) -> Vec<(ColumnIndex, u64)> {
+        match self {
+            Line::Empty => Vec::new(),
+            Line::Singlet(((column, _), count)) => vec![(*column, *count)],
+            Line::Plural(map) => map
+                .iter()
+                .map(|((column, _), count)| (*column, *count))
+                .sorted_by_key(|(column, _)| *column)
+                .coalesce(|(c1, n1), (c2, n2)| {
+                    if c1 == c2 {
+                        Ok((c1, n1 + n2))
+                    } else {
+                        Err(((c1, n1), (c2, n2)))
+                    }
+                })
+                .collect(),
+        }
+    }
 }
 
 /// A struct representing a `.gcov` file
 pub struct GCovFile {
     src_file: Arc<PathBuf>,
     counters: HashMap<LineIndex, (Line, u64)>,
+    panic_site: Option<(LineIndex, ColumnIndex, String)>,
+    exclusions: Exclusions,
 }
 
 impl GCovFile {
@@ -77,15 +106,61 @@ impl GCovFile {
             .collect();
         let src_file = data.file_map[file_idx].clone();
 
-        GCovFile { src_file, counters }
+        GCovFile {
+            src_file,
+            counters,
+            panic_site: None,
+            exclusions: Exclusions::default(),
+        }
     }
-    /// Increment a counter for the block at [`line_idx`]:[`column_idx`]
-    pub fn increment(&mut self, line_idx: LineIndex, column_idx: ColumnIndex) {
+    /// Add `count` hits to the counter for the block at [`line_idx`]:[`column_idx`] with the discriminator `discriminator`
+    /// A count of zero leaves the block as it is, rather than marking it as run
+    pub fn increment(
+        &mut self,
+        line_idx: LineIndex,
+        column_idx: ColumnIndex,
+        discriminator: Discriminator,
+        count: u64,
+    ) {
+        if count == 0 {
+            return;
+        }
         self.counters
             .get_mut(&line_idx)
             .unwrap()
             .0
-            .increment(column_idx)
+            .increment((column_idx, discriminator), count)
+    }
+    /// Mark the block at [`line_idx`]:[`column_idx`] as where the component trapped, with the trap message
+    pub fn set_panic_site(&mut self, line_idx: LineIndex, column_idx: ColumnIndex, message: &str) {
+        self.panic_site = Some((line_idx, column_idx, message.to_string()));
+    }
+    /// Scan the source file for exclusion markers, and leave the lines they exclude out of the reports
+    /// Returns the number of excluded lines
+    pub fn exclude_marked_lines(&mut self) -> usize {
+        self.exclusions = Exclusions::read(self.src_file.as_path());
+        self.exclusions.len()
+    }
+    /// Get the lines excluded by markers in the source file
+    pub fn exclusions(&self) -> &Exclusions {
+        &self.exclusions
+    }
+    /// Whether markers exclude the function that starts at line `start` and ends at line `end`
+    /// It is if its first line is excluded, or if every line of its body with code on it is
+    /// The body leaves out the first and last lines, which only hold the function's prologue and epilogue
+    pub fn excludes_function(&self, start: LineIndex, end: Option<LineIndex>) -> bool {
+        if self.exclusions.excludes_line(start) {
+            return true;
+        }
+        let Some(end) = end else {
+            return false;
+        };
+        let mut body = self
+            .counters
+            .keys()
+            .filter(|line| start < **line && **line < end)
+            .peekable();
+        body.peek().is_some() && body.all(|line| self.exclusions.excludes_line(*line))
     }
     /// Clone the source file this struct represents, using an `Arc`
     pub fn clone_src_file(&self) -> Arc<PathBuf> {
@@ -95,6 +170,17 @@ impl GCovFile {
     pub fn get_counters_for_line(&self, line: LineIndex) -> Option<u64> {
         self.counters.get(&line).map(|(l, _)| l.total_counters())
     }
+    /// Get every line with code on it that isn't excluded, along with its counters and the number of blocks it contains, sorted by line number
+    pub fn lines(&self) -> Vec<(LineIndex, &Line, u64)> {
+        let mut lines = self
+            .counters
+            .iter()
+            .filter(|(idx, _)| !self.exclusions.excludes_line(**idx))
+            .map(|(idx, (line, num_blocks))| (*idx, line, *num_blocks))
+            .collect::<Vec<_>>();
+        lines.sort_by_key(|(idx, _, _)| *idx);
+        lines
+    }
 }
 
 // Will allow us to write into an output file
@@ -109,7 +195,10 @@ impl Display for GCovFile {
             .enumerate()
             .map(|(idx, str_line)| {
                 let idx = idx as u64 + 1;
-                if let Some((line, num_blocks)) = self.counters.get(&idx) {
+                // Excluded lines get their own marker, whether or not they have code on them
+                if self.exclusions.excludes_line(idx) {
+                    ("x:".to_string(), (idx, str_line))
+                } else if let Some((line, num_blocks)) = self.counters.get(&idx) {
                     let block_diff = *num_blocks - line.num_blocks();
                     let total_counters = line.total_counters();
                     let star = if block_diff > 0 && line.num_blocks() > 0 {
@@ -132,7 +221,52 @@ impl Display for GCovFile {
         for (info, (idx, str)) in info_lines {
             let width = max_len;
             writeln!(f, "{:width$} {}:{}", info, idx, str)?;
+            // Like gcov's `call` and `branch` lines, the panic site is listed under the line it's on
+            if let Some((_, column, message)) =
+                self.panic_site.as_ref().filter(|(line, _, _)| *line == idx)
+            {
+                writeln!(f, "panic site at column {}: {}", column, message)?;
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::annotator::data::DebugDataOwned;
+
+    /// A file with code on every line from 1 to 5, excluded by the markers in `source`
+    fn gcov_file(source: &str) -> GCovFile {
+        let data: DebugDataArc = DebugDataOwned {
+            file_map: vec![PathBuf::from("lib.rs")],
+            file_checksums: HashMap::new(),
+            blocks_per_line: HashMap::from([(0, (1..=5).map(|line| (line, 1)).collect())]),
+            counters: Vec::new(),
+            sdi_vec: Vec::new(),
+            line_tables: Vec::new(),
+        }
+        .into();
+        let mut gcov = GCovFile::new(&data, 0);
+        gcov.exclusions = Exclusions::scan(source);
+        gcov
+    }
+
+    #[test]
+    fn excludes_functions_by_their_first_line_or_body() {
+        let gcov = gcov_file("fn f() { // LCOV_EXCL_LINE\na\nb\n}\n");
+        assert!(gcov.excludes_function(1, Some(4)));
+        assert!(!gcov.excludes_function(2, Some(5)));
+
+        let gcov = gcov_file("fn f() {\n// LCOV_EXCL_START\na\n// LCOV_EXCL_STOP\n}\n");
+        assert!(gcov.excludes_function(1, Some(5)));
+        // Only part of the body is excluded
+        assert!(!gcov.excludes_function(1, Some(6)));
+        // Without a body, there's nothing to exclude the function by
+        assert!(!gcov.excludes_function(1, Some(2)));
+        assert!(!gcov.excludes_function(1, None));
+    }
+}
@@ -8,12 +8,19 @@ use wasmtime::StoreContextMut;
 use crate::runner::{gcov::GCovFile, store, ConstantIterator};
 
 /// The `inc-counter` function that modified Wasm component will call out to
+/// Its arguments are the counter's index, the number of times it was hit, and the file, line, and column it's at
 pub fn inc_counter(
     mut store: StoreContextMut<store::MyState>,
     args: (i32, i32, i32, i32, i32),
     noise_level: NoiseLevel,
 ) -> wasmtime::Result<()> {
-    let (idx, file_idx, line_num, col_num) = (args.0 as usize, args.2 as usize, args.3, args.4);
+    let (idx, count, file_idx, line_num, col_num) = (
+        args.0 as usize,
+        args.1 as u32 as u64,
+        args.2 as usize,
+        args.3,
+        args.4,
+    );
     let counters = &mut store.data_mut().counters;
     if counters.len() <= idx {
         counters.extend(ConstantIterator::<i32>::new_default_value(
@@ -23,12 +30,20 @@ pub fn inc_counter(
     let data = store.data_mut();
     if let Some(map) = data.gcov_files.as_mut() {
         let debug_data = data.debug_data.as_ref().unwrap();
-        let path = &debug_data.file_map[file_idx];
-        if !map.contains_key(path) {
-            map.insert(path.clone(), GCovFile::new(debug_data, file_idx));
+        // A counter covers every line row in its basic block, which can span several lines or even files
+        let passed = [(file_idx, line_num as u64, col_num as u64, 0)];
+        let locations = debug_data
+            .counters
+            .get(idx)
+            .map_or(&passed[..], |locations| locations.as_slice());
+        for (file_idx, line, column, discriminator) in locations {
+            let path = &debug_data.file_map[*file_idx];
+            if !map.contains_key(path) {
+                map.insert(path.clone(), GCovFile::new(debug_data, *file_idx));
+            }
+            let gcov_file = map.get_mut(path).unwrap();
+            gcov_file.increment(*line, *column, *discriminator, count);
         }
-        let gcov_file = map.get_mut(path).unwrap();
-        gcov_file.increment(line_num as u64, col_num as u64);
     }
 
     let file = if let Some(debug_data) = &store.data().debug_data {
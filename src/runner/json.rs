@@ -0,0 +1,312 @@
+//! A module containing structs to output coverage info in the JSON format produced by `llvm-cov export -format=text`
+//! Branches are not exported yet, since wcov doesn't collect branch data, so every `branches` array is empty and the branch totals are zero
+
+use serde::Serialize;
+
+use crate::annotator::debug::SourceDebugInfo;
+use crate::runner::gcov::GCovFile;
+
+const EXPORT_VERSION: &str = "2.0.1";
+const EXPORT_TYPE: &str = "llvm.coverage.json.export";
+
+type Segment = (u64, u64, u64, bool, bool, bool); // line, column, exec count, has count, is region entry, is gap region
+type Region = (u64, u64, u64, u64, u64, usize, usize, u64); // start line, start column, end line, end column, exec count, file id, expanded file id, kind
+type Branch = (u64, u64, u64, u64, u64, u64, usize, usize, u64); // start line, start column, end line, end column, true count, false count, file id, expanded file id, kind
+
+// llvm-cov's `CounterMappingRegion::RegionKind` for a plain code region
+const CODE_REGION: u64 = 0;
+
+#[derive(Serialize, Default, Clone, Copy)]
+/// Totals for one kind of coverage item (lines, functions, etc.)
+pub struct SummaryEntry {
+    /// The number of items found
+    pub count: u64,
+    /// The number of items that were executed at least once
+    pub covered: u64,
+    /// The number of items that were never executed, only emitted for regions and branches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notcovered: Option<u64>,
+    /// The percentage of items that were executed
+    pub percent: f64,
+}
+
+impl SummaryEntry {
+    fn new(count: u64, covered: u64, with_notcovered: bool) -> SummaryEntry {
+        let percent = if count == 0 {
+            0.0
+        } else {
+            covered as f64 / count as f64 * 100.0
+        };
+        SummaryEntry {
+            count,
+            covered,
+            notcovered: with_notcovered.then_some(count - covered),
+            percent,
+        }
+    }
+
+    fn add(self, other: SummaryEntry) -> SummaryEntry {
+        SummaryEntry::new(
+            self.count + other.count,
+            self.covered + other.covered,
+            self.notcovered.is_some(),
+        )
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+/// Coverage totals for a single file, or for a whole export
+pub struct Summary {
+    /// Line totals
+    pub lines: SummaryEntry,
+    /// Function totals
+    pub functions: SummaryEntry,
+    /// Function instantiation totals. Wasm code has no templates, so this always matches `functions`
+    pub instantiations: SummaryEntry,
+    /// Region (counter block) totals
+    pub regions: SummaryEntry,
+    /// Branch totals
+    pub branches: SummaryEntry,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            lines: SummaryEntry::new(0, 0, false),
+            functions: SummaryEntry::new(0, 0, false),
+            instantiations: SummaryEntry::new(0, 0, false),
+            regions: SummaryEntry::new(0, 0, true),
+            branches: SummaryEntry::new(0, 0, true),
+        }
+    }
+}
+
+impl Summary {
+    fn add(self, other: Summary) -> Summary {
+        Summary {
+            lines: self.lines.add(other.lines),
+            functions: self.functions.add(other.functions),
+            instantiations: self.instantiations.add(other.instantiations),
+            regions: self.regions.add(other.regions),
+            branches: self.branches.add(other.branches),
+        }
+    }
+}
+
+#[derive(Serialize)]
+/// Coverage information about a single source file
+pub struct FileExport {
+    filename: String,
+    segments: Vec<Segment>,
+    branches: Vec<Branch>,
+    expansions: Vec<()>,
+    summary: Summary,
+    #[serde(skip)]
+    functions: Vec<FunctionExport>,
+}
+
+impl FileExport {
+    /// Create a new `FileExport` from a GCovFile containing counter information and, if avaliable, a SourceDebugInfo struct describing the functions in the file
+    pub fn new(counter_log: &GCovFile, sdi: Option<&SourceDebugInfo>) -> FileExport {
+        let filename = counter_log.clone_src_file().display().to_string();
+        let lines = counter_log.lines();
+
+        let mut segments = Vec::new();
+        let (mut regions_found, mut regions_hit) = (0, 0);
+        for (pos, (line_idx, line, num_blocks)) in lines.iter().enumerate() {
+            let blocks = line.blocks();
+            if blocks.is_empty() {
+                segments.push((*line_idx, 1, 0, true, true, false));
+            } else {
+                segments.extend(
+                    blocks
+                        .iter()
+                        .map(|(col, count)| (*line_idx, (*col).max(1), *count, true, true, false)),
+                );
+            }
+            // Close the region unless the next line picks up where this one left off
+            if lines
+                .get(pos + 1)
+                .is_none_or(|(next, _, _)| *next != line_idx + 1)
+            {
+                segments.push((line_idx + 1, 1, 0, false, false, false));
+            }
+            regions_found += (*num_blocks).max(line.num_blocks());
+            regions_hit += line.num_blocks();
+        }
+
+        let functions = sdi
+            .map(|sdi| {
+                sdi.functions
+                    .iter()
+                    .filter(|(start, end, _, _)| !counter_log.excludes_function(*start, *end))
+                    .map(|(start, end, name, _addr)| {
+                        let count = counter_log.get_counters_for_line(*start).unwrap_or(0);
+                        FunctionExport {
+                            name: name.clone(),
+                            count,
+                            regions: vec![(
+                                *start,
+                                1,
+                                end.unwrap_or(*start),
+                                1,
+                                count,
+                                0,
+                                0,
+                                CODE_REGION,
+                            )],
+                            branches: Vec::new(),
+                            filenames: vec![filename.clone()],
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        // wcov doesn't collect branch data yet, so no branches are exported
+        // The empty arrays are still written, since tools reading llvm-cov's format expect them
+        let branches: Vec<Branch> = Vec::new();
+
+        let lines_hit = lines
+            .iter()
+            .filter(|(_, line, _)| line.total_counters() > 0)
+            .count() as u64;
+        let funcs_hit = functions.iter().filter(|f| f.count > 0).count() as u64;
+        let functions_entry = SummaryEntry::new(functions.len() as u64, funcs_hit, false);
+        let summary = Summary {
+            lines: SummaryEntry::new(lines.len() as u64, lines_hit, false),
+            functions: functions_entry,
+            instantiations: functions_entry,
+            regions: SummaryEntry::new(regions_found, regions_hit, true),
+            branches: SummaryEntry::new(0, 0, true),
+        };
+
+        FileExport {
+            filename,
+            segments,
+            branches,
+            expansions: Vec::new(),
+            summary,
+            functions,
+        }
+    }
+}
+
+#[derive(Serialize)]
+/// Coverage information about a single function
+pub struct FunctionExport {
+    name: String,
+    count: u64,
+    regions: Vec<Region>,
+    branches: Vec<Branch>,
+    filenames: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ExportData {
+    files: Vec<FileExport>,
+    functions: Vec<FunctionExport>,
+    totals: Summary,
+}
+
+#[derive(Serialize)]
+/// A struct representing a full JSON coverage export, in the same shape as `llvm-cov export`
+pub struct CoverageExport {
+    version: &'static str,
+    #[serde(rename = "type")]
+    export_type: &'static str,
+    data: Vec<ExportData>,
+}
+
+impl CoverageExport {
+    /// Create a new export from a list of files
+    pub fn new(mut files: Vec<FileExport>) -> CoverageExport {
+        let totals = files
+            .iter()
+            .fold(Summary::default(), |acc, f| acc.add(f.summary));
+        let functions = files
+            .iter_mut()
+            .flat_map(|f| std::mem::take(&mut f.functions))
+            .collect();
+        CoverageExport {
+            version: EXPORT_VERSION,
+            export_type: EXPORT_TYPE,
+            data: vec![ExportData {
+                files,
+                functions,
+                totals,
+            }],
+        }
+    }
+
+    /// Serialize this export to a JSON string
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::annotator::data::{DebugDataArc, DebugDataOwned};
+
+    fn gcov_file() -> (GCovFile, SourceDebugInfo) {
+        let data: DebugDataArc = DebugDataOwned {
+            file_map: vec![PathBuf::from("src/lib.rs")],
+            file_checksums: HashMap::new(),
+            blocks_per_line: HashMap::from([(0, vec![(1, 1), (2, 2), (5, 1)])]),
+            counters: Vec::new(),
+            line_tables: Vec::new(),
+            sdi_vec: Vec::new(),
+        }
+        .into();
+        let mut gcov = GCovFile::new(&data, 0);
+        gcov.increment(1, 5, 0, 1);
+        gcov.increment(1, 5, 0, 1);
+        gcov.increment(2, 3, 0, 1);
+        let sdi = SourceDebugInfo {
+            path_idx: 0,
+            functions: vec![
+                (1, Some(2), "used".to_string(), 0),
+                (5, None, "unused".to_string(), 10),
+            ],
+            branches: Vec::new(),
+        };
+        (gcov, sdi)
+    }
+
+    #[test]
+    fn sums_file_totals() {
+        let (gcov, sdi) = gcov_file();
+        let file = FileExport::new(&gcov, Some(&sdi));
+        assert_eq!(file.summary.lines.count, 3);
+        assert_eq!(file.summary.lines.covered, 2);
+        assert_eq!(file.summary.functions.count, 2);
+        assert_eq!(file.summary.functions.covered, 1);
+        // Line 2 has two blocks, but only one of them ran
+        assert_eq!(file.summary.regions.count, 4);
+        assert_eq!(file.summary.regions.covered, 2);
+        assert_eq!(file.summary.regions.notcovered, Some(2));
+    }
+
+    #[test]
+    fn adds_file_summaries_into_totals() {
+        let (gcov, sdi) = gcov_file();
+        let export = CoverageExport::new(vec![
+            FileExport::new(&gcov, Some(&sdi)),
+            FileExport::new(&gcov, None),
+        ]);
+        let totals = export.data[0].totals;
+        assert_eq!(totals.lines.count, 6);
+        assert_eq!(totals.lines.covered, 4);
+        assert_eq!(totals.functions.count, 2);
+        assert_eq!(totals.lines.percent, 4.0 / 6.0 * 100.0);
+        // The functions are moved out of the files and listed once for the whole export
+        assert_eq!(export.data[0].functions.len(), 2);
+        let json = export.to_json().unwrap();
+        assert!(json.starts_with(r#"{"version":"2.0.1","type":"llvm.coverage.json.export""#));
+    }
+}
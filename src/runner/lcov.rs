@@ -16,10 +16,64 @@ type Brda = (u64, bool, u64, u64, u64); // line num, is an exception, block idx,
                                         // What lines count as instrumented? idk we should figure that out
 type DA = (u64, u64, Option</*should be an md5 */ u64>); // line num, exec count, hash
 
+/// The number of items of one kind (lines, functions, or branches) that were found and hit
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CoverageCount {
+    /// The number of items that were executed at least once
+    pub hit: u64,
+    /// The total number of items
+    pub found: u64,
+}
+
+impl CoverageCount {
+    /// Get the percentage of items that were hit, or `None` if no items were found
+    pub fn percent(&self) -> Option<f64> {
+        if self.found == 0 {
+            None
+        } else {
+            Some(self.hit as f64 / self.found as f64 * 100.0)
+        }
+    }
+}
+
+impl std::ops::Add for CoverageCount {
+    type Output = CoverageCount;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        CoverageCount {
+            hit: self.hit + rhs.hit,
+            found: self.found + rhs.found,
+        }
+    }
+}
+
+/// Line, function, and branch coverage totals for a source file or a whole tracefile
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Summary {
+    /// Line coverage
+    pub lines: CoverageCount,
+    /// Function coverage
+    pub functions: CoverageCount,
+    /// Branch coverage
+    pub branches: CoverageCount,
+}
+
+impl std::ops::Add for Summary {
+    type Output = Summary;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Summary {
+            lines: self.lines + rhs.lines,
+            functions: self.functions + rhs.functions,
+            branches: self.branches + rhs.branches,
+        }
+    }
+}
+
 /// A struct represention a source file that is part of an LCov tracefile
 pub struct SourceFile {
     path: Arc<PathBuf>,
-    version: Option<u64>,
+    version: Option<String>,
     functions: Vec<FuncDef>,
     func_exces: Vec<Fnda>,
     branch_coverage: Vec<Brda>,
@@ -27,19 +81,24 @@ pub struct SourceFile {
 }
 
 impl SourceFile {
-    /// Create a new `SourceFile` from a Gcovfile containing counter information and a SourceDebugInfo struct
+    /// Create a new `SourceFile` from a Gcovfile containing counter information and, if avaliable, a SourceDebugInfo struct describing the functions in the file
+    /// The version identifies which revision of the source file the coverage is for, such as the MD5 checksum DWARF records for it
     pub fn new(
         counter_log: &GCovFile,
-        sdi: &SourceDebugInfo,
+        sdi: Option<&SourceDebugInfo>,
+        version: Option<String>,
         noise_level: NoiseLevel,
     ) -> SourceFile {
         let path = counter_log.clone_src_file();
-        let version = None;
         let functions = sdi
-            .functions
-            .iter()
-            .map(|(start, end, str, _addr)| (*start, *end, str.clone()))
-            .collect::<Vec<_>>();
+            .map(|sdi| {
+                sdi.functions
+                    .iter()
+                    .filter(|(start, end, _, _)| !counter_log.excludes_function(*start, *end))
+                    .map(|(start, end, str, _addr)| (*start, *end, str.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
         let func_exces = functions
             .iter()
             .enumerate()
@@ -48,26 +107,16 @@ impl SourceFile {
                 if counters.is_none() && noise_level.err() {
                     println_wcov_error("lcov error: function line has no counters");
                 }
-                (counters.unwrap(), idx)
+                (counters.unwrap_or(0), idx)
             })
             .collect::<Vec<_>>();
-        if noise_level.err() {
-            println_wcov_error("TODO: output branch info");
-        }
         let branch_coverage = Vec::new();
-        let last_line = functions
-            .iter()
-            .filter_map(|(_, end, _)| *end)
-            .max()
-            .unwrap();
-        // inefficient but it should work
-        let mut code_lines = Vec::new();
-        for line in 0..=last_line {
-            if let Some(count) = counter_log.get_counters_for_line(line) {
-                let da = (line, count, None);
-                code_lines.push(da);
-            }
-        }
+        // Every line with code on it is reported, even if it was never run
+        let code_lines = counter_log
+            .lines()
+            .into_iter()
+            .map(|(line, counters, _)| (line, counters.total_counters(), None))
+            .collect::<Vec<_>>();
         SourceFile {
             path,
             version,
@@ -77,12 +126,47 @@ impl SourceFile {
             code_lines,
         }
     }
+
+    /// Get the path of the source file this struct represents
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Get the line, function, and branch totals for this file
+    pub fn summary(&self) -> Summary {
+        Summary {
+            lines: CoverageCount {
+                hit: self
+                    .code_lines
+                    .iter()
+                    .filter(|(_, count, _)| *count > 0)
+                    .count() as u64,
+                found: self.code_lines.len() as u64,
+            },
+            functions: CoverageCount {
+                hit: self
+                    .func_exces
+                    .iter()
+                    .filter(|(count, _)| *count > 0)
+                    .count() as u64,
+                found: self.functions.len() as u64,
+            },
+            branches: CoverageCount {
+                hit: self
+                    .branch_coverage
+                    .iter()
+                    .filter(|(_, _, _, _, taken)| *taken > 0)
+                    .count() as u64,
+                found: self.branch_coverage.len() as u64,
+            },
+        }
+    }
 }
 
 impl Display for SourceFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "SF:{}", self.path.display())?;
-        if let Some(v_id) = self.version {
+        if let Some(v_id) = &self.version {
             writeln!(f, "VER:{}", v_id)?;
         }
         for funcdef in &self.functions {
@@ -94,7 +178,7 @@ impl Display for SourceFile {
             writeln!(f, "FNDA:{},{}", exec.0, self.functions[exec.1].2)?;
         }
         writeln!(f, "FNF:{}", self.functions.len())?;
-        writeln!(f, "FNH:{}", self.func_exces.len())?;
+        writeln!(f, "FNH:{}", self.summary().functions.hit)?;
         for branch in &self.branch_coverage {
             writeln!(
                 f,
@@ -154,6 +238,18 @@ impl TraceFile {
             files,
         }
     }
+
+    /// Get the source files in this tracefile
+    pub fn files(&self) -> &Vec<SourceFile> {
+        &self.files
+    }
+
+    /// Get the line, function, and branch totals across every file in this tracefile
+    pub fn totals(&self) -> Summary {
+        self.files
+            .iter()
+            .fold(Summary::default(), |acc, sf| acc + sf.summary())
+    }
 }
 
 impl Display for TraceFile {
@@ -172,3 +268,69 @@ impl Display for TraceFile {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::annotator::data::{DebugDataArc, DebugDataOwned};
+
+    fn source_file(path: &str, hits: &[(u64, u64)]) -> SourceFile {
+        let data: DebugDataArc = DebugDataOwned {
+            file_map: vec![PathBuf::from(path)],
+            file_checksums: HashMap::new(),
+            blocks_per_line: HashMap::from([(0, vec![(1, 1), (2, 1), (3, 1), (4, 1)])]),
+            counters: Vec::new(),
+            line_tables: Vec::new(),
+            sdi_vec: Vec::new(),
+        }
+        .into();
+        let mut gcov = GCovFile::new(&data, 0);
+        for (line, column) in hits {
+            gcov.increment(*line, *column, 0, 1);
+        }
+        let sdi = SourceDebugInfo {
+            path_idx: 0,
+            functions: vec![
+                (1, Some(2), "main".to_string(), 0),
+                (3, Some(4), "helper".to_string(), 8),
+            ],
+            branches: Vec::new(),
+        };
+        SourceFile::new(&gcov, Some(&sdi), None, NoiseLevel::Quiet)
+    }
+
+    #[test]
+    fn summarizes_a_file() {
+        let summary = source_file("a.rs", &[(1, 1), (1, 1), (2, 1)]).summary();
+        assert_eq!((summary.lines.hit, summary.lines.found), (2, 4));
+        assert_eq!((summary.functions.hit, summary.functions.found), (1, 2));
+        assert_eq!(summary.lines.percent(), Some(50.0));
+        assert_eq!(summary.branches.percent(), None);
+    }
+
+    #[test]
+    fn totals_every_file() {
+        let tracefile = TraceFile::new(
+            None,
+            vec![
+                source_file("a.rs", &[(1, 1), (2, 1)]),
+                source_file("b.rs", &[(1, 1), (2, 1), (3, 1), (4, 1)]),
+            ],
+        );
+        let totals = tracefile.totals();
+        assert_eq!((totals.lines.hit, totals.lines.found), (6, 8));
+        assert_eq!((totals.functions.hit, totals.functions.found), (3, 4));
+        assert_eq!(totals.lines.percent(), Some(75.0));
+    }
+
+    #[test]
+    fn reports_never_executed_code_with_zero_counts() {
+        let report = source_file("a.rs", &[]).to_string();
+        assert!(report.contains("FNDA:0,main\nFNDA:0,helper\n"));
+        assert!(report.contains("DA:1,0\nDA:2,0\nDA:3,0\nDA:4,0\n"));
+        assert!(report.contains("LH:0\nLF:4\n"));
+    }
+}
@@ -22,6 +22,21 @@ pub struct MyState {
     pub noise_level: NoiseLevel,
 }
 
+impl MyState {
+    /// Create an empty `GCovFile` for every source file in the debug data that doesn't have one yet
+    /// Doing this before the component runs means files and functions that never execute are still reported, with zero counts
+    pub fn populate_gcov_files(&mut self) {
+        if let (Some(map), Some(debug_data)) = (self.gcov_files.as_mut(), self.debug_data.as_ref()) {
+            for file_idx in debug_data.blocks_per_line.keys() {
+                let path = &debug_data.file_map[*file_idx];
+                if !map.contains_key(path) {
+                    map.insert(path.clone(), GCovFile::new(debug_data, *file_idx));
+                }
+            }
+        }
+    }
+}
+
 impl WasiView for MyState {
     fn ctx(&mut self) -> &mut WasiCtx {
         &mut self.ctx
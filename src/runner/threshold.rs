@@ -0,0 +1,206 @@
+//! This module checks coverage totals against minimums set by the user, so that runs with too little coverage can fail
+
+use std::{fmt::Display, path::PathBuf};
+
+use crate::runner::lcov::{CoverageCount, TraceFile};
+
+/// Minimum coverage percentages a run has to meet
+#[derive(Default, Clone, Copy)]
+pub struct Thresholds {
+    /// The minimum line coverage across all files
+    pub lines: Option<f64>,
+    /// The minimum function coverage across all files
+    pub functions: Option<f64>,
+    /// The minimum branch coverage across all files
+    pub branches: Option<f64>,
+    /// The minimum line coverage for each individual file
+    pub file_lines: Option<f64>,
+}
+
+/// A coverage minimum that wasn't met
+pub struct ThresholdFailure {
+    /// The file that didn't meet the minimum, or `None` if it was the overall total
+    pub file: Option<PathBuf>,
+    /// What was measured ("line", "function", or "branch")
+    pub kind: &'static str,
+    /// The measured coverage percentage, or `None` if no coverage data was collected at all
+    pub percent: Option<f64>,
+    /// The minimum that was required
+    pub limit: f64,
+}
+
+impl Display for ThresholdFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        } else {
+            write!(f, "total ")?;
+        }
+        match self.percent {
+            Some(percent) => write!(
+                f,
+                "{} coverage {:.2}% is below the minimum of {:.2}%",
+                self.kind, percent, self.limit
+            ),
+            None => write!(
+                f,
+                "no {} coverage was collected, but a minimum of {:.2}% was required",
+                self.kind, self.limit
+            ),
+        }
+    }
+}
+
+impl Thresholds {
+    /// Check the totals in a tracefile against these minimums, returning every minimum that wasn't met
+    /// If no tracefile could be created, every minimum that was set fails
+    pub fn check(&self, tracefile: Option<&TraceFile>) -> Vec<ThresholdFailure> {
+        let limits = [
+            ("line", self.lines),
+            ("function", self.functions),
+            ("branch", self.branches),
+            ("line", self.file_lines),
+        ];
+        let Some(tracefile) = tracefile else {
+            return limits
+                .into_iter()
+                .filter_map(|(kind, limit)| {
+                    limit.map(|limit| ThresholdFailure {
+                        file: None,
+                        kind,
+                        percent: None,
+                        limit,
+                    })
+                })
+                .collect();
+        };
+
+        // Having no lines or functions to cover counts as being fully covered
+        fn below(count: &CoverageCount, limit: f64) -> Option<f64> {
+            let percent = count.percent().unwrap_or(100.0);
+            (percent < limit).then_some(percent)
+        }
+
+        let mut failures = Vec::new();
+        let totals = tracefile.totals();
+        for (kind, count, limit) in [
+            ("line", &totals.lines, self.lines),
+            ("function", &totals.functions, self.functions),
+            ("branch", &totals.branches, self.branches),
+        ] {
+            if let Some(limit) = limit {
+                // wcov doesn't collect branch data yet, so a branch minimum can't be met by having no branches
+                if kind == "branch" && count.found == 0 {
+                    failures.push(ThresholdFailure {
+                        file: None,
+                        kind,
+                        percent: None,
+                        limit,
+                    });
+                } else if let Some(percent) = below(count, limit) {
+                    failures.push(ThresholdFailure {
+                        file: None,
+                        kind,
+                        percent: Some(percent),
+                        limit,
+                    });
+                }
+            }
+        }
+        if let Some(limit) = self.file_lines {
+            for sf in tracefile.files() {
+                if let Some(percent) = below(&sf.summary().lines, limit) {
+                    failures.push(ThresholdFailure {
+                        file: Some(sf.path().clone()),
+                        kind: "line",
+                        percent: Some(percent),
+                        limit,
+                    });
+                }
+            }
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::annotator::data::{DebugDataArc, DebugDataOwned};
+    use crate::annotator::debug::SourceDebugInfo;
+    use crate::noise::NoiseLevel;
+    use crate::runner::gcov::GCovFile;
+    use crate::runner::lcov::SourceFile;
+
+    /// A tracefile with two files of two lines each, where `a.rs` is fully covered and `b.rs` is half covered
+    fn tracefile() -> TraceFile {
+        let data: DebugDataArc = DebugDataOwned {
+            file_map: vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")],
+            file_checksums: HashMap::new(),
+            blocks_per_line: HashMap::from([(0, vec![(1, 1), (2, 1)]), (1, vec![(1, 1), (2, 1)])]),
+            counters: Vec::new(),
+            line_tables: Vec::new(),
+            sdi_vec: Vec::new(),
+        }
+        .into();
+        let files = [(0, vec![1, 2]), (1, vec![1])]
+            .into_iter()
+            .map(|(file_idx, hit_lines)| {
+                let mut gcov = GCovFile::new(&data, file_idx);
+                for line in hit_lines {
+                    gcov.increment(line, 1, 0, 1);
+                }
+                let sdi = SourceDebugInfo {
+                    path_idx: file_idx,
+                    functions: vec![(1, Some(2), "f".to_string(), 0)],
+                    branches: Vec::new(),
+                };
+                SourceFile::new(&gcov, Some(&sdi), None, NoiseLevel::Quiet)
+            })
+            .collect();
+        TraceFile::new(None, files)
+    }
+
+    #[test]
+    fn passes_when_minimums_are_met() {
+        let thresholds = Thresholds {
+            lines: Some(75.0),
+            functions: Some(100.0),
+            ..Default::default()
+        };
+        assert!(thresholds.check(Some(&tracefile())).is_empty());
+    }
+
+    #[test]
+    fn fails_totals_and_files_below_minimums() {
+        let thresholds = Thresholds {
+            lines: Some(80.0),
+            file_lines: Some(60.0),
+            ..Default::default()
+        };
+        let failures = thresholds.check(Some(&tracefile()));
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].file.is_none());
+        assert_eq!(failures[0].percent, Some(75.0));
+        assert_eq!(failures[1].file, Some(PathBuf::from("b.rs")));
+        assert_eq!(failures[1].percent, Some(50.0));
+        assert_eq!(
+            failures[1].to_string(),
+            "b.rs: line coverage 50.00% is below the minimum of 60.00%"
+        );
+    }
+
+    #[test]
+    fn fails_every_minimum_without_a_tracefile() {
+        let thresholds = Thresholds {
+            lines: Some(10.0),
+            branches: Some(10.0),
+            ..Default::default()
+        };
+        let failures = thresholds.check(None);
+        assert_eq!(failures.len(), 2);
+        assert!(failures.iter().all(|f| f.percent.is_none()));
+    }
+}